@@ -3,7 +3,16 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 use cw_stream::msg::{
-    ConfigResponse, ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveMsg, StreamResponse,
+    BootstrapResponse, CancelResult, ClaimableAtResponse, ClaimableResponse, ConfigResponse,
+    ControllerQueryMsg, CreateResult, CurrentRecipientResponse, CurrentTimeResponse,
+    DelegateAllowanceResponse, DexHookMsg, ExecuteMsg, FeePoolResponse, InstantiateMsg,
+    IsWithdrawAuthorizedResponse, MigrateMsg, NameServiceQueryMsg, OverdueClaimsResponse,
+    OwnerSummaryResponse, PendingStreamsResponse, PortfolioResponse, ProjectedTotalsResponse,
+    ProposalResponse, QueryMsg, ReceiveMsg, RecipientTaxReportResponse, ResolveRecordResponse,
+    StatsResponse, StorageEstimateResponse, StreamActionsResponse, StreamHumanResponse,
+    StreamResponse, StreamsByStatusResponse, StreamsResponse, StuckStreamsResponse,
+    TimeOracleQueryMsg, TokenAvailableResponse, TopUpPreviewResponse, WithdrawPreviewResponse,
+    WithdrawResult, WithdrawalHistoryResponse,
 };
 use cw_stream::state::{Config, Stream};
 
@@ -15,10 +24,45 @@ fn main() {
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(ReceiveMsg), &out_dir);
+    export_schema(&schema_for!(ControllerQueryMsg), &out_dir);
+    export_schema(&schema_for!(NameServiceQueryMsg), &out_dir);
+    export_schema(&schema_for!(TimeOracleQueryMsg), &out_dir);
+    export_schema(&schema_for!(DexHookMsg), &out_dir);
     export_schema(&schema_for!(Config), &out_dir);
     export_schema(&schema_for!(Stream), &out_dir);
     export_schema(&schema_for!(ConfigResponse), &out_dir);
+    export_schema(&schema_for!(BootstrapResponse), &out_dir);
     export_schema(&schema_for!(StreamResponse), &out_dir);
+    export_schema(&schema_for!(StreamHumanResponse), &out_dir);
+    export_schema(&schema_for!(StreamsResponse), &out_dir);
+    export_schema(&schema_for!(OverdueClaimsResponse), &out_dir);
+    export_schema(&schema_for!(StuckStreamsResponse), &out_dir);
+    export_schema(&schema_for!(StreamsByStatusResponse), &out_dir);
+    export_schema(&schema_for!(ProposalResponse), &out_dir);
+    export_schema(&schema_for!(PendingStreamsResponse), &out_dir);
+    export_schema(&schema_for!(RecipientTaxReportResponse), &out_dir);
+    export_schema(&schema_for!(OwnerSummaryResponse), &out_dir);
+    export_schema(&schema_for!(StorageEstimateResponse), &out_dir);
+    export_schema(&schema_for!(PortfolioResponse), &out_dir);
+    export_schema(&schema_for!(StreamActionsResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawalHistoryResponse), &out_dir);
+    export_schema(&schema_for!(StatsResponse), &out_dir);
+    export_schema(&schema_for!(IsWithdrawAuthorizedResponse), &out_dir);
+    export_schema(&schema_for!(DelegateAllowanceResponse), &out_dir);
+    export_schema(&schema_for!(TokenAvailableResponse), &out_dir);
+    export_schema(&schema_for!(FeePoolResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawPreviewResponse), &out_dir);
+    export_schema(&schema_for!(TopUpPreviewResponse), &out_dir);
+    export_schema(&schema_for!(ClaimableResponse), &out_dir);
+    export_schema(&schema_for!(ClaimableAtResponse), &out_dir);
+    export_schema(&schema_for!(ProjectedTotalsResponse), &out_dir);
+    export_schema(&schema_for!(CurrentRecipientResponse), &out_dir);
+    export_schema(&schema_for!(ResolveRecordResponse), &out_dir);
+    export_schema(&schema_for!(CurrentTimeResponse), &out_dir);
+    export_schema(&schema_for!(CreateResult), &out_dir);
+    export_schema(&schema_for!(WithdrawResult), &out_dir);
+    export_schema(&schema_for!(CancelResult), &out_dir);
 }
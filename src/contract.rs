@@ -1,19 +1,78 @@
 use crate::error::ContractError;
 use crate::msg::{
-    ConfigResponse, ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveMsg, StreamResponse,
+    AutoSwapSpec, BootstrapResponse, CancelResult, ClaimableAtResponse, ClaimableResponse,
+    ConditionSpec, ConfigResponse, ControllerQueryMsg, CreateResult, CurrentRecipientResponse,
+    CurrentTimeResponse, DelegateAllowanceResponse, DexHookMsg, ExecuteMsg, FeePoolResponse,
+    InstantiateMsg, IsWithdrawAuthorizedResponse, MigrateMsg, NameServiceQueryMsg, OverdueClaim,
+    OverdueClaimsResponse, OwnerSummaryResponse, PendingStreamsResponse, PortfolioEntry,
+    PortfolioResponse, ProjectedTotalsResponse, ProposalResponse, QueryMsg, ReceiveMsg,
+    RecipientKind, RecipientTaxReportResponse, ResolveRecordResponse, StatsResponse,
+    StorageEstimateResponse, StreamActionResponse, StreamActionsResponse, StreamHumanResponse,
+    StreamResponse, StreamsByStatusResponse, StreamsResponse, StuckStream, StuckStreamsResponse,
+    TaxReportEntry, TimeOracleQueryMsg, TokenAvailableResponse, TopUpPreviewResponse,
+    WithdrawPreviewResponse, WithdrawResult, WithdrawalHistoryResponse, WithdrawalRecordResponse,
+};
+use crate::state::{
+    log_stream_action, record_withdrawal, save_stream, streams, update_stats, withdrawals,
+    AutoSwap, Beneficiary, Condition, Config, DustPolicy, Encumbrance, Installments, LastTopUp,
+    PendingAssignment, PendingCancellation, PendingConversion, PendingFallback, Proposal,
+    SharePool, Stream, StreamActionKind, StreamStatus, TimeOracle, WithdrawalRecord,
+    ALLOWED_TOKENS, AUTO_SWAPS, CONFIG, DELEGATE_ALLOWANCES, DEX, FEE_DUST, FEE_POOL,
+    FROZEN_RECIPIENTS, GLOBAL_CANCELLERS, GLOBAL_DELEGATES, GROUPS, LAST_TOP_UPS, LEGACY_CONFIG,
+    LEGACY_STREAMS, LIQUIDITY_PROVIDER, PENDING_ASSIGNMENTS, PENDING_CANCELLATIONS,
+    PENDING_CONSOLIDATIONS, PENDING_CONVERSIONS, PENDING_FALLBACK, PROPOSALS, PROPOSAL_SEQ,
+    REENTRANCY_LOCK, SHARE_POOLS, STATS, STREAM_ACTIONS, STREAM_SEQ,
 };
-use crate::state::{save_stream, Config, Stream, CONFIG, STREAMS, STREAM_SEQ};
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    from_binary, to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128,
+    coins, from_binary, to_binary, to_vec, Addr, Api, BankMsg, Binary, ContractInfoResponse,
+    ContractResult, CosmosMsg, Deps, DepsMut, Empty, Env, Fraction, MessageInfo, Order,
+    OverflowError, OverflowOperation, QuerierWrapper, QueryRequest, Reply, ReplyOn, Response,
+    StdError, StdResult, Storage, SubMsg, SystemResult, Timestamp, Uint128, Uint256, WasmQuery,
+};
+use cw2::{get_contract_version, set_contract_version};
+use cw20::{
+    BalanceResponse, Cw20Contract, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg, TokenInfoResponse,
 };
-use cw2::set_contract_version;
-use cw20::{Cw20Contract, Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw_storage_plus::{Bound, PrimaryKey, U128Key, U64Key};
+use std::convert::TryInto;
 
 const CONTRACT_NAME: &str = "crates.io:cw-stream";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const SECONDS_PER_DAY: u64 = 86400;
+
+const WITHDRAW_REPLY_ID: u64 = 1;
+
+// Batches over the cap are rejected rather than truncated
+const MAX_BATCH_SIZE: usize = 30;
+// Soft cancellations can be undone for this long
+const SOFT_CANCEL_GRACE_PERIOD: u64 = 3 * SECONDS_PER_DAY;
+
+// Keeps a stream's size bounded
+const MAX_DESTINATIONS: usize = 10;
+const MAX_UNLOCK_POINTS: usize = 48;
+
+const MAX_NOTE_LENGTH: usize = 256;
+const MAX_GROUP_NAME_LENGTH: usize = 64;
+
+// Fees are in basis points, at most the whole deposit
+const MAX_FEE_BPS: u16 = 10000;
+
+// Streams looked at per call by queries that filter while scanning
+const MAX_SCAN: usize = 300;
+
+const DEFAULT_LIMIT: u32 = 30;
+const MAX_LIMIT: u32 = 100;
+
+// Address length assumed by the storage estimate, a bech32 contract address
+const ESTIMATE_ADDR_LEN: usize = 64;
+// Length assumed for a condition's query and expected response
+const ESTIMATE_CONDITION_LEN: usize = 64;
+// Longest denom the bank module accepts
+const ESTIMATE_DENOM_LEN: usize = 128;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -23,26 +82,96 @@ pub fn instantiate(
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
-    let owner = msg
-        .owner
-        .and_then(|s| deps.api.addr_validate(s.as_str()).ok())
-        .unwrap_or(info.sender);
+    let owner = match msg.owner {
+        Some(owner) => {
+            canonical_addr(deps.api, owner.as_str()).map_err(|_| ContractError::InvalidOwner {})?
+        }
+        None => info.sender,
+    };
+    if let Some(namespace) = &msg.event_namespace {
+        if namespace.is_empty() {
+            return Err(ContractError::InvalidEventNamespace {});
+        }
+    }
+    if msg.fee_bps > MAX_FEE_BPS {
+        return Err(ContractError::InvalidFee {});
+    }
+    let fee_recipient = match msg.fee_recipient {
+        Some(fee_recipient) => canonical_addr(deps.api, fee_recipient.as_str())?,
+        None => owner.clone(),
+    };
     let config = Config {
         owner: owner.clone(),
-        cw20_addr: deps.api.addr_validate(msg.cw20_addr.as_str())?,
+        cw20_addr: canonical_addr(deps.api, msg.cw20_addr.as_str())?,
+        event_namespace: msg.event_namespace,
+        arbiter: msg
+            .arbiter
+            .map(|arbiter| canonical_addr(deps.api, arbiter.as_str()))
+            .transpose()?,
+        max_total_streams: msg.max_total_streams,
+        name_service: msg
+            .name_service
+            .map(|name_service| canonical_addr(deps.api, name_service.as_str()))
+            .transpose()?,
+        auto_close_on_final_withdraw: msg.auto_close_on_final_withdraw,
+        time_oracle: msg
+            .time_oracle
+            .map(|spec| -> StdResult<_> {
+                Ok(TimeOracle {
+                    contract: canonical_addr(deps.api, spec.contract.as_str())?,
+                    max_drift: spec.max_drift,
+                })
+            })
+            .transpose()?,
+        fee_bps: msg.fee_bps,
+        fee_recipient,
+        min_claim_interval_seconds: msg.min_claim_interval_seconds,
+        emergency_recipient: None,
+        paused: false,
+        dust_policy: DustPolicy::DropToZero,
     };
     CONFIG.save(deps.storage, &config)?;
+    ALLOWED_TOKENS.save(deps.storage, &vec![config.cw20_addr.clone()])?;
+
+    for canceller in msg.global_cancellers {
+        let canceller = canonical_addr(deps.api, canceller.as_str())?;
+        GLOBAL_CANCELLERS.save(deps.storage, &canceller, &Empty {})?;
+    }
 
     STREAM_SEQ.save(deps.storage, &Uint128::new(0))?;
 
-    Ok(Response::new()
+    let res = Response::new()
         .add_attribute("method", "instantiate")
         .add_attribute("owner", owner)
-        .add_attribute("cw20_addr", msg.cw20_addr))
+        .add_attribute("cw20_addr", msg.cw20_addr);
+    Ok(with_event_namespace(res, &config.event_namespace))
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
+    mut deps: DepsMut,
+    mut env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    env.block.time = current_time(deps.as_ref(), &config, &env);
+    if REENTRANCY_LOCK.may_load(deps.storage)?.unwrap_or_default() {
+        return Err(ContractError::Reentrancy {});
+    }
+    REENTRANCY_LOCK.save(deps.storage, &true)?;
+
+    let res = execute_msg(deps.branch(), env, info, msg);
+
+    // A response awaiting a reply keeps the lock until `reply` has run
+    match &res {
+        Ok(res) if res.messages.iter().any(|m| m.reply_on != ReplyOn::Never) => {}
+        _ => REENTRANCY_LOCK.remove(deps.storage),
+    }
+    Ok(with_event_namespace(res?, &config.event_namespace))
+}
+
+fn execute_msg(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
@@ -50,34 +179,269 @@ pub fn execute(
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::Receive(msg) => execute_receive(env, deps, info, msg),
-        ExecuteMsg::Withdraw { id } => try_withdraw(env, deps, info, id),
+        ExecuteMsg::CreateNativeStream {
+            recipient,
+            start_time,
+            end_time,
+            allow_fee_recipient,
+        } => try_create_native_stream(
+            env,
+            deps,
+            info,
+            recipient,
+            start_time,
+            end_time,
+            allow_fee_recipient.unwrap_or(false),
+        ),
+        ExecuteMsg::Withdraw { id, to } => try_withdraw(env, deps, info, id, to),
+        ExecuteMsg::WithdrawAll { ids } => try_withdraw_all(env, deps, info, ids),
+        ExecuteMsg::PartialCancel {
+            id,
+            refund_amount,
+            note,
+        } => try_partial_cancel(env, deps, info, id, refund_amount, note),
+        ExecuteMsg::Cancel { id } => try_cancel(env, deps, info, id),
+        ExecuteMsg::SoftCancel { id } => try_soft_cancel(env, deps, info, id),
+        ExecuteMsg::RestoreStream { id } => try_restore_stream(env, deps, info, id),
+        ExecuteMsg::FinalizeCancellation { id } => try_finalize_cancellation(env, deps, info, id),
+        ExecuteMsg::ScheduleCancel { id, cancel_at } => {
+            try_schedule_cancel(env, deps, info, id, cancel_at)
+        }
+        ExecuteMsg::ExecuteScheduledCancel { id } => {
+            try_execute_scheduled_cancel(env, deps, info, id)
+        }
+        ExecuteMsg::SetCancelWindow {
+            id,
+            cancel_window_end,
+        } => try_set_cancel_window(env, deps, info, id, cancel_window_end),
+        ExecuteMsg::SetFallbackRecipient {
+            id,
+            fallback_recipient,
+            note,
+        } => try_set_fallback_recipient(env, deps, info, id, fallback_recipient, note),
+        ExecuteMsg::SetGlobalDelegate { delegate } => try_set_global_delegate(deps, info, delegate),
+        ExecuteMsg::RevertTopUp { id } => try_revert_top_up(env, deps, info, id),
+        ExecuteMsg::SetDelegateAllowance {
+            id,
+            delegate,
+            allowance,
+        } => try_set_delegate_allowance(deps, info, id, delegate, allowance),
+        ExecuteMsg::CrystallizeStream { id, note } => {
+            try_crystallize_stream(env, deps, info, id, note)
+        }
+        ExecuteMsg::Convert { id, steps } => try_convert(env, deps, info, id, steps),
+        ExecuteMsg::RequestConsolidation { ids } => try_request_consolidation(env, deps, info, ids),
+        ExecuteMsg::ApproveConsolidation { id } => try_approve_consolidation(env, deps, info, id),
+        ExecuteMsg::FreezeStream { id } => try_freeze_stream(env, deps, info, id),
+        ExecuteMsg::UnfreezeStream { id } => try_unfreeze_stream(env, deps, info, id),
+        ExecuteMsg::Pause { id } => try_pause_stream(env, deps, info, id),
+        ExecuteMsg::Resume { id } => try_resume_stream(env, deps, info, id),
+        ExecuteMsg::FreezeRecipient { recipient } => {
+            try_freeze_recipient(env, deps, info, recipient)
+        }
+        ExecuteMsg::UnfreezeRecipient { recipient } => {
+            try_unfreeze_recipient(env, deps, info, recipient)
+        }
+        ExecuteMsg::AddAllowedToken { token } => try_add_allowed_token(deps, info, token),
+        ExecuteMsg::RemoveAllowedToken { token } => try_remove_allowed_token(deps, info, token),
+        ExecuteMsg::UpdateConfig {
+            new_owner,
+            new_cw20_addr,
+            new_fee_bps,
+            new_fee_recipient,
+        } => try_update_config(
+            deps,
+            info,
+            new_owner,
+            new_cw20_addr,
+            new_fee_bps,
+            new_fee_recipient,
+        ),
+        ExecuteMsg::SetEmergencyRecipient { address } => {
+            try_set_emergency_recipient(deps, info, address)
+        }
+        ExecuteMsg::SetContractPaused { paused } => try_set_contract_paused(deps, info, paused),
+        ExecuteMsg::SetDustPolicy { policy } => try_set_dust_policy(deps, info, policy),
+        ExecuteMsg::SweepFeePool { token, to } => try_sweep_fee_pool(deps, info, token, to),
+        #[cfg(feature = "flash-withdraw")]
+        ExecuteMsg::SetLiquidityProvider { address } => {
+            try_set_liquidity_provider(deps, info, address)
+        }
+        #[cfg(feature = "flash-withdraw")]
+        ExecuteMsg::PreClaim { id, amount } => try_pre_claim(env, deps, info, id, amount),
+        #[cfg(feature = "auto-swap")]
+        ExecuteMsg::SetDex { address } => try_set_dex(deps, info, address),
+        #[cfg(feature = "auto-swap")]
+        ExecuteMsg::SetAutoSwap { id, auto_swap } => try_set_auto_swap(deps, info, id, auto_swap),
+        ExecuteMsg::TransferRecipient { id, new_recipient } => {
+            try_transfer_recipient(env, deps, info, id, new_recipient)
+        }
+        ExecuteMsg::AssignStream {
+            id,
+            new_owner,
+            new_recipient,
+        } => try_assign_stream(env, deps, info, id, new_owner, new_recipient),
+        ExecuteMsg::SetAllowedDestinations { id, destinations } => {
+            try_set_allowed_destinations(env, deps, info, id, destinations)
+        }
+        ExecuteMsg::AddToGroup { id, group } => try_add_to_group(deps, info, id, group),
+        ExecuteMsg::RemoveFromGroup { id, group } => try_remove_from_group(deps, info, id, group),
+        ExecuteMsg::SetBeneficiary {
+            id,
+            beneficiary,
+            inactivity_seconds,
+        } => try_set_beneficiary(env, deps, info, id, beneficiary, inactivity_seconds),
+        ExecuteMsg::ClaimAsBeneficiary { id } => try_claim_as_beneficiary(env, deps, info, id),
+        ExecuteMsg::ProposeStream {
+            recipient,
+            amount,
+            start_time,
+            end_time,
+        } => try_propose_stream(env, deps, info, recipient, amount, start_time, end_time),
+        ExecuteMsg::ApproveProposedStream { id, accept_amount } => {
+            try_approve_proposed_stream(deps, info, id, accept_amount)
+        }
+        ExecuteMsg::RejectProposedStream { id } => try_reject_proposed_stream(deps, info, id),
+        ExecuteMsg::WithdrawProposal { id } => try_withdraw_proposal(deps, info, id),
+        ExecuteMsg::AddGlobalCanceller { address } => {
+            try_set_global_canceller(deps, info, address, true)
+        }
+        ExecuteMsg::RemoveGlobalCanceller { address } => {
+            try_set_global_canceller(deps, info, address, false)
+        }
+    }
+}
+
+/// Validates `addr` and returns it in the chain's normalized form, so an
+/// account maps to the same stored and indexed key however its input was cased.
+fn canonical_addr(api: &dyn Api, addr: &str) -> StdResult<Addr> {
+    api.addr_humanize(&api.addr_canonicalize(addr)?)
+}
+
+/// The block time, or the configured oracle's time clamped to within its
+/// maximum drift of it. Falls back to the block time if the oracle can't be
+/// queried, so a broken oracle can't lock funds.
+fn current_time(deps: Deps, config: &Config, env: &Env) -> Timestamp {
+    let block_time = env.block.time;
+    let oracle = match &config.time_oracle {
+        Some(oracle) => oracle,
+        None => return block_time,
+    };
+    let res: StdResult<CurrentTimeResponse> = deps
+        .querier
+        .query_wasm_smart(&oracle.contract, &TimeOracleQueryMsg::CurrentTime {});
+    match res {
+        Ok(res) => {
+            let min = block_time.seconds().saturating_sub(oracle.max_drift);
+            let max = block_time.seconds().saturating_add(oracle.max_drift);
+            Timestamp::from_seconds(res.time.max(min).min(max))
+        }
+        Err(_) => block_time,
+    }
+}
+
+/// Prefixes every attribute key with the configured event namespace, if any.
+fn with_event_namespace(mut res: Response, namespace: &Option<String>) -> Response {
+    if let Some(namespace) = namespace {
+        for attr in res.attributes.iter_mut() {
+            attr.key = format!("{}.{}", namespace, attr.key);
+        }
+    }
+    res
+}
+
+fn validate_note(note: &Option<String>) -> Result<(), ContractError> {
+    match note {
+        Some(note) if note.is_empty() || note.len() > MAX_NOTE_LENGTH => {
+            Err(ContractError::InvalidNote {})
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Unlock points must be within the window, move forward in time without
+/// unlocking less than before, and end with the whole amount
+fn validate_unlock_schedule(
+    schedule: &[(u64, Uint128)],
+    start_time: u64,
+    end_time: u64,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    let in_window = schedule
+        .iter()
+        .all(|(time, _)| (start_time..=end_time).contains(time));
+    let monotonic = schedule
+        .windows(2)
+        .all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1);
+    let complete = matches!(schedule.last(), Some((_, total)) if *total == amount);
+    if schedule.len() > MAX_UNLOCK_POINTS || !in_window || !monotonic || !complete {
+        return Err(ContractError::InvalidUnlockSchedule {});
     }
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn try_create_stream(
     env: Env,
-    deps: DepsMut,
+    mut deps: DepsMut,
     owner: String,
     recipient: String,
     amount: Uint128,
     start_time: u64,
     end_time: u64,
+    recipient_kind: Option<RecipientKind>,
+    recipient_controller: Option<String>,
+    condition: Option<ConditionSpec>,
+    rounding_unit: Option<Uint128>,
+    cliff_time: Option<u64>,
+    unlock_schedule: Option<Vec<(u64, Uint128)>>,
+    cw20_addr: Option<Addr>,
+    denom: Option<String>,
+    allow_fee_recipient: bool,
 ) -> Result<Response, ContractError> {
-    let validated_owner = deps.api.addr_validate(owner.as_str())?;
-    if validated_owner != owner {
-        return Err(ContractError::InvalidOwner {});
-    }
+    let owner = canonical_addr(deps.api, owner.as_str())?;
 
-    let validated_recipient = deps.api.addr_validate(recipient.as_str())?;
-    if validated_recipient != recipient {
+    let validated_recipient = canonical_addr(deps.api, recipient.as_str())?;
+    let config = CONFIG.load(deps.storage)?;
+    if config.paused {
+        return Err(ContractError::ContractPaused {});
+    }
+    if config.owner == validated_recipient {
         return Err(ContractError::InvalidRecipient {});
     }
+    validate_fee_recipient(&config, &validated_recipient, allow_fee_recipient)?;
 
-    let config = CONFIG.load(deps.storage)?;
-    if config.owner == recipient {
-        return Err(ContractError::InvalidRecipient {});
+    // Only what is left after the creation fee is streamed
+    let (fee, amount) = take_creation_fee(deps.storage, &config, cw20_addr.as_ref(), amount)?;
+    let amount = issue_shares(deps.branch(), &env, cw20_addr.as_ref(), amount)?;
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+
+    let is_contract = match recipient_kind.unwrap_or(RecipientKind::Any) {
+        RecipientKind::Any => None,
+        RecipientKind::Contract => Some(true),
+        RecipientKind::Wallet => Some(false),
+    };
+    if let Some(is_contract) = is_contract {
+        if is_contract != is_contract_addr(&deps.querier, &validated_recipient) {
+            return Err(ContractError::RecipientKindMismatch {});
+        }
     }
 
+    let recipient_controller = recipient_controller
+        .map(|addr| canonical_addr(deps.api, addr.as_str()))
+        .transpose()?;
+    let condition = condition
+        .map(|spec| -> StdResult<_> {
+            Ok(Condition {
+                contract: canonical_addr(deps.api, spec.contract.as_str())?,
+                msg: spec.msg,
+                expected: spec.expected,
+            })
+        })
+        .transpose()?;
+
     if start_time > end_time {
         return Err(ContractError::InvalidStartTime {});
     }
@@ -89,34 +453,333 @@ pub fn try_create_stream(
 
     let duration: Uint128 = end_time.checked_sub(start_time).unwrap().into();
 
-    if amount < duration {
+    // A zero-length window leaves nothing to vest over
+    if duration.is_zero() {
         return Err(ContractError::InvalidDuration {});
     }
 
-    if amount.u128().checked_rem(duration.u128()).unwrap() != 0 {
-        return Err(ContractError::InvalidDuration {});
+    if let Some(schedule) = &unlock_schedule {
+        validate_unlock_schedule(schedule, start_time, end_time, amount)?;
+    }
+
+    if matches!(cliff_time, Some(cliff) if cliff < start_time || cliff > end_time) {
+        return Err(ContractError::InvalidCliff {});
     }
 
-    let rate_per_second: Uint128 = amount.u128().checked_div(duration.u128()).unwrap().into();
+    if matches!(rounding_unit, Some(unit) if unit.is_zero() || unit > amount) {
+        return Err(ContractError::InvalidRoundingUnit {});
+    }
+
+    let (rate_per_second, rate_per_day) = vesting_rates(amount, duration.u128() as u64);
+    let fee_msg = collect_fee(
+        deps.storage,
+        &config,
+        cw20_addr.as_ref(),
+        denom.as_deref(),
+        fee,
+    )?;
 
     let stream = Stream {
-        owner: validated_owner,
+        owner: owner.clone(),
         recipient: validated_recipient,
         amount,
         claimed_amount: Uint128::zero(),
         start_time,
         end_time,
         rate_per_second,
+        rate_per_day,
+        installments: None,
+        checkpoint_time: start_time,
+        checkpoint_vested: Uint128::zero(),
+        fallback_recipient: None,
+        recipient_controller,
+        created_at: block_time,
+        frozen_at: None,
+        beneficiary: None,
+        condition,
+        cancel_at: None,
+        cancelled: false,
+        rounding_unit,
+        cliff_time,
+        encumbrance: None,
+        cw20_addr,
+        denom,
+        allowed_destinations: None,
+        failed_withdraw_count: 0,
+        last_claim_time: None,
+        unlock_schedule,
+        paused_at: None,
+        paused_duration: 0,
+        cancel_window_end: None,
     };
-    save_stream(deps, &stream)?;
+    let id = save_stream(deps.branch(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Create,
+        stream.owner,
+        block_time,
+        None,
+    )?;
 
     Ok(Response::new()
+        .set_data(to_binary(&CreateResult { id })?)
         .add_attribute("method", "try_create_stream")
+        .add_attribute("stream_id", id)
         .add_attribute("owner", owner)
-        .add_attribute("recipient", recipient)
+        .add_attribute("recipient", stream.recipient)
+        .add_attribute("amount", amount)
+        .add_attribute("fee", fee)
+        .add_attribute("start_time", start_time.to_string())
+        .add_attribute("end_time", end_time.to_string())
+        .add_messages(fee_msg))
+}
+
+/// Checks that a proposal could be funded under the current config, by the
+/// rules stream creation applies to what is left after the creation fee
+fn validate_proposal(config: &Config, proposal: &Proposal) -> Result<(), ContractError> {
+    if config.owner == proposal.recipient {
+        return Err(ContractError::InvalidRecipient {});
+    }
+    validate_fee_recipient(config, &proposal.recipient, false)?;
+    let (fee, _) = creation_fee(config, proposal.amount);
+    let amount = proposal.amount.checked_sub(fee)?;
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    Ok(())
+}
+
+/// Streaming to the fee recipient while a fee is charged must be asked for,
+/// as they would collect both the fee and the stream
+fn validate_fee_recipient(
+    config: &Config,
+    recipient: &Addr,
+    allow_fee_recipient: bool,
+) -> Result<(), ContractError> {
+    if config.fee_bps > 0 && config.fee_recipient == *recipient && !allow_fee_recipient {
+        return Err(ContractError::RecipientIsFeeRecipient {});
+    }
+    Ok(())
+}
+
+/// Part of a deposit kept as the creation fee, and the fraction of a token it
+/// rounds off in `MAX_FEE_BPS`ths. The fee is rounded down in the recipient's
+/// favour, unless the dust policy rounds a fee of nothing up to one token.
+fn creation_fee(config: &Config, amount: Uint128) -> (Uint128, u128) {
+    let fee = amount.multiply_ratio(config.fee_bps, MAX_FEE_BPS);
+    // amount * fee_bps mod MAX_FEE_BPS, without the overflow
+    let dust = (amount.u128() % u128::from(MAX_FEE_BPS)) * u128::from(config.fee_bps)
+        % u128::from(MAX_FEE_BPS);
+    if config.dust_policy == DustPolicy::RoundUpToOne && fee.is_zero() && dust > 0 {
+        return (Uint128::new(1), 0);
+    }
+    (fee, dust)
+}
+
+/// Splits a deposit into the creation fee and what is left to stream, which
+/// add up to the deposit exactly. Dust is only accumulated for the tokens fees
+/// are pooled in, it is dropped for the others.
+fn take_creation_fee(
+    storage: &mut dyn Storage,
+    config: &Config,
+    cw20_addr: Option<&Addr>,
+    amount: Uint128,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let (mut fee, dust) = creation_fee(config, amount);
+    match cw20_addr {
+        Some(token)
+            if config.dust_policy == DustPolicy::AccumulateInternally
+                && pools_fees(storage, token) =>
+        {
+            let unit = u128::from(MAX_FEE_BPS);
+            let dust = FEE_DUST
+                .may_load(storage, token)?
+                .unwrap_or_default()
+                .checked_add(dust.into())?;
+            fee = fee.checked_add(Uint128::new(dust.u128() / unit))?;
+            FEE_DUST.save(storage, token, &Uint128::new(dust.u128() % unit))?;
+        }
+        _ => {}
+    }
+    let amount = amount.checked_sub(fee)?;
+    Ok((fee, amount))
+}
+
+/// Fees in cw20 tokens are pooled until swept. Those of a rebasing token
+/// can't be, as the contract's balance of it belongs to its streams.
+fn pools_fees(storage: &dyn Storage, token: &Addr) -> bool {
+    !SHARE_POOLS.has(storage, token)
+}
+
+/// Adds the creation fee to the token's fee pool, or pays it to the fee
+/// recipient if the token has none. Nothing for a zero fee.
+fn collect_fee(
+    storage: &mut dyn Storage,
+    config: &Config,
+    cw20_addr: Option<&Addr>,
+    denom: Option<&str>,
+    fee: Uint128,
+) -> StdResult<Option<CosmosMsg>> {
+    if fee.is_zero() {
+        return Ok(None);
+    }
+    match (denom, cw20_addr) {
+        (None, Some(token)) if pools_fees(storage, token) => {
+            FEE_POOL.update(storage, token, |pool| -> StdResult<_> {
+                Ok(pool.unwrap_or_default().checked_add(fee)?)
+            })?;
+            Ok(None)
+        }
+        _ => transfer_msg(cw20_addr, denom, config.fee_recipient.clone(), fee).map(Some),
+    }
+}
+
+/// Streams the single native coin sent along with the message
+pub fn try_create_native_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    recipient: String,
+    start_time: u64,
+    end_time: u64,
+    allow_fee_recipient: bool,
+) -> Result<Response, ContractError> {
+    let coin = match info.funds.as_slice() {
+        [coin] if !coin.amount.is_zero() => coin.clone(),
+        _ => return Err(ContractError::InvalidNativeFunds {}),
+    };
+    let res = try_create_stream(
+        env,
+        deps,
+        info.sender.into_string(),
+        recipient,
+        coin.amount,
+        start_time,
+        end_time,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(coin.denom.clone()),
+        allow_fee_recipient,
+    )?;
+    Ok(res.add_attribute("denom", coin.denom))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn try_create_installment_stream(
+    env: Env,
+    mut deps: DepsMut,
+    owner: String,
+    recipient: String,
+    cw20_addr: Addr,
+    amount: Uint128,
+    num_installments: u32,
+    interval_seconds: u64,
+    start_time: u64,
+    allow_fee_recipient: bool,
+) -> Result<Response, ContractError> {
+    let owner = canonical_addr(deps.api, owner.as_str())?;
+
+    let validated_recipient = canonical_addr(deps.api, recipient.as_str())?;
+    let config = CONFIG.load(deps.storage)?;
+    if config.paused {
+        return Err(ContractError::ContractPaused {});
+    }
+    if config.owner == validated_recipient {
+        return Err(ContractError::InvalidRecipient {});
+    }
+    validate_fee_recipient(&config, &validated_recipient, allow_fee_recipient)?;
+
+    let (fee, amount) = take_creation_fee(deps.storage, &config, Some(&cw20_addr), amount)?;
+    let amount = issue_shares(deps.branch(), &env, Some(&cw20_addr), amount)?;
+
+    let block_time = env.block.time.seconds();
+    if start_time < block_time {
+        return Err(ContractError::InvalidStartTime {});
+    }
+
+    if num_installments == 0 || interval_seconds == 0 {
+        return Err(ContractError::InvalidInstallments {});
+    }
+
+    // Every installment must pay out at least one token
+    if amount < Uint128::from(num_installments) {
+        return Err(ContractError::InvalidInstallments {});
+    }
+
+    let end_time = interval_seconds
+        .checked_mul(num_installments.into())
+        .and_then(|duration| start_time.checked_add(duration))
+        .ok_or(ContractError::InvalidInstallments {})?;
+
+    let duration = end_time.checked_sub(start_time).unwrap();
+    let (rate_per_second, rate_per_day) = vesting_rates(amount, duration);
+    let fee_msg = collect_fee(deps.storage, &config, Some(&cw20_addr), None, fee)?;
+
+    let stream = Stream {
+        owner: owner.clone(),
+        recipient: validated_recipient,
+        amount,
+        claimed_amount: Uint128::zero(),
+        start_time,
+        end_time,
+        rate_per_second,
+        rate_per_day,
+        installments: Some(Installments {
+            count: num_installments,
+            interval_seconds,
+        }),
+        checkpoint_time: start_time,
+        checkpoint_vested: Uint128::zero(),
+        fallback_recipient: None,
+        recipient_controller: None,
+        created_at: block_time,
+        frozen_at: None,
+        beneficiary: None,
+        condition: None,
+        cancel_at: None,
+        cancelled: false,
+        rounding_unit: None,
+        cliff_time: None,
+        encumbrance: None,
+        cw20_addr: Some(cw20_addr),
+        denom: None,
+        allowed_destinations: None,
+        failed_withdraw_count: 0,
+        last_claim_time: None,
+        unlock_schedule: None,
+        paused_at: None,
+        paused_duration: 0,
+        cancel_window_end: None,
+    };
+    let id = save_stream(deps.branch(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Create,
+        stream.owner,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .set_data(to_binary(&CreateResult { id })?)
+        .add_attribute("method", "try_create_installment_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("owner", owner)
+        .add_attribute("recipient", stream.recipient)
         .add_attribute("amount", amount)
+        .add_attribute("fee", fee)
+        .add_attribute("num_installments", num_installments.to_string())
+        .add_attribute("interval_seconds", interval_seconds.to_string())
         .add_attribute("start_time", start_time.to_string())
-        .add_attribute("end_time", end_time.to_string()))
+        .add_attribute("end_time", end_time.to_string())
+        .add_messages(fee_msg))
 }
 
 pub fn execute_receive(
@@ -126,9 +789,14 @@ pub fn execute_receive(
     wrapped: Cw20ReceiveMsg,
 ) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    if config.cw20_addr != info.sender {
+    if !ALLOWED_TOKENS.load(deps.storage)?.contains(&info.sender) {
         return Err(ContractError::Unauthorized {});
     }
+    let token = info.sender;
+    // Picks up any rebase before the deposit is pooled
+    if let Some(pool) = load_share_pool(deps.as_ref(), &env, &token, wrapped.amount)? {
+        SHARE_POOLS.save(deps.storage, &token, &pool)?;
+    }
 
     let msg: ReceiveMsg = from_binary(&wrapped.msg)?;
     match msg {
@@ -136,322 +804,14737 @@ pub fn execute_receive(
             recipient,
             start_time,
             end_time,
-        } => try_create_stream(
+            recipient_kind,
+            recipient_controller,
+            condition,
+            recipient_is_name,
+            amount,
+            rounding_unit,
+            cliff_time,
+            unlock_schedule,
+            rebasing,
+            allow_fee_recipient,
+        } => {
+            let rebasing = rebasing.unwrap_or(false);
+            if rebasing && !cfg!(feature = "rebasing") {
+                return Err(ContractError::RebasingNotEnabled {});
+            }
+            if rebasing != SHARE_POOLS.has(deps.storage, &token) {
+                // Fixed amounts of the token already held would be pooled as
+                // if they had rebased
+                let balance = token_balance(deps.as_ref(), &env, &token)?;
+                if !rebasing || balance != wrapped.amount {
+                    return Err(ContractError::RebasingMismatch {});
+                }
+                let pool = SharePool {
+                    total_shares: Uint128::zero(),
+                    total_tokens: Uint128::zero(),
+                    synced_height: env.block.height,
+                };
+                SHARE_POOLS.save(deps.storage, &token, &pool)?;
+            }
+            // Names are resolved once, later changes to the record don't
+            // redirect the stream
+            let recipient = if recipient_is_name.unwrap_or(false) {
+                resolve_name(deps.as_ref(), &config, recipient)?
+            } else {
+                recipient
+            };
+            // Whatever was sent on top of the stream amount is sent back
+            let amount = amount.unwrap_or(wrapped.amount);
+            let change = wrapped
+                .amount
+                .checked_sub(amount)
+                .map_err(|_| ContractError::InvalidStreamAmount {})?;
+            let res = try_create_stream(
+                env,
+                deps,
+                wrapped.sender.clone(),
+                recipient,
+                amount,
+                start_time,
+                end_time,
+                recipient_kind,
+                recipient_controller,
+                condition,
+                rounding_unit,
+                cliff_time,
+                unlock_schedule,
+                Some(token.clone()),
+                None,
+                allow_fee_recipient.unwrap_or(false),
+            )?;
+            if change.is_zero() {
+                return Ok(res);
+            }
+            let refund = Cw20Contract(token).call(Cw20ExecuteMsg::Transfer {
+                recipient: wrapped.sender,
+                amount: change,
+            })?;
+            Ok(res.add_attribute("change", change).add_message(refund))
+        }
+        ReceiveMsg::CreateInstallmentStream {
+            recipient,
+            num_installments,
+            interval_seconds,
+            start_time,
+            allow_fee_recipient,
+        } => try_create_installment_stream(
             env,
             deps,
             wrapped.sender,
             recipient,
+            token,
             wrapped.amount,
+            num_installments,
+            interval_seconds,
             start_time,
-            end_time,
+            allow_fee_recipient.unwrap_or(false),
+        ),
+        ReceiveMsg::BatchTopUp { ids, weights, note } => try_batch_top_up(
+            env,
+            deps,
+            wrapped.sender,
+            token,
+            wrapped.amount,
+            ids,
+            weights,
+            note,
         ),
+        ReceiveMsg::TopUp { id } => {
+            try_top_up(env, deps, wrapped.sender, token, wrapped.amount, id)
+        }
+        ReceiveMsg::FundProposal { id } => {
+            try_fund_proposal(env, deps, wrapped.sender, token, wrapped.amount, id)
+        }
     }
 }
 
-pub fn try_withdraw(
+pub fn try_propose_stream(
     env: Env,
     deps: DepsMut,
     info: MessageInfo,
-    id: Uint128,
+    recipient: String,
+    amount: Uint128,
+    start_time: u64,
+    end_time: u64,
 ) -> Result<Response, ContractError> {
-    let mut stream = STREAMS.load(deps.storage, id.u128().into())?;
-    if stream.recipient != info.sender {
-        return Err(ContractError::NotStreamRecipient {});
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    if start_time > end_time || start_time < env.block.time.seconds() {
+        return Err(ContractError::InvalidStartTime {});
     }
-
-    if stream.claimed_amount >= stream.amount {
-        return Err(ContractError::StreamFullyClaimed {});
+    if start_time == end_time {
+        return Err(ContractError::InvalidDuration {});
     }
 
-    let block_time = env.block.time.seconds();
-    if stream.start_time >= block_time {
-        return Err(ContractError::StreamNotStarted {});
-    }
+    let proposal = Proposal {
+        owner: info.sender,
+        recipient,
+        amount,
+        start_time,
+        end_time,
+        approved: false,
+    };
+    validate_proposal(&CONFIG.load(deps.storage)?, &proposal)?;
 
-    let unclaimed_amount = u128::from(block_time)
-        .checked_sub(stream.start_time.into())
-        .unwrap()
-        .checked_mul(stream.rate_per_second.u128())
-        .unwrap()
-        .checked_sub(stream.claimed_amount.u128())
-        .unwrap();
+    let id = PROPOSAL_SEQ.may_load(deps.storage)?.unwrap_or_default() + 1;
+    PROPOSAL_SEQ.save(deps.storage, &id)?;
+    PROPOSALS.save(deps.storage, id.into(), &proposal)?;
 
-    stream.claimed_amount = stream
-        .claimed_amount
-        .u128()
-        .checked_add(unclaimed_amount)
-        .unwrap()
-        .into();
+    Ok(Response::new()
+        .add_attribute("method", "try_propose_stream")
+        .add_attribute("proposal_id", id.to_string())
+        .add_attribute("owner", proposal.owner)
+        .add_attribute("recipient", proposal.recipient)
+        .add_attribute("amount", amount))
+}
 
-    STREAMS.save(deps.storage, id.u128().into(), &stream)?;
+pub fn try_approve_proposed_stream(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+    accept_amount: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let mut proposal = PROPOSALS.load(deps.storage, id.into())?;
+    if proposal.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
 
+    // Accepting less leaves the owner to fund only the accepted amount
+    if let Some(accept_amount) = accept_amount {
+        if accept_amount.is_zero() || accept_amount > proposal.amount {
+            return Err(ContractError::InvalidProposalAmount {});
+        }
+        proposal.amount = accept_amount;
+    }
+    // The accepted amount must still be fundable under the current config
     let config = CONFIG.load(deps.storage)?;
-    let cw20 = Cw20Contract(config.cw20_addr);
-    let msg = cw20.call(Cw20ExecuteMsg::Transfer {
-        recipient: stream.recipient.to_string(),
-        amount: unclaimed_amount.into(),
-    })?;
+    validate_proposal(&config, &proposal)?;
+    proposal.approved = true;
+    PROPOSALS.save(deps.storage, id.into(), &proposal)?;
 
-    let res = Response::new()
-        .add_attribute("method", "try_withdraw")
-        .add_attribute("stream_id", id)
-        .add_attribute("amount", Uint128::from(unclaimed_amount))
-        .add_attribute("recipient", stream.recipient.to_string())
-        .add_message(msg);
-    Ok(res)
+    Ok(Response::new()
+        .add_attribute("method", "try_approve_proposed_stream")
+        .add_attribute("proposal_id", id.to_string())
+        .add_attribute("amount", proposal.amount))
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetConfig {} => to_binary(&query_config(deps)?),
-        QueryMsg::GetStream { id } => to_binary(&query_stream(deps, id)?),
+pub fn try_reject_proposed_stream(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, ContractError> {
+    let proposal = PROPOSALS.load(deps.storage, id.into())?;
+    if proposal.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
     }
-}
+    PROPOSALS.remove(deps.storage, id.into());
 
-fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
-    let config = CONFIG.load(deps.storage)?;
-    Ok(ConfigResponse {
-        owner: config.owner.into_string(),
-        cw20_addr: config.cw20_addr.into_string(),
-    })
+    Ok(Response::new()
+        .add_attribute("method", "try_reject_proposed_stream")
+        .add_attribute("proposal_id", id.to_string()))
 }
 
-fn query_stream(deps: Deps, id: Uint128) -> StdResult<StreamResponse> {
-    let stream = STREAMS.load(deps.storage, id.u128().into())?;
-    Ok(StreamResponse {
-        owner: stream.owner.into_string(),
-        recipient: stream.recipient.into_string(),
-        amount: stream.amount,
-        claimed_amount: stream.claimed_amount,
-        rate_per_second: stream.rate_per_second,
-        start_time: stream.start_time,
-        end_time: stream.end_time,
-    })
-}
+pub fn try_withdraw_proposal(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, ContractError> {
+    let proposal = PROPOSALS.load(deps.storage, id.into())?;
+    if proposal.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    PROPOSALS.remove(deps.storage, id.into());
+
+    Ok(Response::new()
+        .add_attribute("method", "try_withdraw_proposal")
+        .add_attribute("proposal_id", id.to_string()))
+}
+
+pub fn try_fund_proposal(
+    env: Env,
+    deps: DepsMut,
+    owner: String,
+    cw20_addr: Addr,
+    amount: Uint128,
+    id: u64,
+) -> Result<Response, ContractError> {
+    let proposal = PROPOSALS.load(deps.storage, id.into())?;
+    if proposal.owner != owner {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    if !proposal.approved {
+        return Err(ContractError::ProposalNotApproved {});
+    }
+    if proposal.amount != amount {
+        return Err(ContractError::InvalidProposalAmount {});
+    }
+    PROPOSALS.remove(deps.storage, id.into());
+
+    let res = try_create_stream(
+        env,
+        deps,
+        owner,
+        proposal.recipient.into_string(),
+        amount,
+        proposal.start_time,
+        proposal.end_time,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(cw20_addr),
+        None,
+        false,
+    )?;
+    Ok(res.add_attribute("proposal_id", id.to_string()))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn try_batch_top_up(
+    env: Env,
+    mut deps: DepsMut,
+    owner: String,
+    token: Addr,
+    amount: Uint128,
+    ids: Vec<Uint128>,
+    weights: Option<Vec<u16>>,
+    note: Option<String>,
+) -> Result<Response, ContractError> {
+    validate_note(&note)?;
+    let amount = issue_shares(deps.branch(), &env, Some(&token), amount)?;
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    if ids.is_empty() {
+        return Err(ContractError::InvalidWeights {});
+    }
+    if ids.len() > MAX_BATCH_SIZE {
+        return Err(ContractError::BatchTooLarge {});
+    }
+
+    let weights = match weights {
+        Some(weights) => {
+            let total: u64 = weights.iter().map(|w| u64::from(*w)).sum();
+            if weights.len() != ids.len() || total != 10000 {
+                return Err(ContractError::InvalidWeights {});
+            }
+            weights.into_iter().map(u64::from).collect()
+        }
+        None => vec![1; ids.len()],
+    };
+    let shares = split_amount(amount, &weights)?;
+    // Every listed stream must get part of the deposit
+    if shares.iter().any(Uint128::is_zero) {
+        return Err(ContractError::ZeroAmount {});
+    }
+
+    let mut res = Response::new()
+        .add_attribute("method", "try_batch_top_up")
+        .add_attribute("owner", owner.clone())
+        .add_attribute("amount", amount);
+
+    for (id, share) in ids.iter().zip(shares) {
+        let stream = save_top_up(deps.storage, &env, *id, &owner, &token, share, note.clone())?;
+        res = res
+            .add_attribute("stream_id", *id)
+            .add_attribute("new_amount", stream.amount);
+    }
+
+    Ok(res)
+}
+
+pub fn try_top_up(
+    env: Env,
+    mut deps: DepsMut,
+    owner: String,
+    token: Addr,
+    amount: Uint128,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let amount = issue_shares(deps.branch(), &env, Some(&token), amount)?;
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    let stream = save_top_up(deps.storage, &env, id, &owner, &token, amount, None)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_top_up")
+        .add_attribute("stream_id", id)
+        .add_attribute("amount", amount)
+        .add_attribute("new_amount", stream.amount))
+}
+
+/// Tops up a stream of `owner`, keeping the prior state revertible within
+/// the block.
+fn save_top_up(
+    storage: &mut dyn Storage,
+    env: &Env,
+    id: Uint128,
+    owner: &str,
+    token: &Addr,
+    amount: Uint128,
+    note: Option<String>,
+) -> Result<Stream, ContractError> {
+    let mut stream = streams().load(storage, id.u128().into())?;
+    if stream.owner != owner {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    // Top-ups arrive as cw20, which can't fund a native stream or one of
+    // another token
+    if stream.cw20_addr.as_ref() != Some(token) {
+        return Err(ContractError::DenomMismatch {});
+    }
+
+    let prior = stream.clone();
+    let block_time = env.block.time.seconds();
+    top_up_stream(&mut stream, amount, block_time)?;
+    // The claim cooldown restarts so the new funds can't be claimed early
+    stream.last_claim_time = Some(block_time);
+    streams().save(storage, id.u128().into(), &stream)?;
+    update_stats(storage, &[&prior], &[&stream])?;
+    let last_top_up = LastTopUp {
+        height: env.block.height,
+        amount,
+        sender: stream.owner.clone(),
+        prior,
+    };
+    LAST_TOP_UPS.save(storage, id.u128().into(), &last_top_up)?;
+    log_stream_action(
+        storage,
+        id,
+        StreamActionKind::TopUp,
+        stream.owner.clone(),
+        block_time,
+        note,
+    )?;
+    Ok(stream)
+}
+
+pub fn try_revert_top_up(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    // Only a top-up from this block that nothing else has changed since
+    let last_top_up = LAST_TOP_UPS
+        .may_load(deps.storage, id.u128().into())?
+        .filter(|last| last.height == env.block.height)
+        .ok_or(ContractError::TopUpNotRevertible {})?;
+    let prior = last_top_up.prior;
+    if prior.amount.checked_add(last_top_up.amount)? != stream.amount {
+        return Err(ContractError::TopUpNotRevertible {});
+    }
+    let before = stream.clone();
+
+    // Within the block vesting is the same under either schedule, so what was
+    // claimed since stays valid
+    stream.amount = prior.amount;
+    stream.checkpoint_time = prior.checkpoint_time;
+    stream.checkpoint_vested = prior.checkpoint_vested;
+    stream.rate_per_second = prior.rate_per_second;
+    stream.rate_per_day = prior.rate_per_day;
+    // The claim cooldown the top-up restarted runs from the previous claim
+    // again, unless there has been a claim since
+    if stream.claimed_amount == prior.claimed_amount {
+        stream.last_claim_time = prior.last_claim_time;
+    }
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    LAST_TOP_UPS.remove(deps.storage, id.u128().into());
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::RevertTopUp,
+        info.sender.clone(),
+        env.block.time.seconds(),
+        None,
+    )?;
+
+    let amount = redeem_shares(
+        deps.branch(),
+        &env,
+        stream.cw20_addr.as_ref(),
+        last_top_up.amount,
+    )?;
+    // Always returned to whoever sent it, even with an emergency recipient
+    let refund = transfer_msg(
+        stream.cw20_addr.as_ref(),
+        stream.denom.as_deref(),
+        last_top_up.sender,
+        amount,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_revert_top_up")
+        .add_attribute("stream_id", id)
+        .add_attribute("amount", amount)
+        .add_attribute("new_amount", stream.amount)
+        .add_message(refund))
+}
+
+/// Splits `total` in proportion to `weights` using the largest remainder
+/// method: every part is rounded down, then the units lost to rounding go one
+/// each to the parts with the largest remainders, so the parts always sum to
+/// `total`. Ties favour the earlier part.
+fn split_amount(total: Uint128, weights: &[u64]) -> Result<Vec<Uint128>, OverflowError> {
+    let weight_sum = weights.iter().try_fold(0u64, |sum, w| {
+        sum.checked_add(*w)
+            .ok_or_else(|| OverflowError::new(OverflowOperation::Add, sum, w))
+    })?;
+    let mut parts: Vec<Uint128> = weights
+        .iter()
+        .map(|w| total.multiply_ratio(*w, weight_sum))
+        .collect();
+
+    let remainders: Vec<Uint256> = weights
+        .iter()
+        .map(|w| {
+            total
+                .full_mul(*w)
+                .checked_rem(Uint256::from(weight_sum))
+                .unwrap()
+        })
+        .collect();
+    let mut order: Vec<usize> = (0..parts.len()).collect();
+    order.sort_by(|a, b| remainders[*b].cmp(&remainders[*a]));
+
+    // Fewer units are left over than there are parts
+    let split = parts
+        .iter()
+        .try_fold(Uint128::zero(), |sum, part| sum.checked_add(*part))?;
+    let left_over = total.checked_sub(split)?.u128() as usize;
+    for i in order.into_iter().take(left_over) {
+        parts[i] = parts[i].checked_add(Uint128::new(1))?;
+    }
+    Ok(parts)
+}
+
+/// Adds `amount` to a linear stream, spreading the unvested balance over the
+/// time left until `end_time`. Already vested funds are left untouched.
+fn top_up_stream(
+    stream: &mut Stream,
+    amount: Uint128,
+    block_time: u64,
+) -> Result<(), ContractError> {
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    if !is_linear(stream) {
+        return Err(ContractError::NotLinearStream {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+    if schedule_time(stream, block_time) >= stream.end_time {
+        return Err(ContractError::InvalidTopUp {});
+    }
+
+    let new_amount = stream.amount.checked_add(amount)?;
+    rebase_stream(stream, new_amount, block_time)?;
+    Ok(())
+}
+
+/// Changes the total amount of a linear stream from `block_time` on. Vesting
+/// is checkpointed first so the change only affects what is still unvested,
+/// which then vests evenly until `end_time`.
+fn rebase_stream(
+    stream: &mut Stream,
+    new_amount: Uint128,
+    block_time: u64,
+) -> Result<(), OverflowError> {
+    let now = schedule_time(stream, block_time);
+    if now > stream.checkpoint_time {
+        stream.checkpoint_vested = compute_vested(stream, block_time)?;
+        stream.checkpoint_time = now;
+    }
+    stream.amount = new_amount;
+
+    let remaining = stream.amount.checked_sub(stream.checkpoint_vested)?;
+    let duration = checked_sub_seconds(stream.end_time, stream.checkpoint_time)?;
+    let (rate_per_second, rate_per_day) = vesting_rates(remaining, duration);
+    stream.rate_per_second = rate_per_second;
+    stream.rate_per_day = rate_per_day;
+    Ok(())
+}
+
+fn checked_sub_seconds(a: u64, b: u64) -> Result<u64, OverflowError> {
+    a.checked_sub(b)
+        .ok_or_else(|| OverflowError::new(OverflowOperation::Sub, a, b))
+}
+
+fn checked_add_seconds(a: u64, b: u64) -> Result<u64, OverflowError> {
+    a.checked_add(b)
+        .ok_or_else(|| OverflowError::new(OverflowOperation::Add, a, b))
+}
+
+/// Whether `stream` vests continuously, which changes to its schedule such as
+/// top-ups and partial cancellations rely on
+fn is_linear(stream: &Stream) -> bool {
+    stream.installments.is_none() && stream.unlock_schedule.is_none()
+}
+
+/// Per second and per day rates for `amount` vesting over `duration` seconds.
+/// Cached on the stream so read paths don't need to divide.
+fn vesting_rates(amount: Uint128, duration: u64) -> (Uint128, Uint128) {
+    (
+        amount.multiply_ratio(1u128, duration),
+        amount.multiply_ratio(SECONDS_PER_DAY, duration),
+    )
+}
+
+/// Best-effort check whether `addr` is a contract, based on whether the chain
+/// returns contract info for it.
+fn is_contract_addr(querier: &QuerierWrapper, addr: &Addr) -> bool {
+    querier
+        .query::<ContractInfoResponse>(
+            &WasmQuery::ContractInfo {
+                contract_addr: addr.to_string(),
+            }
+            .into(),
+        )
+        .is_ok()
+}
+
+/// Time on `stream`'s schedule at `block_time`. The schedule stands still
+/// while the stream is paused and runs behind by the time it spent paused.
+fn schedule_time(stream: &Stream, block_time: u64) -> u64 {
+    let block_time = match stream.paused_at {
+        Some(paused_at) => block_time.min(paused_at),
+        None => block_time,
+    };
+    block_time.saturating_sub(stream.paused_duration)
+}
+
+/// Total amount of the stream vested at `block_time`, claimed or not.
+fn compute_vested(stream: &Stream, block_time: u64) -> Result<Uint128, OverflowError> {
+    // Nothing accrues while the stream is frozen or paused, or after its
+    // scheduled cancellation
+    let block_time = match stream.frozen_at {
+        Some(frozen_at) => block_time.min(frozen_at),
+        None => block_time,
+    };
+    let block_time = schedule_time(stream, block_time);
+    let block_time = match stream.cancel_at {
+        Some(cancel_at) => block_time.min(cancel_at),
+        None => block_time,
+    };
+
+    // Nothing vests before the cliff, then what accrued since the start
+    // unlocks at once
+    if matches!(stream.cliff_time, Some(cliff) if block_time < cliff) {
+        return Ok(Uint128::zero());
+    }
+
+    // Past the end everything is vested, regardless of rounding in the schedule
+    if block_time >= stream.end_time {
+        return Ok(stream.amount);
+    }
+
+    // A step schedule has vested the last cumulative amount it reached
+    if let Some(schedule) = &stream.unlock_schedule {
+        let unlocked = schedule
+            .iter()
+            .take_while(|(time, _)| *time <= block_time)
+            .last()
+            .map_or(Uint128::zero(), |(_, cumulative)| *cumulative);
+        return Ok(unlocked.min(stream.amount));
+    }
+
+    match &stream.installments {
+        Some(installments) => {
+            // Installments pay out what was unvested at the checkpoint. The
+            // final one, which absorbs the remainder, is only reached at the
+            // end time
+            let elapsed = block_time.saturating_sub(stream.checkpoint_time);
+            let paid =
+                (elapsed / installments.interval_seconds).min(u64::from(installments.count) - 1);
+            let remaining = stream.amount.checked_sub(stream.checkpoint_vested)?;
+            let per_installment = remaining.u128() / u128::from(installments.count);
+            let unlocked = Uint128::new(per_installment).checked_mul(Uint128::from(paid))?;
+            stream.checkpoint_vested.checked_add(unlocked)
+        }
+        None => {
+            let elapsed = block_time.saturating_sub(stream.checkpoint_time);
+            let duration = checked_sub_seconds(stream.end_time, stream.checkpoint_time)?;
+            // A zero-length window vests everything at once, at its end time
+            if duration == 0 {
+                return Ok(stream.checkpoint_vested);
+            }
+            let remaining = stream.amount.checked_sub(stream.checkpoint_vested)?;
+            stream
+                .checkpoint_vested
+                .checked_add(remaining.multiply_ratio(elapsed, duration))
+        }
+    }
+}
+
+/// Vested but unclaimed amount at `block_time`, see `round_claimable`.
+fn compute_claimable(stream: &Stream, block_time: u64) -> Result<Uint128, OverflowError> {
+    // An oracle clock may step back behind a previous withdrawal
+    let vested = compute_vested(stream, block_time)?;
+    round_claimable(stream, vested, vested.saturating_sub(stream.claimed_amount))
+}
+
+/// Floors `claimable` to the stream's rounding unit. The remainder carries
+/// over to later claims and is released in full once everything has vested.
+fn round_claimable(
+    stream: &Stream,
+    vested: Uint128,
+    claimable: Uint128,
+) -> Result<Uint128, OverflowError> {
+    match stream.rounding_unit {
+        Some(unit) if vested < stream.amount => {
+            claimable.checked_sub(Uint128::new(claimable.u128() % unit.u128()))
+        }
+        _ => Ok(claimable),
+    }
+}
+
+pub fn try_withdraw(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    to: Option<String>,
+) -> Result<Response, ContractError> {
+    let withdrawal = withdraw_stream(deps.branch(), &env, &info.sender, id, to)?;
+    let mut res = Response::new()
+        .set_data(to_binary(&withdrawal.data)?)
+        .add_attribute("method", "try_withdraw")
+        .add_attribute("stream_id", id)
+        .add_attribute("amount", withdrawal.data.amount)
+        .add_attribute("recipient", withdrawal.recipient.clone());
+
+    let cw20_addr = withdrawal.cw20_addr.as_ref();
+    let denom = withdrawal.denom.as_deref();
+    if let Some((lender, repaid)) = withdrawal.repayment {
+        res = res
+            .add_attribute("repaid", repaid)
+            .add_message(transfer_msg(cw20_addr, denom, lender, repaid)?);
+    }
+    if withdrawal.payout.is_zero() {
+        return Ok(res);
+    }
+    let msg = payout_msg(
+        deps.as_ref(),
+        id,
+        cw20_addr,
+        denom,
+        withdrawal.recipient,
+        withdrawal.payout,
+    )?;
+
+    // With a fallback recipient a failed transfer is rerouted in `reply`
+    // instead of reverting the withdrawal. The reply is requested on success
+    // too so that it always releases the reentrancy lock.
+    let msg = match withdrawal.fallback_recipient {
+        Some(fallback_recipient) => {
+            PENDING_FALLBACK.save(
+                deps.storage,
+                &PendingFallback {
+                    stream_id: id,
+                    fallback_recipient,
+                    amount: withdrawal.payout,
+                    cw20_addr: withdrawal.cw20_addr,
+                    denom: withdrawal.denom,
+                },
+            )?;
+            SubMsg::reply_always(msg, WITHDRAW_REPLY_ID)
+        }
+        None => SubMsg::new(msg),
+    };
+    Ok(res.add_submessage(msg))
+}
+
+/// Withdraws from several streams at once. Streams with nothing to claim
+/// right now are skipped. Transfers of the same token to the same address
+/// are merged. Fallback recipients aren't used, a failed transfer reverts
+/// the whole batch.
+pub fn try_withdraw_all(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    ids: Vec<Uint128>,
+) -> Result<Response, ContractError> {
+    if ids.len() > MAX_BATCH_SIZE {
+        return Err(ContractError::BatchTooLarge {});
+    }
+    // Without ids, the first of the sender's own streams with funds left
+    let ids = if ids.is_empty() {
+        streams()
+            .idx
+            .recipient
+            .prefix(info.sender.as_bytes().to_vec())
+            .range(deps.storage, None, None, Order::Ascending)
+            .take(MAX_SCAN)
+            .filter(
+                |item| !matches!(item, Ok((_, stream)) if stream.claimed_amount >= stream.amount),
+            )
+            .take(MAX_BATCH_SIZE)
+            .map(|item| id_from_key(&item?.0))
+            .collect::<StdResult<Vec<_>>>()?
+    } else {
+        ids
+    };
+
+    let mut res = Response::new().add_attribute("method", "try_withdraw_all");
+    let mut transfers: Vec<(Option<Addr>, Option<String>, Addr, Uint128)> = vec![];
+    let mut add_transfer = |cw20_addr: &Option<Addr>,
+                            denom: &Option<String>,
+                            to,
+                            amount|
+     -> Result<(), OverflowError> {
+        let existing = transfers
+            .iter_mut()
+            .find(|(c, d, t, _)| c == cw20_addr && d == denom && *t == to);
+        match existing {
+            Some((_, _, _, total)) => *total = total.checked_add(amount)?,
+            None => transfers.push((cw20_addr.clone(), denom.clone(), to, amount)),
+        }
+        Ok(())
+    };
+    for id in ids {
+        let withdrawal = match withdraw_stream(deps.branch(), &env, &info.sender, id, None) {
+            Ok(withdrawal) => withdrawal,
+            Err(ContractError::StreamNotStarted {})
+            | Err(ContractError::NoFundsToClaim {})
+            | Err(ContractError::StreamFullyClaimed {})
+            | Err(ContractError::StreamCancelled {})
+            | Err(ContractError::CancellationPending {})
+            | Err(ContractError::ConditionNotMet {})
+            | Err(ContractError::ClaimCooldown {}) => continue,
+            Err(e) => return Err(e),
+        };
+        res = res
+            .add_attribute("stream_id", id)
+            .add_attribute("amount", withdrawal.data.amount);
+        if let Some((lender, repaid)) = withdrawal.repayment {
+            add_transfer(&withdrawal.cw20_addr, &withdrawal.denom, lender, repaid)?;
+        }
+        if withdrawal.payout.is_zero() {
+            continue;
+        }
+        // Swaps can't be merged, each goes out on its own
+        if AUTO_SWAPS.has(deps.storage, id.u128().into()) {
+            res = res.add_message(payout_msg(
+                deps.as_ref(),
+                id,
+                withdrawal.cw20_addr.as_ref(),
+                withdrawal.denom.as_deref(),
+                withdrawal.recipient,
+                withdrawal.payout,
+            )?);
+        } else {
+            add_transfer(
+                &withdrawal.cw20_addr,
+                &withdrawal.denom,
+                withdrawal.recipient,
+                withdrawal.payout,
+            )?;
+        }
+    }
+
+    for (cw20_addr, denom, to, amount) in transfers {
+        res = res.add_message(transfer_msg(
+            cw20_addr.as_ref(),
+            denom.as_deref(),
+            to,
+            amount,
+        )?);
+    }
+    Ok(res)
+}
+
+/// A withdrawal recorded on its stream, still to be paid out
+struct Withdrawal {
+    data: WithdrawResult,
+    recipient: Addr,
+    /// Lender paid first out of an encumbered stream
+    repayment: Option<(Addr, Uint128)>,
+    payout: Uint128,
+    cw20_addr: Option<Addr>,
+    denom: Option<String>,
+    fallback_recipient: Option<Addr>,
+}
+
+/// Claims what `sender` may withdraw from a stream and records it, leaving
+/// the transfers to the caller
+fn withdraw_stream(
+    mut deps: DepsMut,
+    env: &Env,
+    sender: &Addr,
+    id: Uint128,
+    to: Option<String>,
+) -> Result<Withdrawal, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.paused {
+        return Err(ContractError::ContractPaused {});
+    }
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_withdraw_authorized(deps.as_ref(), id, &stream, sender)? {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+    // Streams are paid in their own token, the config's may have been
+    // replaced since they were created
+    if stream.cw20_addr.is_none() && stream.denom.is_none() {
+        return Err(ContractError::StreamTokenMismatch {});
+    }
+    // Delegates withdraw to the recipient, only the recipient picks another
+    // destination
+    if to.is_some() && stream.recipient != *sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+
+    // A soft cancelled stream keeps nothing to withdraw while it can still
+    // be restored, and a cancelled one only vested funds that waited on its
+    // condition
+    if PENDING_CANCELLATIONS.has(deps.storage, id.u128().into()) {
+        return Err(ContractError::CancellationPending {});
+    }
+    if stream.cancelled && stream.claimed_amount >= stream.amount {
+        return Err(ContractError::StreamCancelled {});
+    }
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+    if stream.claimed_amount >= stream.amount {
+        return Err(ContractError::StreamFullyClaimed {});
+    }
+
+    let block_time = env.block.time.seconds();
+    if stream.start_time >= schedule_time(&stream, block_time) {
+        return Err(ContractError::StreamNotStarted {});
+    }
+    if let (Some(interval), Some(last_claim_time)) =
+        (config.min_claim_interval_seconds, stream.last_claim_time)
+    {
+        if block_time < last_claim_time.saturating_add(interval) {
+            return Err(ContractError::ClaimCooldown {});
+        }
+    }
+
+    let unclaimed_amount = compute_claimable(&stream, block_time)?;
+    if unclaimed_amount.is_zero() {
+        return Err(ContractError::NoFundsToClaim {});
+    }
+    check_condition(deps.as_ref(), &stream)?;
+
+    let recipient = match to {
+        Some(to) => canonical_addr(deps.api, to.as_str())?,
+        None => resolve_recipient(deps.as_ref(), id, &stream)?,
+    };
+    if matches!(&stream.allowed_destinations, Some(allowed) if !allowed.contains(&recipient)) {
+        return Err(ContractError::DestinationNotAllowed {});
+    }
+
+    // Delegates with an allowance can't withdraw more than is left of it
+    if stream.recipient != *sender {
+        let key = (id.u128().into(), sender);
+        if let Some(allowance) = DELEGATE_ALLOWANCES.may_load(deps.storage, key.clone())? {
+            let allowance = allowance
+                .checked_sub(unclaimed_amount)
+                .map_err(|_| ContractError::AllowanceExceeded {})?;
+            DELEGATE_ALLOWANCES.save(deps.storage, key, &allowance)?;
+        }
+    }
+
+    let before = stream.clone();
+    stream.claimed_amount = stream.claimed_amount.checked_add(unclaimed_amount)?;
+    stream.last_claim_time = Some(block_time);
+    // An encumbered stream repays its lender before the recipient is paid
+    let repayment = take_repayment(&mut stream, unclaimed_amount)?;
+    let payout = match &repayment {
+        Some((_, repaid)) => unclaimed_amount.checked_sub(*repaid)?,
+        None => unclaimed_amount,
+    };
+    // Rebasing streams can't be encumbered, only their payout is in shares
+    let payout = redeem_shares(deps.branch(), env, stream.cw20_addr.as_ref(), payout)?;
+    let repaid = repayment.as_ref().map(|(_, repaid)| *repaid);
+    let paid = repaid.unwrap_or_default().checked_add(payout)?;
+
+    let close = config.auto_close_on_final_withdraw && stream.claimed_amount == stream.amount;
+    if close {
+        streams().remove(deps.storage, id.u128().into())?;
+    } else {
+        streams().save(deps.storage, id.u128().into(), &stream)?;
+    }
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    let record = WithdrawalRecord {
+        time: block_time,
+        amount: paid,
+        recipient: recipient.clone(),
+    };
+    record_withdrawal(deps.storage, id, &record)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Withdraw,
+        sender.clone(),
+        block_time,
+        None,
+    )?;
+    if close {
+        log_stream_action(
+            deps.storage,
+            id,
+            StreamActionKind::Close,
+            sender.clone(),
+            block_time,
+            None,
+        )?;
+    }
+
+    Ok(Withdrawal {
+        data: WithdrawResult {
+            id,
+            amount: unclaimed_amount,
+            new_claimed: stream.claimed_amount,
+            remaining: stream.amount.checked_sub(stream.claimed_amount)?,
+        },
+        recipient,
+        repayment,
+        payout,
+        cw20_addr: stream.cw20_addr,
+        denom: stream.denom,
+        fallback_recipient: stream.fallback_recipient,
+    })
+}
+
+/// Pays out `amount` of a stream's token, the native `denom` if it has one
+/// and its cw20 otherwise
+fn transfer_msg(
+    cw20_addr: Option<&Addr>,
+    denom: Option<&str>,
+    recipient: impl Into<String>,
+    amount: Uint128,
+) -> StdResult<CosmosMsg> {
+    match (denom, cw20_addr) {
+        (Some(denom), _) => Ok(BankMsg::Send {
+            to_address: recipient.into(),
+            amount: coins(amount.u128(), denom),
+        }
+        .into()),
+        (None, Some(cw20_addr)) => Cw20Contract(cw20_addr.clone()).call(Cw20ExecuteMsg::Transfer {
+            recipient: recipient.into(),
+            amount,
+        }),
+        (None, None) => Err(StdError::generic_err("stream has no token")),
+    }
+}
+
+/// Pays out a withdrawal of stream `id`. With an auto-swap and a DEX set the
+/// cw20 is sent to the DEX, which pays the recipient in the target token.
+fn payout_msg(
+    deps: Deps,
+    id: Uint128,
+    cw20_addr: Option<&Addr>,
+    denom: Option<&str>,
+    recipient: Addr,
+    amount: Uint128,
+) -> StdResult<CosmosMsg> {
+    let auto_swap = AUTO_SWAPS.may_load(deps.storage, id.u128().into())?;
+    let dex = DEX.may_load(deps.storage)?;
+    match (auto_swap, dex, cw20_addr) {
+        (Some(auto_swap), Some(dex), Some(cw20_addr)) if denom.is_none() => {
+            let rate = auto_swap.min_output_rate;
+            let minimum_receive = (amount.full_mul(rate.numerator())
+                / Uint256::from(rate.denominator().u128()))
+            .try_into()
+            .map_err(|_| OverflowError::new(OverflowOperation::Mul, amount, rate))?;
+            let hook = DexHookMsg::Swap {
+                ask_token: auto_swap.target_token.into_string(),
+                minimum_receive,
+                to: recipient.into_string(),
+            };
+            Cw20Contract(cw20_addr.clone()).call(Cw20ExecuteMsg::Send {
+                contract: dex.into_string(),
+                amount,
+                msg: to_binary(&hook)?,
+            })
+        }
+        _ => transfer_msg(cw20_addr, denom, recipient, amount),
+    }
+}
+
+/// Takes what an encumbered stream still owes its lender out of `amount`,
+/// returning the lender and the part repaid. The encumbrance is released
+/// once it is fully repaid.
+fn take_repayment(
+    stream: &mut Stream,
+    amount: Uint128,
+) -> Result<Option<(Addr, Uint128)>, OverflowError> {
+    let encumbrance = match stream.encumbrance.as_mut() {
+        Some(encumbrance) => encumbrance,
+        None => return Ok(None),
+    };
+    let repaid = encumbrance.outstanding.min(amount);
+    encumbrance.outstanding = encumbrance.outstanding.checked_sub(repaid)?;
+    let lender = encumbrance.lender.clone();
+    if encumbrance.outstanding.is_zero() {
+        stream.encumbrance = None;
+    }
+    Ok(Some((lender, repaid)))
+}
+
+/// The contract's balance of a cw20 token
+fn token_balance(deps: Deps, env: &Env, token: &Addr) -> StdResult<Uint128> {
+    let res: BalanceResponse = deps.querier.query_wasm_smart(
+        token,
+        &Cw20QueryMsg::Balance {
+            address: env.contract.address.to_string(),
+        },
+    )?;
+    Ok(res.balance)
+}
+
+/// The share pool of a rebasing token, with rebases since it was last synced
+/// picked up from the contract's balance. The balance is only read once per
+/// block, as later in an execution it still includes the transfers being
+/// paid out. `incoming` was just received and isn't pooled yet.
+fn load_share_pool(
+    deps: Deps,
+    env: &Env,
+    token: &Addr,
+    incoming: Uint128,
+) -> StdResult<Option<SharePool>> {
+    let mut pool = match SHARE_POOLS.may_load(deps.storage, token)? {
+        Some(pool) => pool,
+        None => return Ok(None),
+    };
+    if pool.synced_height < env.block.height {
+        pool.total_tokens = token_balance(deps, env, token)?.checked_sub(incoming)?;
+        pool.synced_height = env.block.height;
+    }
+    Ok(Some(pool))
+}
+
+/// Pools a deposit of a rebasing token, returning the shares it buys. Other
+/// deposits are streamed as they are.
+fn issue_shares(
+    deps: DepsMut,
+    env: &Env,
+    cw20_addr: Option<&Addr>,
+    amount: Uint128,
+) -> StdResult<Uint128> {
+    let token = match cw20_addr {
+        Some(token) => token,
+        None => return Ok(amount),
+    };
+    let mut pool = match load_share_pool(deps.as_ref(), env, token, Uint128::zero())? {
+        Some(pool) => pool,
+        None => return Ok(amount),
+    };
+    // Shares are issued one for one into an empty pool
+    let shares = if pool.total_shares.is_zero() {
+        amount
+    } else if pool.total_tokens.is_zero() {
+        return Err(StdError::generic_err("rebasing token balance is zero"));
+    } else {
+        amount.multiply_ratio(pool.total_shares, pool.total_tokens)
+    };
+    pool.total_shares = pool.total_shares.checked_add(shares)?;
+    pool.total_tokens = pool.total_tokens.checked_add(amount)?;
+    SHARE_POOLS.save(deps.storage, token, &pool)?;
+    Ok(shares)
+}
+
+/// Takes `amount` of a rebasing stream's shares out of the pool, returning
+/// the tokens they are worth. Other streams are paid the amount as it is.
+fn redeem_shares(
+    deps: DepsMut,
+    env: &Env,
+    cw20_addr: Option<&Addr>,
+    amount: Uint128,
+) -> StdResult<Uint128> {
+    let token = match cw20_addr {
+        Some(token) => token,
+        None => return Ok(amount),
+    };
+    let mut pool = match load_share_pool(deps.as_ref(), env, token, Uint128::zero())? {
+        Some(pool) if !amount.is_zero() => pool,
+        _ => return Ok(amount),
+    };
+    let tokens = amount.multiply_ratio(pool.total_tokens, pool.total_shares);
+    pool.total_shares = pool.total_shares.checked_sub(amount)?;
+    pool.total_tokens = pool.total_tokens.checked_sub(tokens)?;
+    SHARE_POOLS.save(deps.storage, token, &pool)?;
+    Ok(tokens)
+}
+
+/// What `amount` of a stream would be paid out at the current balance
+fn share_value(deps: Deps, env: &Env, stream: &Stream, amount: Uint128) -> StdResult<Uint128> {
+    let pool = match &stream.cw20_addr {
+        Some(token) => load_share_pool(deps, env, token, Uint128::zero())?,
+        None => None,
+    };
+    match pool {
+        Some(pool) if !pool.total_shares.is_zero() => {
+            Ok(amount.multiply_ratio(pool.total_tokens, pool.total_shares))
+        }
+        _ => Ok(amount),
+    }
+}
+
+pub fn try_set_liquidity_provider(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let res = Response::new().add_attribute("method", "try_set_liquidity_provider");
+    match address {
+        Some(address) => {
+            let address = canonical_addr(deps.api, address.as_str())?;
+            LIQUIDITY_PROVIDER.save(deps.storage, &address)?;
+            Ok(res.add_attribute("liquidity_provider", address))
+        }
+        None => {
+            LIQUIDITY_PROVIDER.remove(deps.storage);
+            Ok(res.add_attribute("liquidity_provider", ""))
+        }
+    }
+}
+
+pub fn try_set_dex(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let res = Response::new().add_attribute("method", "try_set_dex");
+    match address {
+        Some(address) => {
+            let address = canonical_addr(deps.api, address.as_str())?;
+            DEX.save(deps.storage, &address)?;
+            Ok(res.add_attribute("dex", address))
+        }
+        None => {
+            DEX.remove(deps.storage);
+            Ok(res.add_attribute("dex", ""))
+        }
+    }
+}
+
+pub fn try_set_auto_swap(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    auto_swap: Option<AutoSwapSpec>,
+) -> Result<Response, ContractError> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+
+    let res = Response::new()
+        .add_attribute("method", "try_set_auto_swap")
+        .add_attribute("stream_id", id);
+    let spec = match auto_swap {
+        Some(spec) => spec,
+        None => {
+            AUTO_SWAPS.remove(deps.storage, id.u128().into());
+            return Ok(res.add_attribute("target_token", ""));
+        }
+    };
+    if DEX.may_load(deps.storage)?.is_none() {
+        return Err(ContractError::DexNotConfigured {});
+    }
+    // The DEX is handed the withdrawal with a cw20 send
+    if stream.cw20_addr.is_none() || stream.denom.is_some() {
+        return Err(ContractError::DenomMismatch {});
+    }
+
+    let auto_swap = AutoSwap {
+        target_token: canonical_addr(deps.api, spec.target_token.as_str())?,
+        min_output_rate: spec.min_output_rate,
+    };
+    AUTO_SWAPS.save(deps.storage, id.u128().into(), &auto_swap)?;
+    Ok(res
+        .add_attribute("target_token", auto_swap.target_token)
+        .add_attribute("min_output_rate", auto_swap.min_output_rate.to_string()))
+}
+
+/// Advances up to the unvested part of a stream to its recipient out of the
+/// liquidity provider's cw20 allowance to this contract. The stream is
+/// encumbered until later withdrawals have repaid the provider.
+pub fn try_pre_claim(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    // Advances are drawn from a cw20 allowance
+    let cw20_addr = stream
+        .cw20_addr
+        .clone()
+        .ok_or(ContractError::DenomMismatch {})?;
+    // Advances are paid in tokens, which can't be set against shares
+    if SHARE_POOLS.has(deps.storage, &cw20_addr) {
+        return Err(ContractError::RebasingMismatch {});
+    }
+    let lender = LIQUIDITY_PROVIDER
+        .may_load(deps.storage)?
+        .ok_or(ContractError::LiquidityProviderNotSet {})?;
+
+    // A stream is only ever indebted to one provider at a time
+    let outstanding = match &stream.encumbrance {
+        Some(encumbrance) if encumbrance.lender != lender => {
+            return Err(ContractError::StreamEncumbered {});
+        }
+        Some(encumbrance) => encumbrance.outstanding.checked_add(amount)?,
+        None => amount,
+    };
+    let block_time = env.block.time.seconds();
+    let unvested = stream
+        .amount
+        .checked_sub(compute_vested(&stream, block_time)?)?;
+    if amount.is_zero() || outstanding > unvested {
+        return Err(ContractError::InvalidPreClaimAmount {});
+    }
+
+    stream.encumbrance = Some(Encumbrance {
+        lender: lender.clone(),
+        outstanding,
+    });
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+
+    let msg = Cw20Contract(cw20_addr).call(Cw20ExecuteMsg::TransferFrom {
+        owner: lender.to_string(),
+        recipient: stream.recipient.into_string(),
+        amount,
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "try_pre_claim")
+        .add_attribute("stream_id", id)
+        .add_attribute("amount", amount)
+        .add_attribute("lender", lender)
+        .add_attribute("outstanding", outstanding)
+        .add_message(msg))
+}
+
+/// Whether `sender` may trigger a withdrawal of `stream`. Funds are always
+/// paid to the resolved recipient, whoever triggers it.
+fn is_withdraw_authorized(
+    deps: Deps,
+    id: Uint128,
+    stream: &Stream,
+    sender: &Addr,
+) -> StdResult<bool> {
+    if stream.recipient == *sender {
+        return Ok(true);
+    }
+    let delegate = GLOBAL_DELEGATES.may_load(deps.storage, &stream.recipient)?;
+    if delegate.as_ref() == Some(sender) {
+        return Ok(true);
+    }
+    if DELEGATE_ALLOWANCES.has(deps.storage, (id.u128().into(), sender)) {
+        return Ok(true);
+    }
+    if stream.recipient_controller.is_some() {
+        return Ok(resolve_recipient(deps, id, stream)? == *sender);
+    }
+    Ok(false)
+}
+
+/// Errors unless the stream's condition contract answers its query with the
+/// expected response. Vesting isn't affected, only claims are gated.
+fn check_condition(deps: Deps, stream: &Stream) -> Result<(), ContractError> {
+    let condition = match &stream.condition {
+        Some(condition) => condition,
+        None => return Ok(()),
+    };
+    let request: QueryRequest<Empty> = WasmQuery::Smart {
+        contract_addr: condition.contract.to_string(),
+        msg: condition.msg.clone(),
+    }
+    .into();
+    let res = match deps.querier.raw_query(&to_vec(&request)?) {
+        SystemResult::Ok(ContractResult::Ok(res)) => res,
+        SystemResult::Ok(ContractResult::Err(err)) => {
+            return Err(StdError::generic_err(format!("Condition query failed: {}", err)).into())
+        }
+        SystemResult::Err(err) => {
+            return Err(StdError::generic_err(format!("Condition query failed: {}", err)).into())
+        }
+    };
+    if res != condition.expected {
+        return Err(ContractError::ConditionNotMet {});
+    }
+    Ok(())
+}
+
+fn resolve_name(deps: Deps, config: &Config, name: String) -> Result<String, ContractError> {
+    let name_service = config
+        .name_service
+        .as_ref()
+        .ok_or(ContractError::NameServiceNotConfigured {})?;
+    let res: ResolveRecordResponse = deps
+        .querier
+        .query_wasm_smart(name_service, &NameServiceQueryMsg::ResolveRecord { name })?;
+    res.address.ok_or(ContractError::NameNotResolved {})
+}
+
+/// Where refunds of `stream` go: the emergency recipient when one is set,
+/// otherwise the stream owner.
+fn refund_address(config: &Config, stream: &Stream) -> Addr {
+    config
+        .emergency_recipient
+        .clone()
+        .unwrap_or_else(|| stream.owner.clone())
+}
+
+/// Address currently entitled to the stream's payments. With a recipient
+/// controller this is whoever the controller designates at the time of the
+/// call, otherwise the stored recipient.
+fn resolve_recipient(deps: Deps, id: Uint128, stream: &Stream) -> StdResult<Addr> {
+    match &stream.recipient_controller {
+        Some(controller) => {
+            let res: CurrentRecipientResponse = deps.querier.query_wasm_smart(
+                controller,
+                &ControllerQueryMsg::CurrentRecipient { stream_id: id },
+            )?;
+            canonical_addr(deps.api, res.recipient.as_str())
+        }
+        None => Ok(stream.recipient.clone()),
+    }
+}
+
+pub fn try_set_delegate_allowance(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    delegate: String,
+    allowance: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+
+    let delegate = canonical_addr(deps.api, delegate.as_str())?;
+    let key = (id.u128().into(), &delegate);
+    match allowance {
+        Some(allowance) => DELEGATE_ALLOWANCES.save(deps.storage, key, &allowance)?,
+        None => DELEGATE_ALLOWANCES.remove(deps.storage, key),
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "try_set_delegate_allowance")
+        .add_attribute("stream_id", id)
+        .add_attribute("delegate", delegate)
+        .add_attribute("allowance", allowance.unwrap_or_default()))
+}
+
+pub fn try_set_global_delegate(
+    deps: DepsMut,
+    info: MessageInfo,
+    delegate: Option<String>,
+) -> Result<Response, ContractError> {
+    let res = Response::new()
+        .add_attribute("method", "try_set_global_delegate")
+        .add_attribute("recipient", info.sender.clone());
+
+    match delegate {
+        Some(delegate) => {
+            let delegate = canonical_addr(deps.api, delegate.as_str())?;
+            GLOBAL_DELEGATES.save(deps.storage, &info.sender, &delegate)?;
+            Ok(res.add_attribute("delegate", delegate))
+        }
+        None => {
+            GLOBAL_DELEGATES.remove(deps.storage, &info.sender);
+            Ok(res.add_attribute("delegate", ""))
+        }
+    }
+}
+
+pub fn try_set_fallback_recipient(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    fallback_recipient: Option<String>,
+    note: Option<String>,
+) -> Result<Response, ContractError> {
+    validate_note(&note)?;
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+
+    stream.fallback_recipient = fallback_recipient
+        .map(|addr| canonical_addr(deps.api, addr.as_str()))
+        .transpose()?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::SetFallbackRecipient,
+        info.sender,
+        env.block.time.seconds(),
+        note,
+    )?;
+
+    let fallback_recipient = stream
+        .fallback_recipient
+        .map(Addr::into_string)
+        .unwrap_or_default();
+    Ok(Response::new()
+        .add_attribute("method", "try_set_fallback_recipient")
+        .add_attribute("stream_id", id)
+        .add_attribute("fallback_recipient", fallback_recipient))
+}
+
+pub fn try_transfer_recipient(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    new_recipient: String,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+    // A frozen recipient can't move its streams out from under the hold
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+
+    let new_recipient = canonical_addr(deps.api, new_recipient.as_str())?;
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner == new_recipient {
+        return Err(ContractError::InvalidRecipient {});
+    }
+
+    clear_recipient_settings(deps.storage, id, &mut stream)?;
+
+    // Saving through the indexed map moves the stream in the recipient index
+    stream.recipient = new_recipient;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::TransferRecipient,
+        info.sender.clone(),
+        env.block.time.seconds(),
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_transfer_recipient")
+        .add_attribute("stream_id", id)
+        .add_attribute("old_recipient", info.sender)
+        .add_attribute("new_recipient", stream.recipient))
+}
+
+/// Resets the payout settings and allowances chosen by a stream's recipient,
+/// which would otherwise keep routing the funds of whoever takes it over
+fn clear_recipient_settings(
+    storage: &mut dyn Storage,
+    id: Uint128,
+    stream: &mut Stream,
+) -> StdResult<()> {
+    stream.fallback_recipient = None;
+    stream.allowed_destinations = None;
+    stream.beneficiary = None;
+    AUTO_SWAPS.remove(storage, id.u128().into());
+    clear_delegate_allowances(storage, id)
+}
+
+fn clear_delegate_allowances(storage: &mut dyn Storage, id: Uint128) -> StdResult<()> {
+    let delegates: Vec<_> = DELEGATE_ALLOWANCES
+        .prefix(id.u128().into())
+        .keys(storage, None, None, Order::Ascending)
+        .collect();
+    for delegate in delegates {
+        let delegate =
+            Addr::unchecked(String::from_utf8(delegate).map_err(StdError::invalid_utf8)?);
+        DELEGATE_ALLOWANCES.remove(storage, (id.u128().into(), &delegate));
+    }
+    Ok(())
+}
+
+pub fn try_assign_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    new_owner: String,
+    new_recipient: String,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender && stream.recipient != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+
+    let new_owner = canonical_addr(deps.api, new_owner.as_str())?;
+    let new_recipient = canonical_addr(deps.api, new_recipient.as_str())?;
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner == new_recipient {
+        return Err(ContractError::InvalidRecipient {});
+    }
+    let res = Response::new()
+        .add_attribute("method", "try_assign_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("new_owner", new_owner.clone())
+        .add_attribute("new_recipient", new_recipient.clone());
+
+    // Applied once the other current party agreed to the same terms, or at
+    // once when the caller holds both roles. Approvals by someone who no
+    // longer holds a role don't count.
+    let other_party = if stream.owner == info.sender {
+        &stream.recipient
+    } else {
+        &stream.owner
+    };
+    let agreed = *other_party == info.sender
+        || PENDING_ASSIGNMENTS
+            .may_load(deps.storage, id.u128().into())?
+            .is_some_and(|pending| {
+                pending.approved_by == *other_party
+                    && pending.new_owner == new_owner
+                    && pending.new_recipient == new_recipient
+            });
+    if !agreed {
+        let pending = PendingAssignment {
+            new_owner,
+            new_recipient,
+            approved_by: info.sender,
+        };
+        PENDING_ASSIGNMENTS.save(deps.storage, id.u128().into(), &pending)?;
+        return Ok(res.add_attribute("status", "pending"));
+    }
+
+    // A recipient who stays keeps its own settings
+    if stream.recipient != new_recipient {
+        clear_recipient_settings(deps.storage, id, &mut stream)?;
+    }
+
+    // Saving through the indexed map moves the stream in both indexes
+    stream.owner = new_owner;
+    stream.recipient = new_recipient;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    PENDING_ASSIGNMENTS.remove(deps.storage, id.u128().into());
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Assign,
+        info.sender,
+        env.block.time.seconds(),
+        None,
+    )?;
+
+    Ok(res.add_attribute("status", "assigned"))
+}
+
+pub fn try_set_allowed_destinations(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    destinations: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+    if matches!(&destinations, Some(destinations) if destinations.len() > MAX_DESTINATIONS) {
+        return Err(ContractError::TooManyDestinations {});
+    }
+
+    stream.allowed_destinations = destinations
+        .map(|destinations| {
+            destinations
+                .iter()
+                .map(|addr| canonical_addr(deps.api, addr.as_str()))
+                .collect::<StdResult<Vec<_>>>()
+        })
+        .transpose()?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::SetAllowedDestinations,
+        info.sender,
+        env.block.time.seconds(),
+        None,
+    )?;
+
+    let destinations = stream
+        .allowed_destinations
+        .map(|destinations| {
+            destinations
+                .iter()
+                .map(Addr::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    Ok(Response::new()
+        .add_attribute("method", "try_set_allowed_destinations")
+        .add_attribute("stream_id", id)
+        .add_attribute("allowed_destinations", destinations))
+}
+
+fn validate_group_name(group: &str) -> Result<(), ContractError> {
+    if group.is_empty() || group.len() > MAX_GROUP_NAME_LENGTH {
+        return Err(ContractError::InvalidGroupName {});
+    }
+    Ok(())
+}
+
+pub fn try_add_to_group(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    group: String,
+) -> Result<Response, ContractError> {
+    validate_group_name(&group)?;
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    GROUPS.save(deps.storage, (group.as_str(), id.u128().into()), &Empty {})?;
+    Ok(Response::new()
+        .add_attribute("method", "try_add_to_group")
+        .add_attribute("stream_id", id)
+        .add_attribute("group", group))
+}
+
+pub fn try_remove_from_group(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    group: String,
+) -> Result<Response, ContractError> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    GROUPS.remove(deps.storage, (group.as_str(), id.u128().into()));
+    Ok(Response::new()
+        .add_attribute("method", "try_remove_from_group")
+        .add_attribute("stream_id", id)
+        .add_attribute("group", group))
+}
+
+pub fn try_set_beneficiary(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    beneficiary: Option<String>,
+    inactivity_seconds: u64,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.recipient != info.sender {
+        return Err(ContractError::NotStreamRecipient {});
+    }
+
+    stream.beneficiary = beneficiary
+        .map(|addr| canonical_addr(deps.api, addr.as_str()))
+        .transpose()?
+        .map(|address| Beneficiary {
+            address,
+            inactivity_seconds,
+        });
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::SetBeneficiary,
+        info.sender,
+        env.block.time.seconds(),
+        None,
+    )?;
+
+    let beneficiary = stream
+        .beneficiary
+        .map(|beneficiary| beneficiary.address.into_string())
+        .unwrap_or_default();
+    Ok(Response::new()
+        .add_attribute("method", "try_set_beneficiary")
+        .add_attribute("stream_id", id)
+        .add_attribute("beneficiary", beneficiary)
+        .add_attribute("inactivity_seconds", inactivity_seconds.to_string()))
+}
+
+pub fn try_claim_as_beneficiary(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.paused {
+        return Err(ContractError::ContractPaused {});
+    }
+
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    let beneficiary = match &stream.beneficiary {
+        Some(beneficiary) if beneficiary.address == info.sender => beneficiary,
+        _ => return Err(ContractError::Unauthorized {}),
+    };
+
+    // A hold on the recipient covers whoever would inherit its streams
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+    if stream.claimed_amount >= stream.amount {
+        return Err(ContractError::StreamFullyClaimed {});
+    }
+
+    // After the end time any withdrawal drains the stream, so funds left past
+    // the window mean the recipient hasn't withdrawn for that long
+    let block_time = env.block.time.seconds();
+    let claimable_from = stream
+        .end_time
+        .saturating_add(beneficiary.inactivity_seconds);
+    if schedule_time(&stream, block_time) < claimable_from {
+        return Err(ContractError::RecipientStillActive {});
+    }
+
+    let amount = compute_claimable(&stream, block_time)?;
+    if amount.is_zero() {
+        return Err(ContractError::NoFundsToClaim {});
+    }
+    check_condition(deps.as_ref(), &stream)?;
+    let before = stream.clone();
+    stream.claimed_amount = stream.claimed_amount.checked_add(amount)?;
+    stream.last_claim_time = Some(block_time);
+    // An encumbered stream repays its lender before the beneficiary is paid
+    let repayment = take_repayment(&mut stream, amount)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::BeneficiaryClaim,
+        info.sender.clone(),
+        block_time,
+        None,
+    )?;
+
+    let cw20_addr = stream.cw20_addr.as_ref();
+    let denom = stream.denom.as_deref();
+    let mut res = Response::new()
+        .add_attribute("method", "try_claim_as_beneficiary")
+        .add_attribute("stream_id", id)
+        .add_attribute("beneficiary", info.sender.clone());
+    let mut payout = amount;
+    if let Some((lender, repaid)) = repayment {
+        payout = amount.checked_sub(repaid)?;
+        res = res
+            .add_attribute("repaid", repaid)
+            .add_message(transfer_msg(cw20_addr, denom, lender, repaid)?);
+    }
+    // Rebasing streams can't be encumbered, only their payout is in shares
+    let tokens = redeem_shares(deps.branch(), &env, cw20_addr, payout)?;
+    let record = WithdrawalRecord {
+        time: block_time,
+        amount: amount.checked_sub(payout)?.checked_add(tokens)?,
+        recipient: info.sender.clone(),
+    };
+    record_withdrawal(deps.storage, id, &record)?;
+    res = res.add_attribute("amount", tokens);
+    if !tokens.is_zero() {
+        res = res.add_message(transfer_msg(cw20_addr, denom, info.sender, tokens)?);
+    }
+    Ok(res)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    REENTRANCY_LOCK.remove(deps.storage);
+    let res = match msg.id {
+        WITHDRAW_REPLY_ID => reply_withdraw(deps, msg),
+        id => Err(StdError::generic_err(format!("unknown reply id: {}", id)).into()),
+    }?;
+    Ok(with_event_namespace(res, &config.event_namespace))
+}
+
+fn reply_withdraw(deps: DepsMut, msg: Reply) -> Result<Response, ContractError> {
+    let pending = PENDING_FALLBACK.load(deps.storage)?;
+    PENDING_FALLBACK.remove(deps.storage);
+
+    // The stream is gone if the withdrawal drained and closed it
+    let id = pending.stream_id.u128();
+    let stream = streams().may_load(deps.storage, id.into())?;
+    let error = match msg.result {
+        ContractResult::Err(error) => error,
+        ContractResult::Ok(_) => {
+            if let Some(mut stream) = stream.filter(|stream| stream.failed_withdraw_count > 0) {
+                stream.failed_withdraw_count = 0;
+                streams().save(deps.storage, id.into(), &stream)?;
+            }
+            return Ok(Response::new());
+        }
+    };
+    if let Some(mut stream) = stream {
+        stream.failed_withdraw_count = stream.failed_withdraw_count.saturating_add(1);
+        streams().save(deps.storage, id.into(), &stream)?;
+    }
+
+    let msg = transfer_msg(
+        pending.cw20_addr.as_ref(),
+        pending.denom.as_deref(),
+        pending.fallback_recipient.clone(),
+        pending.amount,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "reply_withdraw")
+        .add_attribute("stream_id", pending.stream_id)
+        .add_attribute("amount", pending.amount)
+        .add_attribute("fallback_recipient", pending.fallback_recipient)
+        .add_attribute("error", error)
+        .add_message(msg))
+}
+
+pub fn try_partial_cancel(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    refund_amount: Uint128,
+    note: Option<String>,
+) -> Result<Response, ContractError> {
+    validate_note(&note)?;
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_cancel_authorized(deps.as_ref(), &stream, &info.sender) {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    if !is_linear(&stream) {
+        return Err(ContractError::NotLinearStream {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+    let block_time = env.block.time.seconds();
+    check_cancel_window(&stream, block_time)?;
+
+    // Only the unvested part can be refunded, vested funds belong to the recipient
+    let unvested = stream
+        .amount
+        .checked_sub(compute_vested(&stream, block_time)?)?;
+    if refund_amount.is_zero() || refund_amount > unvested {
+        return Err(ContractError::InvalidRefundAmount {});
+    }
+
+    let before = stream.clone();
+    let new_amount = stream.amount.checked_sub(refund_amount)?;
+    rebase_stream(&mut stream, new_amount, block_time)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::PartialCancel,
+        info.sender,
+        block_time,
+        note,
+    )?;
+
+    let config = CONFIG.load(deps.storage)?;
+    let refunded = redeem_shares(
+        deps.branch(),
+        &env,
+        stream.cw20_addr.as_ref(),
+        refund_amount,
+    )?;
+    let msg = transfer_msg(
+        stream.cw20_addr.as_ref(),
+        stream.denom.as_deref(),
+        refund_address(&config, &stream),
+        refunded,
+    )?;
+
+    let data = CancelResult {
+        id,
+        refunded: refund_amount,
+        paid: Uint128::zero(),
+    };
+    Ok(Response::new()
+        .set_data(to_binary(&data)?)
+        .add_attribute("method", "try_partial_cancel")
+        .add_attribute("stream_id", id)
+        .add_attribute("refund_amount", refund_amount)
+        .add_attribute("new_amount", stream.amount)
+        .add_message(msg))
+}
+
+pub fn try_cancel(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    let config = CONFIG.load(deps.storage)?;
+    if !is_cancel_authorized(deps.as_ref(), &stream, &info.sender) && config.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+
+    let block_time = env.block.time.seconds();
+    check_cancel_window(&stream, block_time)?;
+    let before = stream.clone();
+    let (paid, refund) = cancel_stream(deps.as_ref(), &mut stream, block_time)?;
+    let repayment = take_repayment(&mut stream, paid)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Cancel,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    let res = Response::new()
+        .add_attribute("method", "try_cancel")
+        .add_attribute("stream_id", id)
+        .add_attribute("paid", paid)
+        .add_attribute("refund_amount", refund);
+    disburse_cancellation(deps, &env, id, &stream, paid, refund, repayment, res)
+}
+
+/// Stops the stream's vesting at `block_time` and marks it cancelled,
+/// returning what the recipient is paid and the owner refunded
+fn cancel_stream(
+    deps: Deps,
+    stream: &mut Stream,
+    block_time: u64,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let vested = compute_vested(stream, block_time)?;
+    let refund = stream.amount.checked_sub(vested)?;
+    // Vested funds gated by an unmet condition stay in the stream, to be
+    // withdrawn once it holds
+    let paid = match check_condition(deps, stream) {
+        Ok(()) => vested.checked_sub(stream.claimed_amount)?,
+        Err(ContractError::ConditionNotMet {}) => Uint128::zero(),
+        Err(err) => return Err(err),
+    };
+
+    let end_time = stream.end_time.min(schedule_time(stream, block_time));
+    stream.amount = vested;
+    stream.claimed_amount = stream.claimed_amount.checked_add(paid)?;
+    stream.end_time = end_time;
+    stream.checkpoint_time = end_time;
+    stream.checkpoint_vested = vested;
+    stream.rate_per_second = Uint128::zero();
+    stream.rate_per_day = Uint128::zero();
+    stream.cancelled = true;
+    Ok((paid, refund))
+}
+
+/// Sends out a cancellation: the lender's repayment and the rest of `paid`
+/// to the recipient, and `refund` to the owner
+#[allow(clippy::too_many_arguments)]
+fn disburse_cancellation(
+    mut deps: DepsMut,
+    env: &Env,
+    id: Uint128,
+    stream: &Stream,
+    paid: Uint128,
+    refund: Uint128,
+    repayment: Option<(Addr, Uint128)>,
+    res: Response,
+) -> Result<Response, ContractError> {
+    let cw20_addr = stream.cw20_addr.as_ref();
+    let denom = stream.denom.as_deref();
+    let data = CancelResult {
+        id,
+        refunded: refund,
+        paid,
+    };
+    let mut res = res.set_data(to_binary(&data)?);
+    let mut payout = paid;
+    if let Some((lender, repaid)) = repayment {
+        payout = paid.checked_sub(repaid)?;
+        res = res
+            .add_attribute("repaid", repaid)
+            .add_message(transfer_msg(cw20_addr, denom, lender, repaid)?);
+    }
+    // Rebasing streams can't be encumbered, only the payout and refund are
+    // in shares
+    let tokens = redeem_shares(deps.branch(), env, cw20_addr, payout)?;
+    let paid = paid.checked_sub(payout)?.checked_add(tokens)?;
+    if !paid.is_zero() {
+        let recipient = resolve_recipient(deps.as_ref(), id, stream)?;
+        let record = WithdrawalRecord {
+            time: env.block.time.seconds(),
+            amount: paid,
+            recipient: recipient.clone(),
+        };
+        record_withdrawal(deps.storage, id, &record)?;
+        if !tokens.is_zero() {
+            res = res.add_message(transfer_msg(cw20_addr, denom, recipient, tokens)?);
+        }
+    }
+    let refund = redeem_shares(deps.branch(), env, cw20_addr, refund)?;
+    if !refund.is_zero() {
+        let config = CONFIG.load(deps.storage)?;
+        let owner = refund_address(&config, stream);
+        res = res.add_message(transfer_msg(cw20_addr, denom, owner, refund)?);
+    }
+    Ok(res)
+}
+
+/// Cancels like `try_cancel`, but holds the payout and refund in escrow so
+/// that the stream owner can still restore the stream
+pub fn try_soft_cancel(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    let config = CONFIG.load(deps.storage)?;
+    if !is_cancel_authorized(deps.as_ref(), &stream, &info.sender) && config.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+
+    let block_time = env.block.time.seconds();
+    check_cancel_window(&stream, block_time)?;
+    let before = stream.clone();
+    let (paid, refund) = cancel_stream(deps.as_ref(), &mut stream, block_time)?;
+    // Counted in the stats once finalized, as nothing leaves the contract
+    // until then
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    let finalize_after = block_time.saturating_add(SOFT_CANCEL_GRACE_PERIOD);
+    let pending = PendingCancellation {
+        prior: before,
+        paid,
+        refund,
+        finalize_after,
+    };
+    PENDING_CANCELLATIONS.save(deps.storage, id.u128().into(), &pending)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::SoftCancel,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_soft_cancel")
+        .add_attribute("stream_id", id)
+        .add_attribute("paid", paid)
+        .add_attribute("refund_amount", refund)
+        .add_attribute("finalize_after", finalize_after.to_string()))
+}
+
+/// Undoes a soft cancellation within its grace period. The stream vests as
+/// if it had never been cancelled.
+pub fn try_restore_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    let pending = PENDING_CANCELLATIONS
+        .may_load(deps.storage, id.u128().into())?
+        .ok_or(ContractError::NoPendingCancellation {})?;
+    let block_time = env.block.time.seconds();
+    if block_time >= pending.finalize_after {
+        return Err(ContractError::GracePeriodOver {});
+    }
+
+    let prior = pending.prior;
+    stream.amount = prior.amount;
+    stream.claimed_amount = prior.claimed_amount;
+    stream.end_time = prior.end_time;
+    stream.checkpoint_time = prior.checkpoint_time;
+    stream.checkpoint_vested = prior.checkpoint_vested;
+    stream.rate_per_second = prior.rate_per_second;
+    stream.rate_per_day = prior.rate_per_day;
+    stream.cancelled = false;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    PENDING_CANCELLATIONS.remove(deps.storage, id.u128().into());
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::RestoreStream,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_restore_stream")
+        .add_attribute("stream_id", id))
+}
+
+/// Pays out a soft cancellation once its grace period is over. Callable by
+/// anyone.
+pub fn try_finalize_cancellation(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    let pending = PENDING_CANCELLATIONS
+        .may_load(deps.storage, id.u128().into())?
+        .ok_or(ContractError::NoPendingCancellation {})?;
+    let block_time = env.block.time.seconds();
+    if block_time < pending.finalize_after {
+        return Err(ContractError::GracePeriodNotOver {});
+    }
+
+    let repayment = take_repayment(&mut stream, pending.paid)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&pending.prior], &[&stream])?;
+    PENDING_CANCELLATIONS.remove(deps.storage, id.u128().into());
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::FinalizeCancellation,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    let res = Response::new()
+        .add_attribute("method", "try_finalize_cancellation")
+        .add_attribute("stream_id", id)
+        .add_attribute("paid", pending.paid)
+        .add_attribute("refund_amount", pending.refund);
+    disburse_cancellation(
+        deps,
+        &env,
+        id,
+        &stream,
+        pending.paid,
+        pending.refund,
+        repayment,
+        res,
+    )
+}
+
+pub fn try_schedule_cancel(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    cancel_at: Option<u64>,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_cancel_authorized(deps.as_ref(), &stream, &info.sender) {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    // A cancellation that is already due can't be moved or cleared
+    let block_time = env.block.time.seconds();
+    if matches!(stream.cancel_at, Some(due) if due <= block_time) {
+        return Err(ContractError::InvalidCancelTime {});
+    }
+    if let Some(cancel_at) = cancel_at {
+        if cancel_at <= block_time || cancel_at >= stream.end_time {
+            return Err(ContractError::InvalidCancelTime {});
+        }
+        check_cancel_window(&stream, cancel_at)?;
+    }
+
+    stream.cancel_at = cancel_at;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::ScheduleCancel,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_schedule_cancel")
+        .add_attribute("stream_id", id)
+        .add_attribute(
+            "cancel_at",
+            cancel_at.map(|t| t.to_string()).unwrap_or_default(),
+        ))
+}
+
+pub fn try_execute_scheduled_cancel(
+    env: Env,
+    mut deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+    // Settles like `try_cancel`, which a dispute holds off as well
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+    let block_time = env.block.time.seconds();
+    let cancel_at = match stream.cancel_at {
+        Some(cancel_at) if cancel_at <= block_time && cancel_at < stream.end_time => cancel_at,
+        _ => return Err(ContractError::ScheduledCancelNotDue {}),
+    };
+    // The window may have been shortened since it was scheduled
+    check_cancel_window(&stream, cancel_at)?;
+
+    let vested = compute_vested(&stream, block_time)?;
+    let refund = stream.amount.checked_sub(vested)?;
+    // Vested funds gated by an unmet condition stay in the stream, to be
+    // withdrawn once it holds
+    let settle = match check_condition(deps.as_ref(), &stream) {
+        Ok(()) => vested.checked_sub(stream.claimed_amount)?,
+        Err(ContractError::ConditionNotMet {}) => Uint128::zero(),
+        Err(err) => return Err(err),
+    };
+
+    let before = stream.clone();
+    stream.amount = vested;
+    stream.claimed_amount = stream.claimed_amount.checked_add(settle)?;
+    stream.end_time = cancel_at;
+    stream.checkpoint_time = cancel_at;
+    stream.checkpoint_vested = vested;
+    stream.rate_per_second = Uint128::zero();
+    stream.rate_per_day = Uint128::zero();
+    stream.cancelled = true;
+    let repayment = take_repayment(&mut stream, settle)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    update_stats(deps.storage, &[&before], &[&stream])?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::ScheduledCancel,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    let cw20_addr = stream.cw20_addr.as_ref();
+    let denom = stream.denom.as_deref();
+    let data = CancelResult {
+        id,
+        refunded: refund,
+        paid: settle,
+    };
+    let mut res = Response::new()
+        .set_data(to_binary(&data)?)
+        .add_attribute("method", "try_execute_scheduled_cancel")
+        .add_attribute("stream_id", id)
+        .add_attribute("settled", settle)
+        .add_attribute("refund_amount", refund);
+    let mut payout = settle;
+    if let Some((lender, repaid)) = repayment {
+        payout = settle.checked_sub(repaid)?;
+        res = res
+            .add_attribute("repaid", repaid)
+            .add_message(transfer_msg(cw20_addr, denom, lender, repaid)?);
+    }
+    // Rebasing streams can't be encumbered, only the payout and refund are
+    // in shares
+    let tokens = redeem_shares(deps.branch(), &env, cw20_addr, payout)?;
+    let settle = settle.checked_sub(payout)?.checked_add(tokens)?;
+    if !settle.is_zero() {
+        let recipient = resolve_recipient(deps.as_ref(), id, &stream)?;
+        let record = WithdrawalRecord {
+            time: block_time,
+            amount: settle,
+            recipient: recipient.clone(),
+        };
+        record_withdrawal(deps.storage, id, &record)?;
+        if !tokens.is_zero() {
+            res = res.add_message(transfer_msg(cw20_addr, denom, recipient, tokens)?);
+        }
+    }
+    let refund = redeem_shares(deps.branch(), &env, cw20_addr, refund)?;
+    if !refund.is_zero() {
+        let config = CONFIG.load(deps.storage)?;
+        let owner = refund_address(&config, &stream);
+        res = res.add_message(transfer_msg(cw20_addr, denom, owner, refund)?);
+    }
+    Ok(res)
+}
+
+pub fn try_set_cancel_window(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    cancel_window_end: u64,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+    if stream.cancelled {
+        return Err(ContractError::StreamCancelled {});
+    }
+
+    // An irrevocable stream has to stay that way
+    if matches!(stream.cancel_window_end, Some(end) if cancel_window_end > end) {
+        return Err(ContractError::InvalidCancelWindow {});
+    }
+
+    let block_time = env.block.time.seconds();
+    stream.cancel_window_end = Some(cancel_window_end);
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::SetCancelWindow,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_set_cancel_window")
+        .add_attribute("stream_id", id)
+        .add_attribute("cancel_window_end", cancel_window_end.to_string()))
+}
+
+/// Cancellations taking effect after the stream's window are refused
+fn check_cancel_window(stream: &Stream, time: u64) -> Result<(), ContractError> {
+    if matches!(stream.cancel_window_end, Some(end) if time > end) {
+        return Err(ContractError::CancellationWindowClosed {});
+    }
+    Ok(())
+}
+
+pub fn try_freeze_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_freeze_authorized(deps.as_ref(), &stream, &info.sender)? {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+    // A paused stream isn't accruing, and its pause is lifted on its own
+    if stream.paused_at.is_some() {
+        return Err(ContractError::StreamPaused {});
+    }
+
+    let block_time = env.block.time.seconds();
+    if schedule_time(&stream, block_time) >= stream.end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    stream.frozen_at = Some(block_time);
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Freeze,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    let frozen_claimable = compute_claimable(&stream, block_time)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_freeze_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("frozen_claimable", frozen_claimable))
+}
+
+pub fn try_unfreeze_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_freeze_authorized(deps.as_ref(), &stream, &info.sender)? {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    // A recipient freeze is lifted as a whole
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+
+    let block_time = env.block.time.seconds();
+    resume_stream(&mut stream, block_time)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Unfreeze,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_unfreeze_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("end_time", stream.end_time.to_string()))
+}
+
+pub fn try_pause_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_pause_authorized(deps.as_ref(), &stream, &info.sender)? {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+    if stream.paused_at.is_some() {
+        return Err(ContractError::StreamPaused {});
+    }
+
+    let block_time = env.block.time.seconds();
+    if schedule_time(&stream, block_time) >= stream.end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    stream.paused_at = Some(block_time);
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Pause,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_pause_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("paused_at", block_time.to_string()))
+}
+
+pub fn try_resume_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if !is_pause_authorized(deps.as_ref(), &stream, &info.sender)? {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let paused_at = stream.paused_at.ok_or(ContractError::StreamNotPaused {})?;
+    if FROZEN_RECIPIENTS.has(deps.storage, &stream.recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+
+    // Only time paused once the schedule would have reached its start holds
+    // vesting back. An oracle clock may also step back behind the pause.
+    let block_time = env.block.time.seconds();
+    let started_at = checked_add_seconds(stream.start_time, stream.paused_duration)?;
+    let paused_for = block_time.saturating_sub(paused_at.max(started_at));
+    stream.paused_at = None;
+    stream.paused_duration = checked_add_seconds(stream.paused_duration, paused_for)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Resume,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_resume_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("paused_duration", stream.paused_duration.to_string()))
+}
+
+/// Shifts the whole schedule of a frozen stream by the time spent frozen,
+/// which resumes accrual from the frozen amount. That amount stays a floor
+/// for what is vested.
+fn resume_stream(stream: &mut Stream, block_time: u64) -> Result<(), ContractError> {
+    let frozen_at = stream
+        .frozen_at
+        .take()
+        .ok_or(ContractError::StreamNotFrozen {})?;
+    // Only time frozen once the stream had started held accrual back. An
+    // oracle clock may also step back behind the freeze, which then lasted
+    // no time at all.
+    let started_at = checked_add_seconds(stream.start_time, stream.paused_duration)?;
+    let frozen_for = block_time.saturating_sub(frozen_at.max(started_at));
+    stream.start_time = checked_add_seconds(stream.start_time, frozen_for)?;
+    stream.checkpoint_time = checked_add_seconds(stream.checkpoint_time, frozen_for)?;
+    stream.end_time = checked_add_seconds(stream.end_time, frozen_for)?;
+    stream.cliff_time = stream
+        .cliff_time
+        .map(|cliff| checked_add_seconds(cliff, frozen_for))
+        .transpose()?;
+    if let Some(schedule) = &mut stream.unlock_schedule {
+        for (time, _) in schedule.iter_mut() {
+            *time = checked_add_seconds(*time, frozen_for)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn try_add_allowed_token(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let token = canonical_addr(deps.api, token.as_str())?;
+    ALLOWED_TOKENS.update(deps.storage, |mut tokens| -> StdResult<_> {
+        if !tokens.contains(&token) {
+            tokens.push(token.clone());
+        }
+        Ok(tokens)
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "try_add_allowed_token")
+        .add_attribute("token", token))
+}
+
+pub fn try_remove_allowed_token(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let token = canonical_addr(deps.api, token.as_str())?;
+    ALLOWED_TOKENS.update(deps.storage, |mut tokens| -> StdResult<_> {
+        tokens.retain(|allowed| *allowed != token);
+        Ok(tokens)
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "try_remove_allowed_token")
+        .add_attribute("token", token))
+}
+
+pub fn try_update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    new_owner: Option<String>,
+    new_cw20_addr: Option<String>,
+    new_fee_bps: Option<u16>,
+    new_fee_recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if let Some(new_owner) = new_owner {
+        config.owner = canonical_addr(deps.api, new_owner.as_str())?;
+    }
+    if let Some(new_cw20_addr) = new_cw20_addr {
+        let token = canonical_addr(deps.api, new_cw20_addr.as_str())?;
+        ALLOWED_TOKENS.update(deps.storage, |mut tokens| -> StdResult<_> {
+            if !tokens.contains(&token) {
+                tokens.push(token.clone());
+            }
+            Ok(tokens)
+        })?;
+        config.cw20_addr = token;
+    }
+    if let Some(new_fee_bps) = new_fee_bps {
+        if new_fee_bps > MAX_FEE_BPS {
+            return Err(ContractError::InvalidFee {});
+        }
+        config.fee_bps = new_fee_bps;
+    }
+    if let Some(new_fee_recipient) = new_fee_recipient {
+        config.fee_recipient = canonical_addr(deps.api, new_fee_recipient.as_str())?;
+    }
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_update_config")
+        .add_attribute("owner", config.owner)
+        .add_attribute("cw20_addr", config.cw20_addr)
+        .add_attribute("fee_bps", config.fee_bps.to_string())
+        .add_attribute("fee_recipient", config.fee_recipient))
+}
+
+pub fn try_set_contract_paused(
+    deps: DepsMut,
+    info: MessageInfo,
+    paused: bool,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    config.paused = paused;
+    CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_set_contract_paused")
+        .add_attribute("paused", paused.to_string()))
+}
+
+pub fn try_set_dust_policy(
+    deps: DepsMut,
+    info: MessageInfo,
+    policy: DustPolicy,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    config.dust_policy = policy;
+    CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_set_dust_policy")
+        .add_attribute("dust_policy", format!("{:?}", config.dust_policy)))
+}
+
+pub fn try_sweep_fee_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: String,
+    to: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let token = canonical_addr(deps.api, token.as_str())?;
+    let to = match to {
+        Some(to) => canonical_addr(deps.api, to.as_str())?,
+        None => config.fee_recipient,
+    };
+    let amount = FEE_POOL.may_load(deps.storage, &token)?.unwrap_or_default();
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+    FEE_POOL.remove(deps.storage, &token);
+
+    let msg = transfer_msg(Some(&token), None, to.clone(), amount)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_sweep_fee_pool")
+        .add_attribute("token", token)
+        .add_attribute("amount", amount)
+        .add_attribute("to", to)
+        .add_message(msg))
+}
+
+pub fn try_set_emergency_recipient(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: Option<String>,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    config.emergency_recipient = address
+        .map(|address| canonical_addr(deps.api, address.as_str()))
+        .transpose()?;
+    CONFIG.save(deps.storage, &config)?;
+
+    let address = config.emergency_recipient.map(Addr::into_string);
+    Ok(Response::new()
+        .add_attribute("method", "try_set_emergency_recipient")
+        .add_attribute("emergency_recipient", address.unwrap_or_default()))
+}
+
+pub fn try_freeze_recipient(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    if FROZEN_RECIPIENTS.has(deps.storage, &recipient) {
+        return Err(ContractError::RecipientFrozen {});
+    }
+
+    // Streams that are already frozen, paused or over are left as they are
+    let block_time = env.block.time.seconds();
+    let running = streams()
+        .idx
+        .recipient
+        .prefix(recipient.as_bytes().to_vec())
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| match item {
+            Ok((_, stream)) => {
+                stream.frozen_at.is_none()
+                    && stream.paused_at.is_none()
+                    && !stream.cancelled
+                    && schedule_time(stream, block_time) < stream.end_time
+            }
+            Err(_) => true,
+        })
+        .map(|item| -> StdResult<_> {
+            let (pk, stream) = item?;
+            Ok((id_from_key(&pk)?, stream))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut ids = vec![];
+    for (id, mut stream) in running {
+        stream.frozen_at = Some(block_time);
+        streams().save(deps.storage, id.u128().into(), &stream)?;
+        log_stream_action(
+            deps.storage,
+            id,
+            StreamActionKind::Freeze,
+            info.sender.clone(),
+            block_time,
+            None,
+        )?;
+        ids.push(id);
+    }
+    FROZEN_RECIPIENTS.save(deps.storage, &recipient, &ids)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_freeze_recipient")
+        .add_attribute("recipient", recipient)
+        .add_attribute("frozen_streams", ids.len().to_string()))
+}
+
+pub fn try_unfreeze_recipient(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    let ids = FROZEN_RECIPIENTS
+        .may_load(deps.storage, &recipient)?
+        .ok_or(ContractError::RecipientNotFrozen {})?;
+    FROZEN_RECIPIENTS.remove(deps.storage, &recipient);
+
+    let block_time = env.block.time.seconds();
+    for id in ids.iter() {
+        let mut stream = streams().load(deps.storage, id.u128().into())?;
+        resume_stream(&mut stream, block_time)?;
+        streams().save(deps.storage, id.u128().into(), &stream)?;
+        log_stream_action(
+            deps.storage,
+            *id,
+            StreamActionKind::Unfreeze,
+            info.sender.clone(),
+            block_time,
+            None,
+        )?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "try_unfreeze_recipient")
+        .add_attribute("recipient", recipient)
+        .add_attribute("unfrozen_streams", ids.len().to_string()))
+}
+
+/// Whether `sender` may freeze `stream`, as its owner or the arbiter.
+fn is_freeze_authorized(deps: Deps, stream: &Stream, sender: &Addr) -> StdResult<bool> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(stream.owner == *sender || config.arbiter.as_ref() == Some(sender))
+}
+
+/// Whether `sender` may pause `stream`, as its owner or the contract owner.
+fn is_pause_authorized(deps: Deps, stream: &Stream, sender: &Addr) -> StdResult<bool> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(stream.owner == *sender || config.owner == *sender)
+}
+
+/// Whether `sender` may cancel `stream`, either as its owner or as one of the
+/// global cancellers. Refunds go to the stream owner, see `refund_address`.
+fn is_cancel_authorized(deps: Deps, stream: &Stream, sender: &Addr) -> bool {
+    stream.owner == *sender || GLOBAL_CANCELLERS.has(deps.storage, sender)
+}
+
+pub fn try_set_global_canceller(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+    allowed: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let address = canonical_addr(deps.api, address.as_str())?;
+    if allowed {
+        GLOBAL_CANCELLERS.save(deps.storage, &address, &Empty {})?;
+    } else {
+        GLOBAL_CANCELLERS.remove(deps.storage, &address);
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "try_set_global_canceller")
+        .add_attribute("address", address)
+        .add_attribute("allowed", allowed.to_string()))
+}
+
+pub fn try_crystallize_stream(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    note: Option<String>,
+) -> Result<Response, ContractError> {
+    validate_note(&note)?;
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    if !is_linear(&stream) {
+        return Err(ContractError::NotLinearStream {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+
+    let block_time = env.block.time.seconds();
+    if schedule_time(&stream, block_time) >= stream.end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    let amount = stream.amount;
+    rebase_stream(&mut stream, amount, block_time)?;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Crystallize,
+        info.sender,
+        block_time,
+        note,
+    )?;
+
+    let lump = stream
+        .checkpoint_vested
+        .checked_sub(stream.claimed_amount)?;
+    Ok(Response::new()
+        .add_attribute("method", "try_crystallize_stream")
+        .add_attribute("stream_id", id)
+        .add_attribute("vested", stream.checkpoint_vested)
+        .add_attribute("claimable", lump))
+}
+
+pub fn try_convert(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+    steps: u32,
+) -> Result<Response, ContractError> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    if stream.owner != info.sender && stream.recipient != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if !is_linear(&stream) {
+        return Err(ContractError::NotLinearStream {});
+    }
+    if stream.frozen_at.is_some() {
+        return Err(ContractError::StreamFrozen {});
+    }
+
+    let block_time = env.block.time.seconds();
+    let now = schedule_time(&stream, block_time);
+    if now >= stream.end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    // Steps are spread over what's left of the stream
+    let duration = checked_sub_seconds(stream.end_time, now.max(stream.checkpoint_time))?;
+    if steps == 0 || u64::from(steps) > duration {
+        return Err(ContractError::InvalidInstallments {});
+    }
+
+    let res = Response::new()
+        .add_attribute("method", "try_convert")
+        .add_attribute("stream_id", id)
+        .add_attribute("steps", steps.to_string());
+
+    // The first party to send the terms only proposes them
+    let pending = PENDING_CONVERSIONS.may_load(deps.storage, id.u128().into())?;
+    match pending {
+        Some(pending) if pending.proposer != info.sender && pending.steps == steps => {
+            PENDING_CONVERSIONS.remove(deps.storage, id.u128().into());
+        }
+        _ => {
+            let pending = PendingConversion {
+                proposer: info.sender,
+                steps,
+            };
+            PENDING_CONVERSIONS.save(deps.storage, id.u128().into(), &pending)?;
+            return Ok(res.add_attribute("status", "proposed"));
+        }
+    }
+
+    // Vested funds are checkpointed and stay claimable, only the rest is
+    // paid out in steps
+    let amount = stream.amount;
+    rebase_stream(&mut stream, amount, block_time)?;
+
+    // Every step must pay out at least one token
+    let remaining = stream.amount.checked_sub(stream.checkpoint_vested)?;
+    if remaining < Uint128::from(steps) {
+        return Err(ContractError::InvalidInstallments {});
+    }
+    stream.installments = Some(Installments {
+        count: steps,
+        interval_seconds: duration / u64::from(steps),
+    });
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+    log_stream_action(
+        deps.storage,
+        id,
+        StreamActionKind::Convert,
+        info.sender,
+        block_time,
+        None,
+    )?;
+
+    Ok(res.add_attribute("status", "converted"))
+}
+
+/// Loads the streams to consolidate, checking they can share one schedule:
+/// linear, neither frozen nor paused, free of liens, conditions, cliffs and
+/// scheduled cancels, behind the block time by as much time spent paused,
+/// paid to `recipient` the same way and funded by the same owner.
+fn load_consolidation(
+    deps: Deps,
+    recipient: &Addr,
+    ids: &[Uint128],
+) -> Result<Vec<Stream>, ContractError> {
+    if ids.len() < 2 {
+        return Err(ContractError::InvalidConsolidation {});
+    }
+    if ids.len() > MAX_BATCH_SIZE {
+        return Err(ContractError::BatchTooLarge {});
+    }
+    if ids.iter().enumerate().any(|(i, id)| ids[..i].contains(id)) {
+        return Err(ContractError::InvalidConsolidation {});
+    }
+
+    let mut loaded: Vec<Stream> = Vec::with_capacity(ids.len());
+    for id in ids {
+        let stream = streams().load(deps.storage, id.u128().into())?;
+        if stream.recipient != *recipient {
+            return Err(ContractError::NotStreamRecipient {});
+        }
+        if !is_linear(&stream) {
+            return Err(ContractError::NotLinearStream {});
+        }
+        if stream.frozen_at.is_some() {
+            return Err(ContractError::StreamFrozen {});
+        }
+        if stream.paused_at.is_some() {
+            return Err(ContractError::StreamPaused {});
+        }
+        // A lien or release terms only cover the stream they were set on, and
+        // would be dropped or stretched over the others by merging
+        if stream.encumbrance.is_some() {
+            return Err(ContractError::StreamEncumbered {});
+        }
+        if stream.condition.is_some() || stream.cliff_time.is_some() || stream.cancel_at.is_some() {
+            return Err(ContractError::InvalidConsolidation {});
+        }
+        if let Some(first) = loaded.first() {
+            if stream.owner != first.owner
+                || stream.recipient_controller != first.recipient_controller
+                || stream.cw20_addr != first.cw20_addr
+                || stream.denom != first.denom
+                || stream.paused_duration != first.paused_duration
+            {
+                return Err(ContractError::InvalidConsolidation {});
+            }
+        }
+        loaded.push(stream);
+    }
+    Ok(loaded)
+}
+
+pub fn try_request_consolidation(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    ids: Vec<Uint128>,
+) -> Result<Response, ContractError> {
+    let loaded = load_consolidation(deps.as_ref(), &info.sender, &ids)?;
+    let end_time = loaded.iter().map(|s| s.end_time).max().unwrap();
+    if schedule_time(&loaded[0], env.block.time.seconds()) >= end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    // Pending until the owner approves, keyed by the stream that is kept
+    PENDING_CONSOLIDATIONS.save(deps.storage, ids[0].u128().into(), &ids)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "try_request_consolidation")
+        .add_attribute("stream_id", ids[0])
+        .add_attribute("count", ids.len().to_string()))
+}
+
+pub fn try_approve_consolidation(
+    env: Env,
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Uint128,
+) -> Result<Response, ContractError> {
+    let ids = PENDING_CONSOLIDATIONS.load(deps.storage, id.u128().into())?;
+    let target = streams().load(deps.storage, id.u128().into())?;
+    // Streams may have changed since the request, so check them again
+    let mut loaded = load_consolidation(deps.as_ref(), &target.recipient, &ids)?;
+    let before = loaded.clone();
+    if target.owner != info.sender {
+        return Err(ContractError::NotStreamOwner {});
+    }
+
+    let block_time = env.block.time.seconds();
+    let start_time = loaded.iter().map(|s| s.start_time).min().unwrap();
+    let end_time = loaded.iter().map(|s| s.end_time).max().unwrap();
+    // The streams share their schedule clock, see `load_consolidation`
+    let now = schedule_time(&loaded[0], block_time);
+    if now >= end_time {
+        return Err(ContractError::StreamEnded {});
+    }
+
+    // What's vested stays claimable, the rest of every stream vests linearly
+    // until the latest end time
+    let mut vested_unclaimed = Uint128::zero();
+    let mut remaining = Uint128::zero();
+    for stream in loaded.iter() {
+        vested_unclaimed = vested_unclaimed
+            .checked_add(compute_vested(stream, block_time)?.checked_sub(stream.claimed_amount)?)?;
+        remaining = remaining.checked_add(stream.amount.checked_sub(stream.claimed_amount)?)?;
+    }
+
+    let mut stream = loaded.remove(0);
+    stream.start_time = start_time;
+    stream.end_time = end_time;
+    stream.checkpoint_time = now.max(start_time);
+    stream.checkpoint_vested = stream.claimed_amount.checked_add(vested_unclaimed)?;
+    stream.amount = stream.claimed_amount.checked_add(remaining)?;
+    let (rate_per_second, rate_per_day) = vesting_rates(
+        stream.amount.checked_sub(stream.checkpoint_vested)?,
+        checked_sub_seconds(stream.end_time, stream.checkpoint_time)?,
+    );
+    stream.rate_per_second = rate_per_second;
+    stream.rate_per_day = rate_per_day;
+    streams().save(deps.storage, id.u128().into(), &stream)?;
+
+    // The other streams are closed, keeping what was claimed from them
+    let mut after = vec![stream.clone()];
+    for (other_id, mut other) in ids[1..].iter().zip(loaded) {
+        other.amount = other.claimed_amount;
+        other.checkpoint_vested = other.claimed_amount;
+        other.rate_per_second = Uint128::zero();
+        other.rate_per_day = Uint128::zero();
+        streams().save(deps.storage, other_id.u128().into(), &other)?;
+        after.push(other);
+    }
+    update_stats(
+        deps.storage,
+        &before.iter().collect::<Vec<_>>(),
+        &after.iter().collect::<Vec<_>>(),
+    )?;
+
+    PENDING_CONSOLIDATIONS.remove(deps.storage, id.u128().into());
+    for consolidated in ids.iter() {
+        PENDING_CONVERSIONS.remove(deps.storage, consolidated.u128().into());
+        log_stream_action(
+            deps.storage,
+            *consolidated,
+            StreamActionKind::Consolidate,
+            info.sender.clone(),
+            block_time,
+            None,
+        )?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "try_approve_consolidation")
+        .add_attribute("stream_id", id)
+        .add_attribute("count", ids.len().to_string())
+        .add_attribute("new_amount", stream.amount))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract {});
+    }
+    let stored_version = parse_version(&stored.version)?;
+    if stored_version > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::CannotDowngrade {});
+    }
+
+    if stored_version < (0, 2, 0) {
+        migrate_from_v0_1(deps.branch())?;
+    }
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION))
+}
+
+/// Splits a `major.minor.patch` version so that versions can be compared
+fn parse_version(version: &str) -> StdResult<(u64, u64, u64)> {
+    let invalid = || StdError::generic_err(format!("invalid version: {}", version));
+    let parts = version
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| invalid())?;
+    match parts.as_slice() {
+        [major, minor, patch] => Ok((*major, *minor, *patch)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Rewrites the 0.1.0 config and streams in the current layout. Fields added
+/// since get the values matching how 0.1.0 behaved.
+fn migrate_from_v0_1(deps: DepsMut) -> StdResult<()> {
+    let legacy = LEGACY_CONFIG.load(deps.storage)?;
+    let config = Config {
+        owner: legacy.owner.clone(),
+        cw20_addr: legacy.cw20_addr,
+        event_namespace: None,
+        arbiter: None,
+        max_total_streams: None,
+        name_service: None,
+        auto_close_on_final_withdraw: false,
+        time_oracle: None,
+        fee_bps: 0,
+        fee_recipient: legacy.owner,
+        min_claim_interval_seconds: None,
+        emergency_recipient: None,
+        paused: false,
+        dust_policy: DustPolicy::DropToZero,
+    };
+    CONFIG.save(deps.storage, &config)?;
+    ALLOWED_TOKENS.save(deps.storage, &vec![config.cw20_addr.clone()])?;
+
+    let legacy_streams = LEGACY_STREAMS
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (pk, legacy) in legacy_streams {
+        let id = id_from_key(&pk)?;
+        let stream = Stream {
+            owner: legacy.owner,
+            recipient: legacy.recipient,
+            amount: legacy.amount,
+            claimed_amount: legacy.claimed_amount,
+            start_time: legacy.start_time,
+            end_time: legacy.end_time,
+            rate_per_second: legacy.rate_per_second,
+            rate_per_day: legacy.rate_per_second.checked_mul(Uint128::new(86400))?,
+            installments: None,
+            checkpoint_time: legacy.start_time,
+            checkpoint_vested: Uint128::zero(),
+            fallback_recipient: None,
+            recipient_controller: None,
+            // The creation time wasn't recorded, the start time is the
+            // closest bound on it
+            created_at: legacy.start_time,
+            frozen_at: None,
+            beneficiary: None,
+            condition: None,
+            cancel_at: None,
+            cancelled: false,
+            rounding_unit: None,
+            cliff_time: None,
+            encumbrance: None,
+            cw20_addr: Some(config.cw20_addr.clone()),
+            denom: None,
+            allowed_destinations: None,
+            failed_withdraw_count: 0,
+            last_claim_time: None,
+            unlock_schedule: None,
+            paused_at: None,
+            paused_duration: 0,
+            cancel_window_end: None,
+        };
+        // Removed first so that the indexed map doesn't try to read the old
+        // layout when it builds the index entries
+        LEGACY_STREAMS.remove(deps.storage, id.u128().into());
+        streams().save(deps.storage, id.u128().into(), &stream)?;
+        update_stats(deps.storage, &[], &[&stream])?;
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, mut env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    let config = CONFIG.load(deps.storage)?;
+    env.block.time = current_time(deps, &config, &env);
+    match msg {
+        QueryMsg::GetConfig {} => to_binary(&query_config(deps)?),
+        QueryMsg::Bootstrap { recipient } => to_binary(&query_bootstrap(deps, env, recipient)?),
+        QueryMsg::GetStream { id } => to_binary(&query_stream(deps, env, id)?),
+        QueryMsg::TokenAvailable {} => to_binary(&query_token_available(deps)?),
+        QueryMsg::FeePool { token } => to_binary(&query_fee_pool(deps, token)?),
+        QueryMsg::StorageEstimate {} => to_binary(&query_storage_estimate(deps)?),
+        QueryMsg::ListStreams { start_after, limit } => {
+            to_binary(&query_list_streams(deps, env, start_after, limit)?)
+        }
+        QueryMsg::OwnerSummary { owner, start_after } => {
+            to_binary(&query_owner_summary(deps, env, owner, start_after)?)
+        }
+        QueryMsg::StreamsByOwner {
+            owner,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_by_owner(
+            deps,
+            env,
+            owner,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::StreamsByToken {
+            cw20_addr,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_by_token(
+            deps,
+            env,
+            cw20_addr,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::StreamsByRecipient {
+            recipient,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_by_recipient(
+            deps,
+            env,
+            recipient,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::StreamsInGroup {
+            group,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_in_group(
+            deps,
+            env,
+            group,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::StreamsByStatus {
+            status,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_by_status(
+            deps,
+            env,
+            status,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::StreamsCreatedBetween {
+            from,
+            to,
+            start_after,
+            limit,
+        } => to_binary(&query_streams_created_between(
+            deps,
+            env,
+            from,
+            to,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::IsWithdrawAuthorized { id, address } => {
+            to_binary(&query_is_withdraw_authorized(deps, id, address)?)
+        }
+        QueryMsg::GetStreamHuman { id } => to_binary(&query_stream_human(deps, env, id)?),
+        QueryMsg::PreviewTopUp { id, amount } => {
+            to_binary(&query_preview_top_up(deps, env, id, amount)?)
+        }
+        QueryMsg::WithdrawPreview { id } => to_binary(&query_withdraw_preview(deps, env, id)?),
+        QueryMsg::OverdueClaims { start_after, limit } => {
+            to_binary(&query_overdue_claims(deps, env, start_after, limit)?)
+        }
+        QueryMsg::StuckStreams {
+            min_failures,
+            start_after,
+            limit,
+        } => to_binary(&query_stuck_streams(
+            deps,
+            min_failures,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::PortfolioFor { recipient, limit } => {
+            to_binary(&query_portfolio_for(deps, env, recipient, limit)?)
+        }
+        QueryMsg::RecipientTaxReport {
+            recipient,
+            from,
+            to,
+            start_after,
+            limit,
+        } => to_binary(&query_recipient_tax_report(
+            deps,
+            recipient,
+            from,
+            to,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::Claimable { id } => to_binary(&query_claimable(deps, env, id)?),
+        QueryMsg::ClaimableAt { id, at } => to_binary(&query_claimable_at(deps, id, at)?),
+        QueryMsg::ProjectedTotals { id } => to_binary(&query_projected_totals(deps, id)?),
+        QueryMsg::GetProposal { id } => to_binary(&query_proposal(deps, id)?),
+        QueryMsg::PendingStreams {
+            recipient,
+            start_after,
+            limit,
+        } => to_binary(&query_pending_streams(deps, recipient, start_after, limit)?),
+        QueryMsg::DelegateAllowance { id, delegate } => {
+            to_binary(&query_delegate_allowance(deps, id, delegate)?)
+        }
+        QueryMsg::StreamActions {
+            id,
+            start_after,
+            limit,
+        } => to_binary(&query_stream_actions(deps, id, start_after, limit)?),
+        QueryMsg::Stats {} => to_binary(&query_stats(deps)?),
+        QueryMsg::WithdrawalHistory {
+            id,
+            start_after,
+            limit,
+        } => to_binary(&query_withdrawal_history(deps, id, start_after, limit)?),
+    }
+}
+
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(ConfigResponse {
+        owner: config.owner.into_string(),
+        cw20_addr: config.cw20_addr.into_string(),
+        event_namespace: config.event_namespace,
+        arbiter: config.arbiter.map(Addr::into_string),
+        max_total_streams: config.max_total_streams,
+        name_service: config.name_service.map(Addr::into_string),
+        auto_close_on_final_withdraw: config.auto_close_on_final_withdraw,
+        time_oracle: config.time_oracle,
+        fee_bps: config.fee_bps,
+        fee_recipient: config.fee_recipient.into_string(),
+        min_claim_interval_seconds: config.min_claim_interval_seconds,
+        emergency_recipient: config.emergency_recipient.map(Addr::into_string),
+        paused: config.paused,
+        dust_policy: config.dust_policy,
+    })
+}
+
+fn query_bootstrap(
+    deps: Deps,
+    env: Env,
+    recipient: Option<String>,
+) -> StdResult<BootstrapResponse> {
+    let portfolio = recipient
+        .map(|recipient| query_portfolio_for(deps, env, recipient, None))
+        .transpose()?;
+    let total_claimable = portfolio
+        .as_ref()
+        .map(|portfolio| {
+            portfolio
+                .streams
+                .iter()
+                .try_fold(Uint128::zero(), |sum, entry| {
+                    sum.checked_add(entry.claimable)
+                })
+        })
+        .transpose()?;
+    Ok(BootstrapResponse {
+        config: query_config(deps)?,
+        stats: query_stats(deps)?,
+        portfolio,
+        total_claimable,
+    })
+}
+
+fn query_token_available(deps: Deps) -> StdResult<TokenAvailableResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let available = deps
+        .querier
+        .query_wasm_smart::<TokenInfoResponse>(config.cw20_addr, &Cw20QueryMsg::TokenInfo {})
+        .is_ok();
+    Ok(TokenAvailableResponse { available })
+}
+
+fn query_fee_pool(deps: Deps, token: String) -> StdResult<FeePoolResponse> {
+    let token = deps.api.addr_validate(&token)?;
+    let amount = FEE_POOL.may_load(deps.storage, &token)?.unwrap_or_default();
+    Ok(FeePoolResponse {
+        token: token.into_string(),
+        amount,
+    })
+}
+
+fn query_storage_estimate(deps: Deps) -> StdResult<StorageEstimateResponse> {
+    let bytes_per_stream = stream_storage_estimate()?;
+    let total_streams = STREAM_SEQ.load(deps.storage)?;
+    let total_bytes = total_streams.checked_mul(Uint128::from(bytes_per_stream))?;
+    Ok(StorageEstimateResponse {
+        bytes_per_stream,
+        total_streams,
+        total_bytes,
+    })
+}
+
+/// Approximate bytes one stream occupies: its serialized value with every
+/// optional field set, under its primary key and in each index.
+fn stream_storage_estimate() -> StdResult<u64> {
+    let addr = Addr::unchecked("a".repeat(ESTIMATE_ADDR_LEN));
+    let stream = Stream {
+        owner: addr.clone(),
+        recipient: addr.clone(),
+        amount: Uint128::MAX,
+        claimed_amount: Uint128::MAX,
+        start_time: u64::MAX,
+        end_time: u64::MAX,
+        rate_per_second: Uint128::MAX,
+        rate_per_day: Uint128::MAX,
+        installments: Some(Installments {
+            count: u32::MAX,
+            interval_seconds: u64::MAX,
+        }),
+        checkpoint_time: u64::MAX,
+        checkpoint_vested: Uint128::MAX,
+        fallback_recipient: Some(addr.clone()),
+        recipient_controller: Some(addr.clone()),
+        created_at: u64::MAX,
+        frozen_at: Some(u64::MAX),
+        beneficiary: Some(Beneficiary {
+            address: addr.clone(),
+            inactivity_seconds: u64::MAX,
+        }),
+        condition: Some(Condition {
+            contract: addr.clone(),
+            msg: Binary::from(vec![0; ESTIMATE_CONDITION_LEN]),
+            expected: Binary::from(vec![0; ESTIMATE_CONDITION_LEN]),
+        }),
+        cancel_at: Some(u64::MAX),
+        cancelled: false,
+        rounding_unit: Some(Uint128::MAX),
+        cliff_time: Some(u64::MAX),
+        encumbrance: Some(Encumbrance {
+            lender: addr.clone(),
+            outstanding: Uint128::MAX,
+        }),
+        cw20_addr: Some(addr.clone()),
+        denom: Some("a".repeat(ESTIMATE_DENOM_LEN)),
+        allowed_destinations: Some(vec![addr; MAX_DESTINATIONS]),
+        failed_withdraw_count: u32::MAX,
+        last_claim_time: Some(u64::MAX),
+        unlock_schedule: Some(vec![(u64::MAX, Uint128::MAX); MAX_UNLOCK_POINTS]),
+        paused_at: Some(u64::MAX),
+        paused_duration: u64::MAX,
+        cancel_window_end: Some(u64::MAX),
+    };
+    let value = to_vec(&stream)?.len();
+
+    // Keys are length-prefixed namespaces followed by the key parts, index
+    // entries store the primary key length as their value
+    let pk = 16;
+    let primary = 2 + "stream".len() + pk + value;
+    let created_at = 2 + "stream__created_at".len() + 2 + 8 + pk + 2;
+    let recipient = 2 + "stream__recipient".len() + 2 + ESTIMATE_ADDR_LEN + pk + 2;
+    let owner = 2 + "stream__owner".len() + 2 + ESTIMATE_ADDR_LEN + pk + 2;
+    let token = 2 + "stream__token".len() + 2 + ESTIMATE_DENOM_LEN + pk + 2;
+    Ok((primary + created_at + recipient + owner + token) as u64)
+}
+
+fn query_stream(deps: Deps, env: Env, id: Uint128) -> StdResult<StreamResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    Ok(stream_response(id, stream, env.block.time.seconds()))
+}
+
+fn query_claimable(deps: Deps, env: Env, id: Uint128) -> StdResult<ClaimableResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    let claimable = compute_claimable(&stream, env.block.time.seconds())?;
+    Ok(ClaimableResponse {
+        claimable: share_value(deps, &env, &stream, claimable)?,
+        claimed: stream.claimed_amount,
+        remaining: stream.amount.checked_sub(stream.claimed_amount)?,
+    })
+}
+
+fn query_claimable_at(deps: Deps, id: Uint128, at: u64) -> StdResult<ClaimableAtResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    let vested = compute_vested(&stream, at)?;
+    let claimable = round_claimable(
+        &stream,
+        vested,
+        vested.saturating_sub(stream.claimed_amount),
+    )?;
+    Ok(ClaimableAtResponse { claimable })
+}
+
+fn query_projected_totals(deps: Deps, id: Uint128) -> StdResult<ProjectedTotalsResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    Ok(ProjectedTotalsResponse {
+        total_to_recipient: stream.amount,
+        already_claimed: stream.claimed_amount,
+        remaining_to_claim: stream.amount.checked_sub(stream.claimed_amount)?,
+    })
+}
+
+fn query_delegate_allowance(
+    deps: Deps,
+    id: Uint128,
+    delegate: String,
+) -> StdResult<DelegateAllowanceResponse> {
+    let delegate = canonical_addr(deps.api, delegate.as_str())?;
+    let allowance = DELEGATE_ALLOWANCES.may_load(deps.storage, (id.u128().into(), &delegate))?;
+    Ok(DelegateAllowanceResponse { allowance })
+}
+
+fn query_proposal(deps: Deps, id: u64) -> StdResult<ProposalResponse> {
+    let proposal = PROPOSALS.load(deps.storage, id.into())?;
+    Ok(ProposalResponse { id, proposal })
+}
+
+fn query_pending_streams(
+    deps: Deps,
+    recipient: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<PendingStreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let recipient = recipient
+        .map(|addr| canonical_addr(deps.api, addr.as_str()))
+        .transpose()?;
+    let min = start_after.map(Bound::exclusive_int);
+
+    let mut proposals = vec![];
+    let mut last_scanned = None;
+    let scan = PROPOSALS
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(MAX_SCAN);
+    for item in scan {
+        let (pk, proposal) = item?;
+        let id: [u8; 8] = pk
+            .as_slice()
+            .try_into()
+            .map_err(|_| StdError::generic_err("invalid proposal key"))?;
+        let id = u64::from_be_bytes(id);
+        last_scanned = Some(id);
+
+        if proposal.approved || matches!(&recipient, Some(r) if *r != proposal.recipient) {
+            continue;
+        }
+        proposals.push(ProposalResponse { id, proposal });
+        if proposals.len() == limit {
+            break;
+        }
+    }
+    Ok(PendingStreamsResponse {
+        proposals,
+        last_scanned,
+    })
+}
+
+fn query_stream_human(deps: Deps, env: Env, id: Uint128) -> StdResult<StreamHumanResponse> {
+    let stream = query_stream(deps, env, id)?;
+    Ok(StreamHumanResponse {
+        start_time: format_rfc3339(stream.start_time),
+        end_time: format_rfc3339(stream.end_time),
+        created_at: format_rfc3339(stream.created_at),
+        stream,
+    })
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn format_rfc3339(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let secs = seconds % 86400;
+
+    // Civil date from days since 1970-01-01, counting in 400 year eras
+    // starting on March 1st so leap days fall at the end of each year
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn query_is_withdraw_authorized(
+    deps: Deps,
+    id: Uint128,
+    address: String,
+) -> StdResult<IsWithdrawAuthorizedResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    let address = canonical_addr(deps.api, address.as_str())?;
+    Ok(IsWithdrawAuthorizedResponse {
+        authorized: is_withdraw_authorized(deps, id, &stream, &address)?,
+    })
+}
+
+fn query_list_streams(
+    deps: Deps,
+    env: Env,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let min = start_after.map(|id| Bound::exclusive_int(id.u128()));
+
+    let streams = streams()
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            Ok(stream_response(id_from_key(&pk)?, stream, block_time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamsResponse { streams })
+}
+
+fn query_streams_by_owner(
+    deps: Deps,
+    env: Env,
+    owner: String,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let owner = canonical_addr(deps.api, owner.as_str())?;
+    let min = start_after.map(|id| Bound::exclusive(id.u128().to_be_bytes().to_vec()));
+
+    let streams = streams()
+        .idx
+        .owner
+        .prefix(owner.as_bytes().to_vec())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            Ok(stream_response(id_from_key(&pk)?, stream, block_time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamsResponse { streams })
+}
+
+fn query_streams_by_token(
+    deps: Deps,
+    env: Env,
+    cw20_addr: String,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let cw20_addr = canonical_addr(deps.api, cw20_addr.as_str())?;
+    let min = start_after.map(|id| Bound::exclusive(id.u128().to_be_bytes().to_vec()));
+
+    let streams = streams()
+        .idx
+        .token
+        .prefix(cw20_addr.as_bytes().to_vec())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            Ok(stream_response(id_from_key(&pk)?, stream, block_time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamsResponse { streams })
+}
+
+fn query_owner_summary(
+    deps: Deps,
+    env: Env,
+    owner: String,
+    start_after: Option<Uint128>,
+) -> StdResult<OwnerSummaryResponse> {
+    let block_time = env.block.time.seconds();
+    let owner = canonical_addr(deps.api, owner.as_str())?;
+    let min = start_after.map(|id| Bound::exclusive(id.u128().to_be_bytes().to_vec()));
+
+    let mut summary = OwnerSummaryResponse {
+        stream_count: 0,
+        total_committed: Uint128::zero(),
+        total_remaining: Uint128::zero(),
+        total_refundable: Uint128::zero(),
+        last_scanned: None,
+    };
+    let scan = streams()
+        .idx
+        .owner
+        .prefix(owner.as_bytes().to_vec())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(MAX_SCAN);
+    for item in scan {
+        let (pk, stream) = item?;
+        summary.stream_count += 1;
+        summary.total_committed = summary.total_committed.checked_add(stream.amount)?;
+        summary.total_remaining = summary
+            .total_remaining
+            .checked_add(stream.amount.checked_sub(stream.claimed_amount)?)?;
+        // What `try_cancel` would refund
+        let cancellable =
+            !stream.cancelled && !matches!(stream.cancel_window_end, Some(end) if block_time > end);
+        if cancellable {
+            let vested = compute_vested(&stream, block_time)?;
+            summary.total_refundable = summary
+                .total_refundable
+                .checked_add(stream.amount.checked_sub(vested)?)?;
+        }
+        if summary.stream_count == MAX_SCAN as u64 {
+            summary.last_scanned = Some(id_from_key(&pk)?);
+        }
+    }
+    Ok(summary)
+}
+
+fn query_streams_in_group(
+    deps: Deps,
+    env: Env,
+    group: String,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let min = start_after.map(|id| Bound::exclusive(id.u128().to_be_bytes().to_vec()));
+
+    // Streams closed since they were tagged are left out
+    let mut streams_in_group = vec![];
+    let ids = GROUPS
+        .prefix(group.as_str())
+        .keys(deps.storage, min, None, Order::Ascending);
+    for pk in ids {
+        let id = id_from_key(&pk)?;
+        if let Some(stream) = streams().may_load(deps.storage, id.u128().into())? {
+            streams_in_group.push(stream_response(id, stream, block_time));
+            if streams_in_group.len() == limit {
+                break;
+            }
+        }
+    }
+    Ok(StreamsResponse {
+        streams: streams_in_group,
+    })
+}
+
+fn query_streams_by_status(
+    deps: Deps,
+    env: Env,
+    status: StreamStatus,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsByStatusResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let min = start_after.map(|id| Bound::exclusive_int(id.u128()));
+
+    let mut matching = vec![];
+    let mut last_scanned = None;
+    let scan = streams()
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(MAX_SCAN);
+    for item in scan {
+        let (pk, stream) = item?;
+        let id = id_from_key(&pk)?;
+        last_scanned = Some(id);
+
+        if stream_status(&stream, block_time) != status {
+            continue;
+        }
+        matching.push(stream_response(id, stream, block_time));
+        if matching.len() == limit {
+            break;
+        }
+    }
+    Ok(StreamsByStatusResponse {
+        streams: matching,
+        last_scanned,
+    })
+}
+
+fn query_streams_by_recipient(
+    deps: Deps,
+    env: Env,
+    recipient: String,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    let min = start_after.map(|id| Bound::exclusive(id.u128().to_be_bytes().to_vec()));
+
+    let streams = streams()
+        .idx
+        .recipient
+        .prefix(recipient.as_bytes().to_vec())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            Ok(stream_response(id_from_key(&pk)?, stream, block_time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamsResponse { streams })
+}
+
+fn query_streams_created_between(
+    deps: Deps,
+    env: Env,
+    from: u64,
+    to: u64,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+
+    let min = match start_after {
+        // Resume right after the last stream returned, keyed by its creation time
+        Some(id) => {
+            let last = streams().load(deps.storage, id.u128().into())?;
+            if last.created_at < from {
+                Bound::inclusive((U64Key::new(from), vec![]).joined_key())
+            } else {
+                let pk = U128Key::new(id.u128()).joined_key();
+                Bound::exclusive((U64Key::new(last.created_at), pk).joined_key())
+            }
+        }
+        None => Bound::inclusive((U64Key::new(from), vec![]).joined_key()),
+    };
+    let max = to
+        .checked_add(1)
+        .map(|to| Bound::exclusive((U64Key::new(to), vec![]).joined_key()));
+
+    let streams = streams()
+        .idx
+        .created_at
+        .range(deps.storage, Some(min), max, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            Ok(stream_response(id_from_key(&pk)?, stream, block_time))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamsResponse { streams })
+}
+
+fn query_preview_top_up(
+    deps: Deps,
+    env: Env,
+    id: Uint128,
+    amount: Uint128,
+) -> StdResult<TopUpPreviewResponse> {
+    let mut stream = streams().load(deps.storage, id.u128().into())?;
+    top_up_stream(&mut stream, amount, env.block.time.seconds())
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(TopUpPreviewResponse {
+        amount: stream.amount,
+        rate_per_second: stream.rate_per_second,
+        rate_per_day: stream.rate_per_day,
+    })
+}
+
+fn query_withdraw_preview(deps: Deps, env: Env, id: Uint128) -> StdResult<WithdrawPreviewResponse> {
+    let stream = streams().load(deps.storage, id.u128().into())?;
+    let claimable = compute_claimable(&stream, env.block.time.seconds())?;
+    let will_close =
+        !claimable.is_zero() && stream.claimed_amount.checked_add(claimable)? == stream.amount;
+
+    // An encumbered stream repays its lender first, the rest goes to the
+    // recipient in a transfer of its own
+    let repaid = take_repayment(&mut stream.clone(), claimable)?
+        .map_or_else(Uint128::zero, |(_, repaid)| repaid);
+    let payout = claimable.checked_sub(repaid)?;
+    let transfer_count = [repaid, payout].iter().filter(|a| !a.is_zero()).count() as u32;
+    Ok(WithdrawPreviewResponse {
+        claimable: share_value(deps, &env, &stream, claimable)?,
+        will_close,
+        transfer_count,
+    })
+}
+
+fn query_overdue_claims(
+    deps: Deps,
+    env: Env,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<OverdueClaimsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let block_time = env.block.time.seconds();
+    let min = start_after.map(|id| Bound::exclusive_int(id.u128()));
+
+    let mut claims = vec![];
+    let mut last_scanned = None;
+    let scan = streams()
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(MAX_SCAN);
+    for item in scan {
+        let (pk, stream) = item?;
+        let id = id_from_key(&pk)?;
+        last_scanned = Some(id);
+
+        if is_linear(&stream) {
+            continue;
+        }
+        let claimable = compute_claimable(&stream, block_time)?;
+        if !claimable.is_zero() {
+            let claimable = share_value(deps, &env, &stream, claimable)?;
+            claims.push(OverdueClaim { id, claimable });
+            if claims.len() == limit {
+                break;
+            }
+        }
+    }
+    Ok(OverdueClaimsResponse {
+        claims,
+        last_scanned,
+    })
+}
+
+fn query_stuck_streams(
+    deps: Deps,
+    min_failures: u32,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<StuckStreamsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let min = start_after.map(|id| Bound::exclusive_int(id.u128()));
+
+    let mut stuck = vec![];
+    let mut last_scanned = None;
+    let scan = streams()
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(MAX_SCAN);
+    for item in scan {
+        let (pk, stream) = item?;
+        let id = id_from_key(&pk)?;
+        last_scanned = Some(id);
+
+        // Streams without failures never count as stuck, even for 0
+        if stream.failed_withdraw_count < min_failures.max(1) {
+            continue;
+        }
+        stuck.push(StuckStream {
+            id,
+            recipient: stream.recipient.into_string(),
+            failed_withdraw_count: stream.failed_withdraw_count,
+        });
+        if stuck.len() == limit {
+            break;
+        }
+    }
+    Ok(StuckStreamsResponse {
+        streams: stuck,
+        last_scanned,
+    })
+}
+
+fn query_recipient_tax_report(
+    deps: Deps,
+    recipient: String,
+    from: u64,
+    to: u64,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> StdResult<RecipientTaxReportResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    // Payouts are indexed by stream id then sequence, so skipping past the
+    // last sequence of `start_after` resumes at the next stream
+    let min = start_after.map(|id| {
+        Bound::exclusive((U128Key::from(id.u128()), U64Key::from(u64::MAX)).joined_key())
+    });
+
+    let mut total_received = Uint128::zero();
+    let mut entries: Vec<TaxReportEntry> = vec![];
+    let mut last_scanned = None;
+    let mut scanned = 0;
+    let scan = withdrawals()
+        .idx
+        .recipient
+        .prefix(recipient.as_bytes().to_vec())
+        .range(deps.storage, min, None, Order::Ascending);
+    for item in scan {
+        let (pk, record) = item?;
+        // The primary key is the length-prefixed stream id followed by the
+        // sequence
+        let id = pk
+            .get(2..18)
+            .ok_or_else(|| StdError::generic_err("invalid withdrawal key"))
+            .and_then(id_from_key)?;
+        if last_scanned != Some(id) {
+            if scanned == limit {
+                break;
+            }
+            scanned += 1;
+            last_scanned = Some(id);
+        }
+
+        if record.time < from || record.time > to {
+            continue;
+        }
+        total_received = total_received.checked_add(record.amount)?;
+        match entries.last_mut() {
+            Some(entry) if entry.id == id => {
+                entry.received = entry.received.checked_add(record.amount)?
+            }
+            _ => entries.push(TaxReportEntry {
+                id,
+                received: record.amount,
+            }),
+        }
+    }
+    Ok(RecipientTaxReportResponse {
+        total_received,
+        streams: entries,
+        last_scanned,
+    })
+}
+
+fn query_portfolio_for(
+    deps: Deps,
+    env: Env,
+    recipient: String,
+    limit: Option<u32>,
+) -> StdResult<PortfolioResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let recipient = canonical_addr(deps.api, recipient.as_str())?;
+    let block_time = env.block.time.seconds();
+
+    let streams = streams()
+        .idx
+        .recipient
+        .prefix(recipient.as_bytes().to_vec())
+        .range(deps.storage, None, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (pk, stream) = item?;
+            let claimable = compute_claimable(&stream, block_time)?;
+            let claimable = share_value(deps, &env, &stream, claimable)?;
+            // Streams closed by a consolidation can be left with nothing
+            let claimed_percent = if stream.amount.is_zero() {
+                100
+            } else {
+                stream
+                    .claimed_amount
+                    .multiply_ratio(100u128, stream.amount)
+                    .u128() as u64
+            };
+            Ok(PortfolioEntry {
+                stream: stream_response(id_from_key(&pk)?, stream, block_time),
+                claimable,
+                claimed_percent,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PortfolioResponse { streams })
+}
+
+fn query_stream_actions(
+    deps: Deps,
+    id: Uint128,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<StreamActionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let min = start_after.map(Bound::exclusive_int);
+
+    let actions = STREAM_ACTIONS
+        .prefix(id.u128().into())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (seq, action) = item?;
+            let seq: [u8; 8] = seq
+                .as_slice()
+                .try_into()
+                .map_err(|_| StdError::generic_err("invalid action key"))?;
+            Ok(StreamActionResponse {
+                seq: u64::from_be_bytes(seq),
+                kind: action.kind,
+                actor: action.actor.into_string(),
+                timestamp: action.timestamp,
+                note: action.note,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StreamActionsResponse { actions })
+}
+
+fn query_stats(deps: Deps) -> StdResult<StatsResponse> {
+    let stats = STATS.may_load(deps.storage)?.unwrap_or_default();
+    let total_locked = stats
+        .total_deposited
+        .checked_sub(stats.total_claimed)?
+        .checked_sub(stats.total_refunded)?;
+    Ok(StatsResponse {
+        total_deposited: stats.total_deposited,
+        total_claimed: stats.total_claimed,
+        total_refunded: stats.total_refunded,
+        total_locked,
+        active_streams: stats.active_streams,
+    })
+}
+
+fn query_withdrawal_history(
+    deps: Deps,
+    id: Uint128,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<WithdrawalHistoryResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let min = start_after.map(Bound::exclusive_int);
+
+    let withdrawals = withdrawals()
+        .prefix(id.u128().into())
+        .range(deps.storage, min, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (seq, record) = item?;
+            let seq: [u8; 8] = seq
+                .as_slice()
+                .try_into()
+                .map_err(|_| StdError::generic_err("invalid withdrawal key"))?;
+            Ok(WithdrawalRecordResponse {
+                seq: u64::from_be_bytes(seq),
+                time: record.time,
+                amount: record.amount,
+                recipient: record.recipient.into_string(),
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(WithdrawalHistoryResponse { withdrawals })
+}
+
+fn id_from_key(pk: &[u8]) -> StdResult<Uint128> {
+    let bytes: [u8; 16] = pk
+        .try_into()
+        .map_err(|_| StdError::generic_err("invalid stream key"))?;
+    Ok(Uint128::new(u128::from_be_bytes(bytes)))
+}
+
+fn stream_response(id: Uint128, stream: Stream, block_time: u64) -> StreamResponse {
+    let status = stream_status(&stream, block_time);
+    StreamResponse {
+        id,
+        owner: stream.owner.into_string(),
+        recipient: stream.recipient.into_string(),
+        amount: stream.amount,
+        claimed_amount: stream.claimed_amount,
+        rate_per_second: stream.rate_per_second,
+        rate_per_day: stream.rate_per_day,
+        start_time: stream.start_time,
+        end_time: stream.end_time,
+        installments: stream.installments,
+        checkpoint_time: stream.checkpoint_time,
+        checkpoint_vested: stream.checkpoint_vested,
+        fallback_recipient: stream.fallback_recipient.map(Addr::into_string),
+        recipient_controller: stream.recipient_controller.map(Addr::into_string),
+        created_at: stream.created_at,
+        frozen_at: stream.frozen_at,
+        beneficiary: stream.beneficiary,
+        condition: stream.condition,
+        cancel_at: stream.cancel_at,
+        cancelled: stream.cancelled,
+        rounding_unit: stream.rounding_unit,
+        cliff_time: stream.cliff_time,
+        encumbrance: stream.encumbrance,
+        cw20_addr: stream.cw20_addr.map(Addr::into_string),
+        denom: stream.denom,
+        allowed_destinations: stream
+            .allowed_destinations
+            .map(|destinations| destinations.into_iter().map(Addr::into_string).collect()),
+        failed_withdraw_count: stream.failed_withdraw_count,
+        last_claim_time: stream.last_claim_time,
+        unlock_schedule: stream.unlock_schedule,
+        paused_at: stream.paused_at,
+        paused_duration: stream.paused_duration,
+        cancel_window_end: stream.cancel_window_end,
+        status,
+    }
+}
+
+fn stream_status(stream: &Stream, block_time: u64) -> StreamStatus {
+    let block_time = schedule_time(stream, block_time);
+    if stream.cancelled {
+        StreamStatus::Cancelled
+    } else if block_time <= stream.start_time {
+        StreamStatus::NotStarted
+    } else if block_time < stream.end_time {
+        StreamStatus::Active
+    } else {
+        StreamStatus::Completed
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_api::mock_dependencies_case_insensitive;
+    use crate::mock_querier::mock_dependencies_with_wasm;
+    use crate::msg::TimeOracleSpec;
+    use crate::state::{LegacyConfig, LegacyStream};
     use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MOCK_CONTRACT_ADDR};
-    use cosmwasm_std::{Addr, CosmosMsg, WasmMsg};
+    use cosmwasm_std::{attr, coin, SubMsgExecutionResponse, SystemError, WasmMsg};
+    use schemars::schema::Schema;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    fn create_stream(
+        deps: DepsMut,
+        env: Env,
+        owner: &str,
+        recipient: &str,
+        amount: u128,
+        start_time: u64,
+        end_time: u64,
+    ) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from(owner),
+            amount: Uint128::new(amount),
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: String::from(recipient),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        execute(deps, env, mock_info(MOCK_CONTRACT_ADDR, &[]), msg)
+    }
+
+    fn get_stream(deps: Deps, id: u128) -> StreamResponse {
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(id),
+        };
+        from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn initialization() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+
+        let info = mock_info("creator", &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let msg = QueryMsg::GetConfig {};
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let config: Config = from_binary(&res).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                owner: Addr::unchecked("creator"),
+                cw20_addr: Addr::unchecked(MOCK_CONTRACT_ADDR),
+                event_namespace: None,
+                arbiter: None,
+                max_total_streams: None,
+                name_service: None,
+                auto_close_on_final_withdraw: false,
+                time_oracle: None,
+                fee_bps: 0,
+                fee_recipient: Addr::unchecked("creator"),
+                min_claim_interval_seconds: None,
+                emergency_recipient: None,
+                paused: false,
+                dust_policy: DustPolicy::DropToZero,
+            }
+        );
+    }
+
+    #[test]
+    fn instantiate_owner() {
+        let mut deps = mock_dependencies();
+        let info = mock_info("creator", &[]);
+
+        // An omitted owner falls back to the sender
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_binary(&res).unwrap();
+        assert_eq!(config.owner, "creator");
+
+        // A valid owner is used as given
+        let msg = InstantiateMsg {
+            owner: Some(String::from("Alice")),
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_binary(&res).unwrap();
+        assert_eq!(config.owner, "Alice");
+
+        // A malformed owner is rejected rather than replaced by the sender
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: Some(String::from("a")),
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let err = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        match err {
+            ContractError::InvalidOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn try_withdraw() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let mut info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let sender = Addr::unchecked("Alice").to_string();
+        let recipient = Addr::unchecked("Bob").to_string();
+        let amount = Uint128::new(200);
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.clone(),
+            amount,
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: recipient.clone(),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let stream: Stream = from_binary(&res).unwrap();
+
+        assert_eq!(
+            stream,
+            Stream {
+                owner: Addr::unchecked("Alice"),
+                recipient: Addr::unchecked("Bob"),
+                amount,
+                claimed_amount: Uint128::new(0),
+                start_time,
+                rate_per_second: Uint128::new(1),
+                rate_per_day: Uint128::new(86400),
+                end_time,
+                installments: None,
+                checkpoint_time: start_time,
+                checkpoint_vested: Uint128::zero(),
+                fallback_recipient: None,
+                recipient_controller: None,
+                created_at: mock_env().block.time.seconds(),
+                frozen_at: None,
+                beneficiary: None,
+                condition: None,
+                cancel_at: None,
+                cancelled: false,
+                rounding_unit: None,
+                cliff_time: None,
+                encumbrance: None,
+                cw20_addr: Some(Addr::unchecked(MOCK_CONTRACT_ADDR)),
+                denom: None,
+                allowed_destinations: None,
+                failed_withdraw_count: 0,
+                last_claim_time: None,
+                unlock_schedule: None,
+                paused_at: None,
+                paused_duration: 0,
+                cancel_window_end: None,
+            }
+        );
+
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        info.sender = Addr::unchecked("Bob");
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        let msg = res.messages[0].clone().msg;
+
+        assert_eq!(
+            msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Bob"),
+                    amount: Uint128::new(50)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let stream: Stream = from_binary(&res).unwrap();
+        assert_eq!(
+            stream,
+            Stream {
+                owner: Addr::unchecked("Alice"),
+                recipient: Addr::unchecked("Bob"),
+                amount,
+                claimed_amount: Uint128::new(50),
+                start_time,
+                rate_per_second: Uint128::new(1),
+                rate_per_day: Uint128::new(86400),
+                end_time,
+                installments: None,
+                checkpoint_time: start_time,
+                checkpoint_vested: Uint128::zero(),
+                fallback_recipient: None,
+                recipient_controller: None,
+                created_at: mock_env().block.time.seconds(),
+                frozen_at: None,
+                beneficiary: None,
+                condition: None,
+                cancel_at: None,
+                cancelled: false,
+                rounding_unit: None,
+                cliff_time: None,
+                encumbrance: None,
+                cw20_addr: Some(Addr::unchecked(MOCK_CONTRACT_ADDR)),
+                denom: None,
+                allowed_destinations: None,
+                failed_withdraw_count: 0,
+                last_claim_time: Some(env.block.time.seconds()),
+                unlock_schedule: None,
+                paused_at: None,
+                paused_duration: 0,
+                cancel_window_end: None,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_start_time() {
+        let mut deps = mock_dependencies();
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let mut info = mock_info("Alice", &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let sender = Addr::unchecked("Alice").to_string();
+        let recipient = Addr::unchecked("Bob").to_string();
+        let amount = Uint128::new(100);
+        let start_time = mock_env().block.time.plus_seconds(100).seconds();
+        let end_time = mock_env().block.time.plus_seconds(20).seconds();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.clone(),
+            amount,
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: recipient.clone(),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        info.sender = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+        match err {
+            ContractError::InvalidStartTime {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_cw20_addr() {
+        let mut deps = mock_dependencies();
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let mut info = mock_info("Alice", &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let sender = Addr::unchecked("Alice").to_string();
+        let recipient = Addr::unchecked("Bob").to_string();
+        let amount = Uint128::new(100);
+        let start_time = mock_env().block.time.plus_seconds(100).seconds();
+        let end_time = mock_env().block.time.plus_seconds(200).seconds();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.clone(),
+            amount,
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: recipient.clone(),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        info.sender = Addr::unchecked("wrongCw20");
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn uneven_deposit_amount() {
+        let mut deps = mock_dependencies();
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let mut info = mock_info("Alice", &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let sender = Addr::unchecked("Alice").to_string();
+        let recipient = Addr::unchecked("Bob").to_string();
+        let amount = Uint128::new(3);
+        let start_time = mock_env().block.time.plus_seconds(100).seconds();
+        let end_time = mock_env().block.time.plus_seconds(200).seconds();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.clone(),
+            amount,
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: recipient.clone(),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        info.sender = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        // Fewer tokens than seconds still vest pro rata
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::Claimable {
+                id: Uint128::new(1),
+            },
+        )
+        .unwrap();
+        let res: ClaimableResponse = from_binary(&res).unwrap();
+        assert_eq!(res.claimable, Uint128::new(1));
+    }
+
+    #[test]
+    fn installment_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let mut info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Bob"),
+                num_installments: 3,
+                interval_seconds: 100,
+                start_time,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let stream: StreamResponse = from_binary(&res).unwrap();
+        assert_eq!(stream.end_time, start_time + 300);
+        assert_eq!(
+            stream.installments,
+            Some(Installments {
+                count: 3,
+                interval_seconds: 100
+            })
+        );
+
+        let withdraw = |deps: DepsMut, env: Env| {
+            let msg = ExecuteMsg::Withdraw {
+                id: Uint128::new(1),
+                to: None,
+            };
+            execute(deps, env, mock_info("Bob", &[]), msg)
+        };
+
+        // Nothing is paid before the first interval boundary
+        env.block.time = env.block.time.plus_seconds(150);
+        let err = withdraw(deps.as_mut(), env.clone()).unwrap_err();
+        match err {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // First boundary
+        env.block.time = env.block.time.plus_seconds(50);
+        let res = withdraw(deps.as_mut(), env.clone()).unwrap();
+        assert_eq!(res.attributes[2].value, "333");
+
+        // Second boundary, part way into the third interval
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = withdraw(deps.as_mut(), env.clone()).unwrap();
+        assert_eq!(res.attributes[2].value, "333");
+
+        // Final installment absorbs the remainder
+        env.block.time = env.block.time.plus_seconds(50);
+        let res = withdraw(deps.as_mut(), env.clone()).unwrap();
+        assert_eq!(res.attributes[2].value, "334");
+
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let stream: StreamResponse = from_binary(&res).unwrap();
+        assert_eq!(stream.claimed_amount, Uint128::new(1000));
+
+        // A zero installment count is rejected
+        info.sender = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Bob"),
+                num_installments: 0,
+                interval_seconds: 100,
+                start_time,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        match err {
+            ContractError::InvalidInstallments {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn batch_top_up() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        for _ in 0..3 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                300,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+        }
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Carol",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let ids = vec![Uint128::new(1), Uint128::new(2), Uint128::new(3)];
+        let top_up = |ids: Vec<Uint128>, weights: Option<Vec<u16>>, amount: u128| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::BatchTopUp {
+                    ids,
+                    weights,
+                    note: None,
+                })
+                .unwrap(),
+            })
+        };
+
+        // Weighted split before the streams start
+        let msg = top_up(ids.clone(), Some(vec![5000, 3000, 2000]), 1000);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(800));
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(600));
+        assert_eq!(get_stream(deps.as_ref(), 3).amount, Uint128::new(500));
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).rate_per_second,
+            Uint128::new(2)
+        );
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).rate_per_day,
+            Uint128::new(230400)
+        );
+
+        // Equal split, the rounding remainder goes to the first stream
+        let msg = top_up(ids.clone(), None, 100);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(834));
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(633));
+        assert_eq!(get_stream(deps.as_ref(), 3).amount, Uint128::new(533));
+
+        // Mid-stream the new funds only vest over the remaining time
+        env.block.time = env.block.time.plus_seconds(250);
+        let msg = top_up(vec![Uint128::new(2)], None, 150);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.amount, Uint128::new(783));
+        assert_eq!(stream.checkpoint_vested, Uint128::new(316));
+        assert_eq!(stream.checkpoint_time, env.block.time.seconds());
+        // 467 remaining over the last 150 seconds
+        assert_eq!(stream.rate_per_second, Uint128::new(3));
+        assert_eq!(stream.rate_per_day, Uint128::new(268_992));
+
+        env.block.time = env.block.time.plus_seconds(75);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(2),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        assert_eq!(res.attributes[2].value, "549");
+
+        // Weights must sum to 10000 and match the ids
+        let msg = top_up(ids.clone(), Some(vec![5000, 5000]), 100);
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap_err();
+        match err {
+            ContractError::InvalidWeights {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = top_up(ids, Some(vec![5000, 3000, 1000]), 100);
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap_err();
+        match err {
+            ContractError::InvalidWeights {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Batches over the cap are rejected as a whole
+        let ids = (1..=MAX_BATCH_SIZE as u128 + 1).map(Uint128::new).collect();
+        let msg = top_up(ids, None, 100);
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap_err();
+        match err {
+            ContractError::BatchTooLarge {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Nothing may be topped up by zero, either as a whole or by a share
+        // that rounds or is weighted down to zero
+        let two = vec![Uint128::new(1), Uint128::new(2)];
+        for msg in [
+            top_up(two.clone(), None, 0),
+            top_up(two.clone(), None, 1),
+            top_up(two, Some(vec![10000, 0]), 100),
+        ] {
+            let err = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap_err();
+            match err {
+                ContractError::ZeroAmount {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Every stream must belong to the funder
+        let msg = top_up(vec![Uint128::new(1), Uint128::new(4)], None, 100);
+        let err = execute(deps.as_mut(), env, info, msg).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn split_amount_sums_to_total() {
+        let split = |total: u128, weights: &[u64]| -> Vec<u128> {
+            split_amount(Uint128::new(total), weights)
+                .unwrap()
+                .iter()
+                .map(Uint128::u128)
+                .collect()
+        };
+
+        // 100 * 1/3 leaves one unit over, which goes to the earliest part
+        assert_eq!(split(100, &[1, 1, 1]), vec![34, 33, 33]);
+        // Remainders of 0.7, 0.1 and 0.2: the unit left over goes to the first part
+        assert_eq!(split(10, &[3700, 3100, 3200]), vec![4, 3, 3]);
+        assert_eq!(split(7, &[3333, 3333, 3334]), vec![2, 2, 3]);
+        assert_eq!(split(0, &[5000, 5000]), vec![0, 0]);
+
+        for (total, weights) in [
+            (1_000_003u128, vec![1234u64, 5678, 3088]),
+            (u128::MAX, vec![1, 2, 3, 4, 5, 6, 7]),
+            (99, vec![10000]),
+        ] {
+            assert_eq!(split(total, &weights).iter().sum::<u128>(), total);
+        }
+    }
+
+    #[test]
+    fn split_amount_overflow() {
+        // Weights summing past u64::MAX are an error rather than wrapping
+        let err = split_amount(Uint128::new(100), &[u64::MAX, 1]).unwrap_err();
+        assert_eq!(err.operation, OverflowOperation::Add);
+        assert_eq!(
+            ContractError::from(err).to_string(),
+            "Cannot Add with 18446744073709551615 and 1"
+        );
+    }
+
+    #[test]
+    fn token_available() {
+        let mut deps = mock_dependencies_with_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "token" => {
+                let info = TokenInfoResponse {
+                    name: String::from("Token"),
+                    symbol: String::from("TKN"),
+                    decimals: 6,
+                    total_supply: Uint128::new(1000),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&info).unwrap()))
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("paused"))),
+        });
+        let info = mock_info("creator", &[]);
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from("token"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TokenAvailable {}).unwrap();
+        let res: TokenAvailableResponse = from_binary(&res).unwrap();
+        assert!(res.available);
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from("paused_token"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TokenAvailable {}).unwrap();
+        let res: TokenAvailableResponse = from_binary(&res).unwrap();
+        assert!(!res.available);
+
+        // Without any wasm handler the token contract does not exist at all
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from("token"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TokenAvailable {}).unwrap();
+        let res: TokenAvailableResponse = from_binary(&res).unwrap();
+        assert!(!res.available);
+    }
+
+    #[test]
+    fn zero_length_window() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Start and end at the current block time are rejected, not divided by
+        let env = mock_env();
+        let now = env.block.time.seconds();
+        let err = create_stream(deps.as_mut(), env, "Alice", "Bob", 100, now, now).unwrap_err();
+        match err {
+            ContractError::InvalidDuration {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // A degenerate schedule is fully vested from its end time and nothing
+        // before it
+        let stream = Stream {
+            owner: Addr::unchecked("Alice"),
+            recipient: Addr::unchecked("Bob"),
+            amount: Uint128::new(100),
+            claimed_amount: Uint128::zero(),
+            start_time: now,
+            end_time: now,
+            rate_per_second: Uint128::zero(),
+            rate_per_day: Uint128::zero(),
+            installments: None,
+            checkpoint_time: now,
+            checkpoint_vested: Uint128::zero(),
+            fallback_recipient: None,
+            recipient_controller: None,
+            created_at: now,
+            frozen_at: None,
+            beneficiary: None,
+            condition: None,
+            cancel_at: None,
+            cancelled: false,
+            rounding_unit: None,
+            cliff_time: None,
+            encumbrance: None,
+            cw20_addr: None,
+            denom: None,
+            allowed_destinations: None,
+            failed_withdraw_count: 0,
+            last_claim_time: None,
+            unlock_schedule: None,
+            paused_at: None,
+            paused_duration: 0,
+            cancel_window_end: None,
+        };
+        assert_eq!(compute_vested(&stream, now - 1).unwrap(), Uint128::zero());
+        assert_eq!(compute_vested(&stream, now).unwrap(), Uint128::new(100));
+    }
+
+    #[test]
+    fn vested_equals_amount_after_end() {
+        // Deterministic pseudo-random sampling of amount/duration pairs
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for _ in 0..500 {
+            let start_time = next() % 1_000_000;
+            let duration = 1 + next() % 100_000;
+            let end_time = start_time + duration;
+            let amount = Uint128::from(1 + next() as u128 % 1_000_000_000_000);
+            let count = 1 + (next() % 12) as u32;
+            let checkpoint_time = start_time + next() % duration;
+
+            let linear = Stream {
+                owner: Addr::unchecked("Alice"),
+                recipient: Addr::unchecked("Bob"),
+                amount,
+                claimed_amount: Uint128::zero(),
+                start_time,
+                end_time,
+                rate_per_second: amount.multiply_ratio(1u128, duration),
+                rate_per_day: amount.multiply_ratio(SECONDS_PER_DAY, duration),
+                installments: None,
+                checkpoint_time,
+                checkpoint_vested: amount.multiply_ratio(checkpoint_time - start_time, duration),
+                fallback_recipient: None,
+                recipient_controller: None,
+                created_at: mock_env().block.time.seconds(),
+                frozen_at: None,
+                beneficiary: None,
+                condition: None,
+                cancel_at: None,
+                cancelled: false,
+                rounding_unit: None,
+                cliff_time: None,
+                encumbrance: None,
+                cw20_addr: None,
+                denom: None,
+                allowed_destinations: None,
+                failed_withdraw_count: 0,
+                last_claim_time: None,
+                unlock_schedule: None,
+                paused_at: None,
+                paused_duration: 0,
+                cancel_window_end: None,
+            };
+            let installments = Stream {
+                end_time: start_time + duration * u64::from(count),
+                installments: Some(Installments {
+                    count,
+                    interval_seconds: duration,
+                }),
+                checkpoint_time: start_time,
+                checkpoint_vested: Uint128::zero(),
+                ..linear.clone()
+            };
+
+            for stream in [linear, installments].iter() {
+                let before_end = compute_vested(stream, stream.end_time - 1).unwrap();
+                assert!(before_end <= stream.amount);
+                assert_eq!(
+                    compute_vested(stream, stream.end_time).unwrap(),
+                    stream.amount
+                );
+                let past_end = stream.end_time + next() % 1_000_000;
+                assert_eq!(compute_vested(stream, past_end).unwrap(), stream.amount);
+            }
+        }
+    }
+
+    #[test]
+    fn event_namespace() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: Some(String::from("payroll")),
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info("creator", &[]);
+        let res = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(res.attributes[0].key, "payroll.method");
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        let res = create_stream(
+            deps.as_mut(),
+            env,
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let keys: Vec<&str> = res.attributes.iter().map(|a| a.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "payroll.method",
+                "payroll.stream_id",
+                "payroll.owner",
+                "payroll.recipient",
+                "payroll.amount",
+                "payroll.fee",
+                "payroll.start_time",
+                "payroll.end_time"
+            ]
+        );
+
+        // Attribute keys are left untouched without a namespace
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info("creator", &[]);
+        let res = instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        assert_eq!(res.attributes[0].key, "method");
+
+        let env = mock_env();
+        let res = create_stream(
+            deps.as_mut(),
+            env,
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        assert_eq!(res.attributes[0].key, "method");
+
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: Some(String::new()),
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let err = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        match err {
+            ContractError::InvalidEventNamespace {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn recipient_kind() {
+        let mut deps = mock_dependencies_with_wasm(|query| match query {
+            WasmQuery::ContractInfo { contract_addr } if contract_addr == "vault" => {
+                let info = br#"{"code_id":1,"creator":"Alice","admin":null,"pinned":false}"#;
+                SystemResult::Ok(ContractResult::Ok(Binary::from(&info[..])))
+            }
+            WasmQuery::ContractInfo { contract_addr } => {
+                SystemResult::Err(SystemError::NoSuchContract {
+                    addr: contract_addr.clone(),
+                })
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("unsupported"))),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let start_time = mock_env().block.time.plus_seconds(100).seconds();
+        let end_time = mock_env().block.time.plus_seconds(300).seconds();
+        let create = |recipient: &str, recipient_kind: Option<RecipientKind>| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(200),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from(recipient),
+                    start_time,
+                    end_time,
+                    recipient_kind,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+
+        let cases = vec![
+            ("vault", None, true),
+            ("Bob", None, true),
+            ("vault", Some(RecipientKind::Any), true),
+            ("Bob", Some(RecipientKind::Any), true),
+            ("vault", Some(RecipientKind::Contract), true),
+            ("Bob", Some(RecipientKind::Contract), false),
+            ("vault", Some(RecipientKind::Wallet), false),
+            ("Bob", Some(RecipientKind::Wallet), true),
+        ];
+        for (recipient, kind, allowed) in cases {
+            let msg = create(recipient, kind);
+            let res = execute(deps.as_mut(), mock_env(), info.clone(), msg);
+            match res {
+                Ok(_) if allowed => {}
+                Err(ContractError::RecipientKindMismatch {}) if !allowed => {}
+                res => panic!("unexpected result for {}: {:?}", recipient, res),
+            }
+        }
+    }
+
+    #[test]
+    fn partial_cancel() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        // Half way through 150 is vested and 150 can still be refunded
+        env.block.time = env.block.time.plus_seconds(250);
+        let cancel = |refund_amount: u128| ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(refund_amount),
+            note: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            cancel(100),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            cancel(151),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidRefundAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            cancel(100),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Alice"),
+                    amount: Uint128::new(100)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, Uint128::new(200));
+        assert_eq!(stream.end_time, end_time);
+
+        // The remaining 50 keeps vesting until the original end time
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = env.block.time.plus_seconds(75);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, "175");
+
+        env.block.time = env.block.time.plus_seconds(75);
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.attributes[2].value, "25");
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).claimed_amount,
+            Uint128::new(200)
+        );
+    }
+
+    #[test]
+    fn fallback_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        // Only the recipient may set a fallback
+        let msg = ExecuteMsg::SetFallbackRecipient {
+            id: Uint128::new(1),
+            fallback_recipient: Some(String::from("Carol")),
+            note: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            msg.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).fallback_recipient,
+            Some(String::from("Carol"))
+        );
+
+        // The transfer to Bob replies
+        env.block.time = env.block.time.plus_seconds(150);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        assert_eq!(res.messages[0].id, WITHDRAW_REPLY_ID);
+        assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+
+        // The token contract rejects Bob, so Carol receives the tokens instead
+        let msg = Reply {
+            id: WITHDRAW_REPLY_ID,
+            result: ContractResult::Err(String::from("recipient is blocked")),
+        };
+        let res = reply(deps.as_mut(), env, msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Carol"),
+                    amount: Uint128::new(50)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).claimed_amount,
+            Uint128::new(50)
+        );
+        assert!(PENDING_FALLBACK.may_load(&deps.storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn streams_created_between() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Two streams at each of three creation times
+        let mut env = mock_env();
+        let mut created = vec![];
+        for _ in 0..3 {
+            let start_time = env.block.time.plus_seconds(100).seconds();
+            let end_time = env.block.time.plus_seconds(300).seconds();
+            for _ in 0..2 {
+                create_stream(
+                    deps.as_mut(),
+                    env.clone(),
+                    "Alice",
+                    "Bob",
+                    200,
+                    start_time,
+                    end_time,
+                )
+                .unwrap();
+            }
+            created.push(env.block.time.seconds());
+            env.block.time = env.block.time.plus_seconds(1000);
+        }
+
+        let created_between = |deps: Deps, from, to, start_after: Option<u128>, limit| {
+            let msg = QueryMsg::StreamsCreatedBetween {
+                from,
+                to,
+                start_after: start_after.map(Uint128::new),
+                limit,
+            };
+            let res: StreamsResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.streams.iter().map(|s| s.id.u128()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            created_between(deps.as_ref(), created[0], created[2], None, None),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(
+            created_between(deps.as_ref(), created[1], created[1], None, None),
+            vec![3, 4]
+        );
+        assert_eq!(
+            created_between(deps.as_ref(), created[0] + 1, created[2] - 1, None, None),
+            vec![3, 4]
+        );
+        assert_eq!(
+            created_between(deps.as_ref(), created[2] + 1, created[2] + 1000, None, None),
+            Vec::<u128>::new()
+        );
+
+        // Paging through the range
+        assert_eq!(
+            created_between(deps.as_ref(), created[0], created[2], None, Some(3)),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            created_between(deps.as_ref(), created[0], created[2], Some(3), Some(3)),
+            vec![4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn global_delegate() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Carol",
+            "Bob",
+            400,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        env.block.time = env.block.time.plus_seconds(150);
+
+        let withdraw = |id: u128| ExecuteMsg::Withdraw {
+            id: Uint128::new(id),
+            to: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Dave", &[]),
+            withdraw(1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let msg = ExecuteMsg::SetGlobalDelegate {
+            delegate: Some(String::from("Dave")),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+
+        // Dave can withdraw both of Bob's streams, but the tokens go to Bob
+        for (id, amount) in [(1, 50u128), (2, 100)] {
+            let res = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Dave", &[]),
+                withdraw(id),
+            )
+            .unwrap();
+            assert_eq!(
+                res.messages[0].msg,
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: String::from("Bob"),
+                        amount: Uint128::new(amount)
+                    })
+                    .unwrap(),
+                    funds: vec![]
+                })
+            );
+        }
+
+        // Clearing the delegate revokes access
+        let msg = ExecuteMsg::SetGlobalDelegate { delegate: None };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(10);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Dave", &[]),
+            withdraw(1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw(1)).unwrap();
+    }
+
+    #[test]
+    fn is_withdraw_authorized() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let msg = ExecuteMsg::SetGlobalDelegate {
+            delegate: Some(String::from("Dave")),
+        };
+        execute(deps.as_mut(), env, mock_info("Bob", &[]), msg).unwrap();
+
+        let authorized = |deps: Deps, address: &str| {
+            let msg = QueryMsg::IsWithdrawAuthorized {
+                id: Uint128::new(1),
+                address: String::from(address),
+            };
+            let res: IsWithdrawAuthorizedResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.authorized
+        };
+
+        // Recipient
+        assert!(authorized(deps.as_ref(), "Bob"));
+        // Global delegate of the recipient
+        assert!(authorized(deps.as_ref(), "Dave"));
+        // The stream owner and anyone else are not
+        assert!(!authorized(deps.as_ref(), "Alice"));
+        assert!(!authorized(deps.as_ref(), "Eve"));
+    }
+
+    #[test]
+    fn recipient_controller() {
+        let current = Rc::new(RefCell::new(String::from("Bob")));
+        let designated = current.clone();
+        let mut deps = mock_dependencies_with_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "controller" => {
+                let msg: ControllerQueryMsg = from_binary(msg).unwrap();
+                match msg {
+                    ControllerQueryMsg::CurrentRecipient { stream_id } => {
+                        assert_eq!(stream_id, Uint128::new(1));
+                    }
+                }
+                let res = CurrentRecipientResponse {
+                    recipient: designated.borrow().clone(),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&res).unwrap()))
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("unsupported"))),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: String::from("Treasury"),
+                start_time,
+                end_time,
+                recipient_kind: None,
+                recipient_controller: Some(String::from("controller")),
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+
+        // The controller currently designates Bob
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Bob", 50));
+
+        // Later it designates Carol, Bob is no longer authorized
+        *current.borrow_mut() = String::from("Carol");
+        env.block.time = env.block.time.plus_seconds(50);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Carol", 50));
+
+        // The stored recipient can still trigger it, paying the designated address
+        env.block.time = env.block.time.plus_seconds(50);
+        let res = execute(deps.as_mut(), env, mock_info("Treasury", &[]), withdraw).unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Carol", 50));
+    }
+
+    #[test]
+    fn crystallize_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let crystallize = ExecuteMsg::CrystallizeStream {
+            id: Uint128::new(1),
+            note: None,
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+
+        // Bob claims part of the stream, then Alice crystallizes it
+        env.block.time = env.block.time.plus_seconds(150);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        env.block.time = env.block.time.plus_seconds(50);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            crystallize.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            crystallize.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, "100");
+        assert_eq!(res.attributes[3].value, "50");
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, Uint128::new(300));
+        assert_eq!(stream.checkpoint_time, env.block.time.seconds());
+        assert_eq!(stream.checkpoint_vested, Uint128::new(100));
+
+        // The lump plus the rest of the accrual adds up to the original total
+        let mut claimed = stream.claimed_amount.u128();
+        for seconds in [0, 100, 100] {
+            env.block.time = env.block.time.plus_seconds(seconds);
+            let res = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw.clone(),
+            )
+            .unwrap();
+            claimed += res.attributes[2].value.parse::<u128>().unwrap();
+        }
+        assert_eq!(claimed, 300);
+
+        let err = execute(deps.as_mut(), env, mock_info("Alice", &[]), crystallize).unwrap_err();
+        match err {
+            ContractError::StreamEnded {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn stream_actions() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let created_at = env.block.time.seconds();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        env.block.time = env.block.time.plus_seconds(200);
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: vec![Uint128::new(1)],
+                weights: None,
+                note: Some(String::from("Q3 bonus")),
+            })
+            .unwrap(),
+        });
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(MOCK_CONTRACT_ADDR, &[]),
+            top_up,
+        )
+        .unwrap();
+
+        // Notes are bounded in length
+        let cancel = |note: String| ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(100),
+            note: Some(note),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            cancel("x".repeat(MAX_NOTE_LENGTH + 1)),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidNote {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            cancel(String::from("scope reduced")),
+        )
+        .unwrap();
+
+        env.block.time = env.block.time.plus_seconds(50);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+
+        let msg = QueryMsg::StreamActions {
+            id: Uint128::new(1),
+            start_after: None,
+            limit: None,
+        };
+        let res: StreamActionsResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let action = |seq, kind, actor: &str, timestamp, note: Option<&str>| StreamActionResponse {
+            seq,
+            kind,
+            actor: String::from(actor),
+            timestamp,
+            note: note.map(String::from),
+        };
+        assert_eq!(
+            res.actions,
+            vec![
+                action(1, StreamActionKind::Create, "Alice", created_at, None),
+                action(
+                    2,
+                    StreamActionKind::TopUp,
+                    "Alice",
+                    created_at + 200,
+                    Some("Q3 bonus")
+                ),
+                action(
+                    3,
+                    StreamActionKind::PartialCancel,
+                    "Alice",
+                    created_at + 200,
+                    Some("scope reduced")
+                ),
+                action(4, StreamActionKind::Withdraw, "Bob", created_at + 250, None),
+            ]
+        );
+
+        // Paginates by sequence number
+        let msg = QueryMsg::StreamActions {
+            id: Uint128::new(1),
+            start_after: Some(2),
+            limit: Some(1),
+        };
+        let res: StreamActionsResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.actions.len(), 1);
+        assert_eq!(res.actions[0].seq, 3);
+        assert_eq!(res.actions[0].kind, StreamActionKind::PartialCancel);
+    }
+
+    #[test]
+    fn stream_ids_start_at_one() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(200).seconds();
+        let res = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        assert_eq!(res.attributes[1].key, "stream_id");
+        assert_eq!(res.attributes[1].value, "1");
+        assert_eq!(get_stream(deps.as_ref(), 1).id, Uint128::new(1));
+
+        // Id 0 is never assigned, so it is cleanly not found
+        let msg = QueryMsg::GetStream {
+            id: Uint128::zero(),
+        };
+        match query(deps.as_ref(), mock_env(), msg).unwrap_err() {
+            StdError::NotFound { .. } => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::zero(),
+            to: None,
+        };
+        match execute(deps.as_mut(), env, mock_info("Bob", &[]), msg).unwrap_err() {
+            ContractError::Std(StdError::NotFound { .. }) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn portfolio_for() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for (owner, recipient, amount, duration) in [
+            ("Alice", "Bob", 300, 300),
+            ("Alice", "Carol", 100, 100),
+            ("Dave", "Bob", 200, 200),
+        ] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                owner,
+                recipient,
+                amount,
+                start_time,
+                start_time + duration,
+            )
+            .unwrap();
+        }
+
+        env.block.time = env.block.time.plus_seconds(200);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(50);
+        let msg = QueryMsg::PortfolioFor {
+            recipient: String::from("Bob"),
+            limit: None,
+        };
+        let res: PortfolioResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        let entries: Vec<(Uint128, Uint128, u64)> = res
+            .streams
+            .iter()
+            .map(|e| (e.stream.id, e.claimable, e.claimed_percent))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Uint128::new(1), Uint128::new(50), 33),
+                (Uint128::new(3), Uint128::new(150), 0),
+            ]
+        );
+        let msg = QueryMsg::GetStream {
+            id: Uint128::new(3),
+        };
+        let stream: StreamResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.streams[1].stream, stream);
+
+        let msg = QueryMsg::PortfolioFor {
+            recipient: String::from("Bob"),
+            limit: Some(1),
+        };
+        let res: PortfolioResponse = from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!(res.streams.len(), 1);
+        assert_eq!(res.streams[0].stream.id, Uint128::new(1));
+    }
+
+    #[test]
+    fn reentrancy_lock() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let msg = ExecuteMsg::SetFallbackRecipient {
+            id: Uint128::new(1),
+            fallback_recipient: Some(String::from("Carol")),
+            note: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+
+        // The withdrawal awaits its reply, so a call made from within the
+        // transfer is rejected
+        env.block.time = env.block.time.plus_seconds(150);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(MOCK_CONTRACT_ADDR, &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Reentrancy {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The reply releases the lock
+        let msg = Reply {
+            id: WITHDRAW_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), env.clone(), msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(10);
+        execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+    }
+
+    #[test]
+    fn convert() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let convert = |steps| ExecuteMsg::Convert {
+            id: Uint128::new(1),
+            steps,
+        };
+        env.block.time = env.block.time.plus_seconds(200);
+
+        // Only the parties of the stream can agree on a conversion
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            convert(2),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Differing terms only replace the proposal
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            convert(4),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[3].value, "proposed");
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            convert(2),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[3].value, "proposed");
+        assert_eq!(get_stream(deps.as_ref(), 1).installments, None);
+
+        // Alice agrees to Bob's terms: the 100 vested so far is kept and the
+        // remaining 200 is paid in two steps of 100 seconds
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            convert(2),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[3].value, "converted");
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.checkpoint_vested, Uint128::new(100));
+        assert_eq!(
+            stream.installments,
+            Some(Installments {
+                count: 2,
+                interval_seconds: 100,
+            })
+        );
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let mut claimed = vec![];
+        for seconds in [50, 50, 50, 50] {
+            env.block.time = env.block.time.plus_seconds(seconds);
+            let res = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw.clone(),
+            );
+            claimed.push(match res {
+                Ok(res) => res.attributes[2].value.clone(),
+                Err(ContractError::NoFundsToClaim {}) => String::from("0"),
+                Err(e) => panic!("unexpected error: {}", e),
+            });
+        }
+        assert_eq!(claimed, vec!["100", "100", "0", "100"]);
+        env.block.time = env.block.time.plus_seconds(1);
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::StreamFullyClaimed {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Installment streams can't be converted again
+        let err = execute(deps.as_mut(), env, mock_info("Alice", &[]), convert(2)).unwrap_err();
+        match err {
+            ContractError::NotLinearStream {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn global_cancellers() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: Some(String::from("Admin")),
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![String::from("Compliance")],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let cancel = ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(100),
+            note: None,
+        };
+
+        // A global canceller can cancel a stream they don't own, refunding its owner
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Compliance", &[]),
+            cancel.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Alice"),
+                    amount: Uint128::new(100)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Mallory", &[]),
+            cancel.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Only the contract owner manages the set
+        let remove = ExecuteMsg::RemoveGlobalCanceller {
+            address: String::from("Compliance"),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Compliance", &[]),
+            remove.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), mock_info("Admin", &[]), remove).unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Compliance", &[]),
+            cancel.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let add = ExecuteMsg::AddGlobalCanceller {
+            address: String::from("Mallory"),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Admin", &[]), add).unwrap();
+        execute(deps.as_mut(), env, mock_info("Mallory", &[]), cancel).unwrap();
+    }
+
+    #[test]
+    fn withdraw_preview() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let preview = |deps: Deps, env: Env| -> WithdrawPreviewResponse {
+            let msg = QueryMsg::WithdrawPreview {
+                id: Uint128::new(1),
+            };
+            from_binary(&query(deps, env, msg).unwrap()).unwrap()
+        };
+        assert_eq!(
+            preview(deps.as_ref(), env.clone()),
+            WithdrawPreviewResponse {
+                claimable: Uint128::zero(),
+                will_close: false,
+                transfer_count: 0,
+            }
+        );
+
+        env.block.time = env.block.time.plus_seconds(150);
+        assert_eq!(
+            preview(deps.as_ref(), env.clone()),
+            WithdrawPreviewResponse {
+                claimable: Uint128::new(50),
+                will_close: false,
+                transfer_count: 1,
+            }
+        );
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        // The final withdrawal drains the stream, repaying a lien on it first
+        let mut stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        stream.encumbrance = Some(Encumbrance {
+            lender: Addr::unchecked("Lender"),
+            outstanding: Uint128::new(100),
+        });
+        streams()
+            .save(&mut deps.storage, 1u128.into(), &stream)
+            .unwrap();
+        env.block.time = env.block.time.plus_seconds(200);
+        let res = preview(deps.as_ref(), env.clone());
+        assert_eq!(
+            res,
+            WithdrawPreviewResponse {
+                claimable: Uint128::new(150),
+                will_close: true,
+                transfer_count: 2,
+            }
+        );
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.attributes[2].value, "150");
+        assert_eq!(res.messages.len(), 2);
+    }
+
+    #[test]
+    fn freeze_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: Some(String::from("Arbiter")),
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let freeze = ExecuteMsg::FreezeStream {
+            id: Uint128::new(1),
+        };
+        let unfreeze = ExecuteMsg::UnfreezeStream {
+            id: Uint128::new(1),
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+
+        env.block.time = env.block.time.plus_seconds(200);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Mallory", &[]),
+            freeze.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Arbiter", &[]),
+            freeze.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, "100");
+
+        // Withdrawals are capped at the frozen amount
+        env.block.time = env.block.time.plus_seconds(100);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, "100");
+        env.block.time = env.block.time.plus_seconds(50);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The schedule can't be changed while frozen
+        let cancel = ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(100),
+            note: None,
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap_err();
+        match err {
+            ContractError::StreamFrozen {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Unfreezing after 150 seconds shifts the end time by as much
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            unfreeze.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, (end_time + 150).to_string());
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            unfreeze,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::StreamNotFrozen {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Accrual resumes from the frozen amount
+        env.block.time = env.block.time.plus_seconds(50);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2].value, "50");
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.attributes[2].value, "150");
+
+        // Only time frozen after the start holds the schedule back
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let freeze = ExecuteMsg::FreezeStream {
+            id: Uint128::new(2),
+        };
+        let unfreeze = ExecuteMsg::UnfreezeStream {
+            id: Uint128::new(2),
+        };
+        let alice = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), alice.clone(), freeze.clone()).unwrap();
+        env.block.time = env.block.time.plus_seconds(50);
+        execute(deps.as_mut(), env.clone(), alice.clone(), unfreeze.clone()).unwrap();
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.start_time, start_time);
+        assert_eq!(stream.end_time, end_time);
+
+        execute(deps.as_mut(), env.clone(), alice.clone(), freeze).unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        execute(deps.as_mut(), env, alice, unfreeze).unwrap();
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.start_time, start_time + 50);
+        assert_eq!(stream.end_time, end_time + 50);
+    }
+
+    #[test]
+    fn beneficiary() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        for id in 1..=2u128 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                200,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+            let msg = ExecuteMsg::SetBeneficiary {
+                id: Uint128::new(id),
+                beneficiary: Some(String::from("Dave")),
+                inactivity_seconds: 1000,
+            };
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        }
+
+        let claim = |id| ExecuteMsg::ClaimAsBeneficiary {
+            id: Uint128::new(id),
+        };
+
+        // Bob withdraws stream 1 in time, leaving nothing to inherit
+        env.block.time = env.block.time.plus_seconds(500);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+
+        // Stream 2 can't be claimed before the inactivity window has passed
+        let err =
+            execute(deps.as_mut(), env.clone(), mock_info("Dave", &[]), claim(2)).unwrap_err();
+        match err {
+            ContractError::RecipientStillActive {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        env.block.time = env.block.time.plus_seconds(800);
+        let err =
+            execute(deps.as_mut(), env.clone(), mock_info("Dave", &[]), claim(1)).unwrap_err();
+        match err {
+            ContractError::StreamFullyClaimed {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Mallory", &[]),
+            claim(2),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // A compliance hold on Bob holds back what Dave would inherit too
+        let freeze = ExecuteMsg::FreezeRecipient {
+            recipient: String::from("Bob"),
+        };
+        let owner = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), owner.clone(), freeze).unwrap();
+        let err =
+            execute(deps.as_mut(), env.clone(), mock_info("Dave", &[]), claim(2)).unwrap_err();
+        match err {
+            ContractError::RecipientFrozen {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let unfreeze = ExecuteMsg::UnfreezeRecipient {
+            recipient: String::from("Bob"),
+        };
+        execute(deps.as_mut(), env.clone(), owner, unfreeze).unwrap();
+
+        // Bob has been inactive for 1000 seconds past the end of stream 2.
+        // Dave inherits what is left once a lien on it is repaid.
+        let mut stream = streams().load(&deps.storage, 2u128.into()).unwrap();
+        stream.encumbrance = Some(Encumbrance {
+            lender: Addr::unchecked("Lender"),
+            outstanding: Uint128::new(50),
+        });
+        streams()
+            .save(&mut deps.storage, 2u128.into(), &stream)
+            .unwrap();
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Dave", &[]), claim(2)).unwrap();
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+        let msgs: Vec<CosmosMsg> = res.messages.into_iter().map(|m| m.msg).collect();
+        assert_eq!(msgs, vec![transfer("Lender", 50), transfer("Dave", 150)]);
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.encumbrance, None);
+        assert_eq!(stream.last_claim_time, Some(env.block.time.seconds()));
+
+        // The claim is part of the stream's payout history
+        let msg = QueryMsg::WithdrawalHistory {
+            id: Uint128::new(2),
+            start_after: None,
+            limit: None,
+        };
+        let res: WithdrawalHistoryResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(
+            res.withdrawals,
+            vec![WithdrawalRecordResponse {
+                seq: 1,
+                time: env.block.time.seconds(),
+                amount: Uint128::new(200),
+                recipient: String::from("Dave"),
+            }]
+        );
+        assert_eq!(
+            get_stream(deps.as_ref(), 2).claimed_amount,
+            Uint128::new(200)
+        );
+    }
+
+    #[test]
+    fn get_stream_human() {
+        for (seconds, formatted) in [
+            (0, "1970-01-01T00:00:00Z"),
+            (951_782_400, "2000-02-29T00:00:00Z"),
+            (4_102_444_799, "2099-12-31T23:59:59Z"),
+        ] {
+            assert_eq!(format_rfc3339(seconds), formatted);
+        }
+
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env,
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let msg = QueryMsg::GetStreamHuman {
+            id: Uint128::new(1),
+        };
+        let res: StreamHumanResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.stream, get_stream(deps.as_ref(), 1));
+        assert_eq!(res.created_at, "2019-10-23T02:23:39Z");
+        assert_eq!(res.start_time, "2019-10-23T02:25:19Z");
+        assert_eq!(res.end_time, "2019-10-23T02:28:39Z");
+    }
+
+    #[test]
+    fn max_total_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: Some(2),
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                200,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+        }
+
+        let err = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::GlobalStreamLimitReached {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Streams that are fully claimed still count towards the cap
+        env.block.time = env.block.time.plus_seconds(300);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(10),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Bob"),
+                num_installments: 2,
+                interval_seconds: 100,
+                start_time: env.block.time.plus_seconds(100).seconds(),
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let err = execute(deps.as_mut(), env, mock_info(MOCK_CONTRACT_ADDR, &[]), msg).unwrap_err();
+        match err {
+            ContractError::GlobalStreamLimitReached {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn addresses_are_normalized() {
+        let mut deps = mock_dependencies_case_insensitive();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "alice",
+            "BOB",
+            200,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).recipient, "bob");
+
+        // Lookups by a differently cased address still hit the index
+        let msg = QueryMsg::PortfolioFor {
+            recipient: String::from("Bob"),
+            limit: None,
+        };
+        let res: PortfolioResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.streams.len(), 1);
+        assert_eq!(res.streams[0].stream.id, Uint128::new(1));
+
+        let msg = QueryMsg::IsWithdrawAuthorized {
+            id: Uint128::new(1),
+            address: String::from("bOb"),
+        };
+        let res: IsWithdrawAuthorizedResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert!(res.authorized);
+
+        env.block.time = env.block.time.plus_seconds(150);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env, mock_info("bob", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn overdue_claims() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let mut env = mock_env();
+        let installments = |start_time: u64| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(300),
+                msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                    recipient: String::from("Bob"),
+                    num_installments: 3,
+                    interval_seconds: 100,
+                    start_time,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let msg = installments(start_time);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        let msg = installments(start_time + 100);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            200,
+            start_time,
+            start_time + 200,
+        )
+        .unwrap();
+        let msg = installments(start_time);
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+
+        // Bob has claimed the first installment of stream 4 but not stream 1.
+        // Stream 2 has nothing due yet and stream 3 vests continuously.
+        env.block.time = env.block.time.plus_seconds(250);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(4),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+
+        let overdue = |env: Env, start_after, limit| -> OverdueClaimsResponse {
+            let msg = QueryMsg::OverdueClaims { start_after, limit };
+            from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap()
+        };
+        let res = overdue(env.clone(), None, None);
+        assert_eq!(
+            res.claims,
+            vec![OverdueClaim {
+                id: Uint128::new(1),
+                claimable: Uint128::new(100),
+            }]
+        );
+        assert_eq!(res.last_scanned, Some(Uint128::new(4)));
+        let res = overdue(env.clone(), Some(Uint128::new(4)), None);
+        assert_eq!(res.claims, vec![]);
+        assert_eq!(res.last_scanned, None);
+
+        // Once the limit is hit the scan stops at the last stream returned
+        env.block.time = env.block.time.plus_seconds(100);
+        let res = overdue(env, None, Some(2));
+        let ids: Vec<Uint128> = res.claims.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![Uint128::new(1), Uint128::new(2)]);
+        assert_eq!(res.last_scanned, Some(Uint128::new(2)));
+    }
+
+    #[test]
+    fn proposed_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(300).seconds();
+        let propose = ExecuteMsg::ProposeStream {
+            recipient: String::from("Bob"),
+            amount: Uint128::new(200),
+            start_time,
+            end_time,
+        };
+        let fund = |sender: &str, amount: u128, id: u64| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from(sender),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::FundProposal { id }).unwrap(),
+            })
+        };
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+
+        // Proposals must be fundable as they are
+        let alice = mock_info("Alice", &[]);
+        let invalid = |recipient: &str, amount: u128| ExecuteMsg::ProposeStream {
+            recipient: String::from(recipient),
+            amount: Uint128::new(amount),
+            start_time,
+            end_time,
+        };
+        let err =
+            execute(deps.as_mut(), env.clone(), alice.clone(), invalid("Bob", 0)).unwrap_err();
+        match err {
+            ContractError::ZeroAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = invalid(MOCK_CONTRACT_ADDR, 200);
+        let err = execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap_err();
+        match err {
+            ContractError::InvalidRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Propose -> approve -> fund activates the stream
+        execute(deps.as_mut(), env.clone(), alice.clone(), propose.clone()).unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            fund("Alice", 200, 1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ProposalNotApproved {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let approve = ExecuteMsg::ApproveProposedStream {
+            id: 1,
+            accept_amount: None,
+        };
+        let err = execute(deps.as_mut(), env.clone(), alice.clone(), approve.clone()).unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), approve).unwrap();
+
+        let msg = QueryMsg::GetProposal { id: 1 };
+        let res: ProposalResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert!(res.proposal.approved);
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            fund("Alice", 150, 1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidProposalAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            fund("Carl", 200, 1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            fund("Alice", 200, 1),
+        )
+        .unwrap();
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.owner, "Alice");
+        assert_eq!(stream.recipient, "Bob");
+        assert_eq!(stream.amount, Uint128::new(200));
+        assert_eq!(stream.start_time, start_time);
+        assert_eq!(stream.end_time, end_time);
+        assert!(PROPOSALS
+            .may_load(&deps.storage, 1.into())
+            .unwrap()
+            .is_none());
+
+        // Propose -> withdraw cancels the proposal
+        execute(deps.as_mut(), env.clone(), alice.clone(), propose.clone()).unwrap();
+        let withdraw = ExecuteMsg::WithdrawProposal { id: 2 };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), alice.clone(), withdraw).unwrap();
+        assert!(PROPOSALS
+            .may_load(&deps.storage, 2.into())
+            .unwrap()
+            .is_none());
+
+        // Recipient rejects the proposal
+        execute(deps.as_mut(), env.clone(), alice, propose).unwrap();
+        let reject = ExecuteMsg::RejectProposedStream { id: 3 };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), reject).unwrap();
+        let err = execute(deps.as_mut(), env, cw20, fund("Alice", 200, 3)).unwrap_err();
+        match err {
+            ContractError::Std(StdError::NotFound { .. }) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn recipient_tax_report() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for (owner, recipient) in [("Alice", "Bob"), ("Alice", "Carol"), ("Dave", "Bob")] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                owner,
+                recipient,
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let withdraw = |id| ExecuteMsg::Withdraw {
+            id: Uint128::new(id),
+            to: None,
+        };
+
+        // Before the window: 100 from stream 1
+        env.block.time = env.block.time.plus_seconds(200);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw(1),
+        )
+        .unwrap();
+        let from = env.block.time.plus_seconds(1).seconds();
+
+        // Inside the window: 200 from stream 1, 300 from stream 3 and 300 to Carol
+        env.block.time = env.block.time.plus_seconds(200);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw(1),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw(3),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            withdraw(2),
+        )
+        .unwrap();
+
+        // At the end of the window, which is inclusive: 100 from stream 1,
+        // which Bob then hands to Erin
+        env.block.time = env.block.time.plus_seconds(100);
+        let to = env.block.time.seconds();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw(1),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            ExecuteMsg::TransferRecipient {
+                id: Uint128::new(1),
+                new_recipient: String::from("Erin"),
+            },
+        )
+        .unwrap();
+
+        // After the window: 100 from stream 1 to Erin
+        env.block.time = env.block.time.plus_seconds(100);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Erin", &[]),
+            withdraw(1),
+        )
+        .unwrap();
+
+        let report = |start_after, limit| QueryMsg::RecipientTaxReport {
+            recipient: String::from("Bob"),
+            from,
+            to,
+            start_after,
+            limit,
+        };
+        let res: RecipientTaxReportResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), report(None, None)).unwrap()).unwrap();
+        // Stream 1 is still reported for what Bob received from it
+        assert_eq!(res.total_received, Uint128::new(600));
+        assert_eq!(
+            res.streams,
+            vec![
+                TaxReportEntry {
+                    id: Uint128::new(1),
+                    received: Uint128::new(300),
+                },
+                TaxReportEntry {
+                    id: Uint128::new(3),
+                    received: Uint128::new(300),
+                },
+            ]
+        );
+        assert_eq!(res.last_scanned, Some(Uint128::new(3)));
+
+        // Resumes after the last scanned stream
+        let msg = report(None, Some(1));
+        let res: RecipientTaxReportResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.total_received, Uint128::new(300));
+        assert_eq!(res.last_scanned, Some(Uint128::new(1)));
+        let msg = report(res.last_scanned, Some(1));
+        let res: RecipientTaxReportResponse =
+            from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!(res.total_received, Uint128::new(300));
+        assert_eq!(res.last_scanned, Some(Uint128::new(3)));
+    }
+
+    #[test]
+    fn consolidate_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for (owner, amount, start, end) in [
+            ("Alice", 1000, start_time, start_time + 1000),
+            ("Alice", 600, start_time + 200, start_time + 800),
+            ("Dave", 500, start_time, start_time + 500),
+        ] {
+            create_stream(deps.as_mut(), env.clone(), owner, "Bob", amount, start, end).unwrap();
+        }
+
+        env.block.time = env.block.time.plus_seconds(400);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+
+        let request = |ids: &[u128]| ExecuteMsg::RequestConsolidation {
+            ids: ids.iter().map(|id| Uint128::new(*id)).collect(),
+        };
+        let bob = mock_info("Bob", &[]);
+
+        // Streams from different owners can't share a schedule
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), request(&[1, 3])).unwrap_err();
+        match err {
+            ContractError::InvalidConsolidation {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), request(&[1, 1])).unwrap_err();
+        match err {
+            ContractError::InvalidConsolidation {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            request(&[1, 2]),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Request -> approve by the owner
+        execute(deps.as_mut(), env.clone(), bob.clone(), request(&[1, 2])).unwrap();
+        let approve = ExecuteMsg::ApproveConsolidation {
+            id: Uint128::new(1),
+        };
+        let err = execute(deps.as_mut(), env.clone(), bob, approve.clone()).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // A lien or a cliff taken on since the request blocks the approval
+        let unencumbered = streams().load(&deps.storage, 2u128.into()).unwrap();
+        let encumbered = Stream {
+            encumbrance: Some(Encumbrance {
+                lender: Addr::unchecked("Lender"),
+                outstanding: Uint128::new(50),
+            }),
+            ..unencumbered.clone()
+        };
+        let with_cliff = Stream {
+            cliff_time: Some(start_time + 300),
+            ..unencumbered.clone()
+        };
+        for stream in [encumbered, with_cliff] {
+            let expected_lien = stream.encumbrance.is_some();
+            streams()
+                .save(&mut deps.storage, 2u128.into(), &stream)
+                .unwrap();
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Alice", &[]),
+                approve.clone(),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::StreamEncumbered {} if expected_lien => {}
+                ContractError::InvalidConsolidation {} if !expected_lien => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        streams()
+            .save(&mut deps.storage, 2u128.into(), &unencumbered)
+            .unwrap();
+
+        // Stream 1 has 300 claimed and 100 vested unclaimed, stream 2 has 200
+        // vested
+        env.block.time = env.block.time.plus_seconds(100);
+        execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), approve).unwrap();
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, Uint128::new(1600));
+        assert_eq!(stream.claimed_amount, Uint128::new(300));
+        assert_eq!(stream.start_time, start_time);
+        assert_eq!(stream.end_time, start_time + 1000);
+        let block_time = env.block.time.seconds();
+        let stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        assert_eq!(stream.checkpoint_time, block_time);
+        assert_eq!(stream.checkpoint_vested, Uint128::new(600));
+        assert_eq!(
+            compute_vested(&stream, block_time + 300).unwrap(),
+            Uint128::new(600 + 1000 * 300 / 600)
+        );
+        assert_eq!(
+            compute_vested(&stream, start_time + 1000).unwrap(),
+            Uint128::new(1600)
+        );
+
+        let closed = get_stream(deps.as_ref(), 2);
+        assert_eq!(closed.amount, Uint128::zero());
+        assert_eq!(closed.claimed_amount, Uint128::zero());
+        assert!(PENDING_CONSOLIDATIONS
+            .may_load(&deps.storage, 1u128.into())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn storage_estimate() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let estimate = |deps: Deps| -> StorageEstimateResponse {
+            from_binary(&query(deps, mock_env(), QueryMsg::StorageEstimate {}).unwrap()).unwrap()
+        };
+        let res = estimate(deps.as_ref());
+        assert_eq!(res.total_streams, Uint128::zero());
+        assert_eq!(res.total_bytes, Uint128::zero());
+
+        // At least as large as any actual stream
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            start_time + 100,
+        )
+        .unwrap();
+        let stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        assert!(res.bytes_per_stream > to_vec(&stream).unwrap().len() as u64);
+
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                100,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+        }
+        let res = estimate(deps.as_ref());
+        assert_eq!(res.total_streams, Uint128::new(3));
+        assert_eq!(
+            res.total_bytes,
+            Uint128::from(res.bytes_per_stream) * Uint128::new(3)
+        );
+    }
+
+    #[test]
+    fn conditional_stream() {
+        let reached = Rc::new(Cell::new(false));
+        let oracle = reached.clone();
+        let mut deps = mock_dependencies_with_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "oracle" => {
+                assert_eq!(msg, &Binary::from(br#"{"milestone":{"id":1}}"#));
+                let res = format!(r#"{{"reached":{}}}"#, oracle.get());
+                SystemResult::Ok(ContractResult::Ok(Binary::from(res.as_bytes())))
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("unsupported"))),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let condition = ConditionSpec {
+            contract: String::from("oracle"),
+            msg: Binary::from(br#"{"milestone":{"id":1}}"#),
+            expected: Binary::from(br#"{"reached":true}"#),
+        };
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: String::from("Bob"),
+                start_time,
+                end_time: start_time + 200,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: Some(condition.clone()),
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).condition,
+            Some(Condition {
+                contract: Addr::unchecked("oracle"),
+                msg: condition.msg,
+                expected: condition.expected,
+            })
+        );
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = env.block.time.plus_seconds(200);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ConditionNotMet {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Accrual continued while claims were gated
+        reached.set(true);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "100"));
+
+        reached.set(false);
+        env.block.time = env.block.time.plus_seconds(100);
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::ConditionNotMet {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Cancelling refunds the unvested part and keeps the gated vested
+        // part in the stream until the condition holds
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: String::from("Bob"),
+                start_time,
+                end_time: start_time + 200,
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: Some(ConditionSpec {
+                    contract: String::from("oracle"),
+                    msg: Binary::from(br#"{"milestone":{"id":1}}"#),
+                    expected: Binary::from(br#"{"reached":true}"#),
+                }),
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(200);
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            (data.paid, data.refunded),
+            (Uint128::zero(), Uint128::new(100))
+        );
+        assert_eq!(res.messages.len(), 1);
+        let stream = get_stream(deps.as_ref(), 2);
+        assert!(stream.cancelled);
+        assert_eq!(stream.amount, Uint128::new(100));
+        assert_eq!(stream.claimed_amount, Uint128::zero());
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(2),
+            to: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ConditionNotMet {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        reached.set(true);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "100"));
+        let err = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = start_time + SECONDS_PER_DAY;
+        let amount = u128::MAX / u128::from(SECONDS_PER_DAY) * u128::from(SECONDS_PER_DAY);
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            amount,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        // Topping up past the largest amount fails cleanly
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(u128::MAX - amount + 1),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: vec![Uint128::new(1)],
+                weights: None,
+                note: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, top_up).unwrap_err();
+        match err {
+            ContractError::Overflow(OverflowError {
+                operation: OverflowOperation::Add,
+                ..
+            }) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // So does running out of stream ids
+        STREAM_SEQ
+            .save(deps.as_mut().storage, &Uint128::MAX)
+            .unwrap();
+        let err = create_stream(
+            deps.as_mut(),
+            env,
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            start_time + 100,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Overflow(OverflowError {
+                operation: OverflowOperation::Add,
+                ..
+            }) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // A stream whose checkpoint is past its amount can't underflow either
+        let mut stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        stream.checkpoint_vested = Uint128::MAX;
+        let err = compute_vested(&stream, start_time + 1).unwrap_err();
+        assert_eq!(err.operation, OverflowOperation::Sub);
+    }
+
+    #[test]
+    fn recipient_by_name() {
+        let record = Rc::new(RefCell::new(Some(String::from("Bob"))));
+        let name_service = record.clone();
+        let mut deps = mock_dependencies_with_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "names" => {
+                let res = match from_binary(msg).unwrap() {
+                    NameServiceQueryMsg::ResolveRecord { name } if name == "bob.pay" => {
+                        name_service.borrow().clone()
+                    }
+                    NameServiceQueryMsg::ResolveRecord { .. } => None,
+                };
+                let res = to_binary(&ResolveRecordResponse { address: res }).unwrap();
+                SystemResult::Ok(ContractResult::Ok(res))
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("unsupported"))),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: Some(String::from("names")),
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let create = |recipient: &str| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(200),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from(recipient),
+                    start_time,
+                    end_time: start_time + 200,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: Some(true),
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            info.clone(),
+            create("carol.pay"),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NameNotResolved {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let res = execute(deps.as_mut(), env.clone(), info.clone(), create("bob.pay")).unwrap();
+        assert!(res.attributes.contains(&attr("recipient", "Bob")));
+
+        // The resolved address is kept when the record changes
+        *record.borrow_mut() = Some(String::from("Mallory"));
+        assert_eq!(get_stream(deps.as_ref(), 1).recipient, "Bob");
+
+        // Without a name service names can't be used
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let err = execute(deps.as_mut(), env, info, create("bob.pay")).unwrap_err();
+        match err {
+            ContractError::NameServiceNotConfigured {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn bootstrap() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for (recipient, amount) in [("Bob", 200), ("Carol", 100), ("Bob", 400)] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                amount,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+        }
+        env.block.time = env.block.time.plus_seconds(150);
+
+        let msg = QueryMsg::Bootstrap {
+            recipient: Some(String::from("Bob")),
+        };
+        let res: BootstrapResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.config.owner, MOCK_CONTRACT_ADDR);
+        assert_eq!(res.config.cw20_addr, MOCK_CONTRACT_ADDR);
+        let stats: StatsResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Stats {}).unwrap()).unwrap();
+        assert_eq!(res.stats, stats);
+        assert_eq!(res.stats.total_deposited, Uint128::new(700));
+        assert_eq!(res.stats.active_streams, 3);
+        let ids: Vec<Uint128> = res
+            .portfolio
+            .unwrap()
+            .streams
+            .iter()
+            .map(|entry| entry.stream.id)
+            .collect();
+        assert_eq!(ids, vec![Uint128::new(1), Uint128::new(3)]);
+        assert_eq!(res.total_claimable, Some(Uint128::new(300)));
+
+        // Without a recipient the portfolio is skipped
+        let msg = QueryMsg::Bootstrap { recipient: None };
+        let res: BootstrapResponse = from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!(res.stats, stats);
+        assert_eq!(res.portfolio, None);
+        assert_eq!(res.total_claimable, None);
+    }
+
+    #[test]
+    fn create_stream_with_change() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let start_time = mock_env().block.time.plus_seconds(100).seconds();
+        let create = |sent: u128, amount: Option<u128>| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(sent),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time: start_time + 100,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: amount.map(Uint128::new),
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+
+        // Exact amount, nothing to refund
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            create(200, Some(200)),
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(200));
+
+        // Over-sent, the difference goes back to the sender
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            create(250, Some(200)),
+        )
+        .unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(200));
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Alice"),
+                    amount: Uint128::new(50)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+
+        // A stream can't be larger than what was sent
+        let err = execute(deps.as_mut(), mock_env(), info, create(100, Some(200))).unwrap_err();
+        match err {
+            ContractError::InvalidStreamAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn delegate_allowance() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+
+        let set_allowance = ExecuteMsg::SetDelegateAllowance {
+            id: Uint128::new(1),
+            delegate: String::from("Dave"),
+            allowance: Some(Uint128::new(250)),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Dave", &[]),
+            set_allowance.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            set_allowance,
+        )
+        .unwrap();
+
+        let allowance = |deps: Deps| -> Option<Uint128> {
+            let msg = QueryMsg::DelegateAllowance {
+                id: Uint128::new(1),
+                delegate: String::from("Dave"),
+            };
+            let res: DelegateAllowanceResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.allowance
+        };
+        assert_eq!(allowance(deps.as_ref()), Some(Uint128::new(250)));
+
+        // Within the allowance
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = env.block.time.plus_seconds(300);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Dave", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(allowance(deps.as_ref()), Some(Uint128::new(50)));
+
+        // Beyond what's left of it
+        env.block.time = env.block.time.plus_seconds(100);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Dave", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::AllowanceExceeded {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The recipient isn't limited
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(allowance(deps.as_ref()), Some(Uint128::new(50)));
+
+        // Revoked allowances no longer authorize the delegate
+        let msg = ExecuteMsg::SetDelegateAllowance {
+            id: Uint128::new(1),
+            delegate: String::from("Dave"),
+            allowance: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        assert_eq!(allowance(deps.as_ref()), None);
+        env.block.time = env.block.time.plus_seconds(10);
+        let err = execute(deps.as_mut(), env, mock_info("Dave", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn revert_top_up() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            start_time + 300,
+        )
+        .unwrap();
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(3000),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: vec![Uint128::new(1)],
+                weights: None,
+                note: None,
+            })
+            .unwrap(),
+        });
+        let revert = ExecuteMsg::RevertTopUp {
+            id: Uint128::new(1),
+        };
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+
+        // Reverted within the same block
+        env.block.time = env.block.time.plus_seconds(200);
+        let before = streams().load(&deps.storage, 1u128.into()).unwrap();
+        execute(deps.as_mut(), env.clone(), cw20.clone(), top_up.clone()).unwrap();
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            revert.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            revert.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Alice"),
+                    amount: Uint128::new(3000)
+                })
+                .unwrap(),
+                funds: vec![]
+            })
+        );
+        let stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        assert_eq!(stream.amount, before.amount);
+        assert_eq!(stream.rate_per_second, before.rate_per_second);
+        assert_eq!(stream.claimed_amount, Uint128::new(100));
+        assert_eq!(
+            compute_vested(&stream, start_time + 200).unwrap(),
+            Uint128::new(200)
+        );
+
+        // Nothing left to revert
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            revert.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::TopUpNotRevertible {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Too late in a later block
+        execute(deps.as_mut(), env.clone(), cw20, top_up).unwrap();
+        env.block.height += 1;
+        let err = execute(deps.as_mut(), env, mock_info("Alice", &[]), revert).unwrap_err();
+        match err {
+            ContractError::TopUpNotRevertible {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn revert_top_up_refunds_sender() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: Some(100),
+        };
+        let owner = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let emergency = ExecuteMsg::SetEmergencyRecipient {
+            address: Some(String::from("Vault")),
+        };
+        execute(deps.as_mut(), mock_env(), owner, emergency).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = Timestamp::from_seconds(start_time + 200);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        // The top-up restarts the cooldown
+        env.block.time = Timestamp::from_seconds(start_time + 400);
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(500),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), cw20, top_up).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).last_claim_time,
+            Some(start_time + 400)
+        );
+
+        // Reverting it pays Alice back rather than the emergency recipient,
+        // and the cooldown runs from Bob's last claim again
+        let revert = ExecuteMsg::RevertTopUp {
+            id: Uint128::new(1),
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), revert).unwrap();
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(Some(&token), None, "Alice", Uint128::new(500)).unwrap()
+        );
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).last_claim_time,
+            Some(start_time + 200)
+        );
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(Some(&token), None, "Bob", Uint128::new(200)).unwrap()
+        );
+    }
+
+    #[test]
+    fn linear_vesting_accrues_mid_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Fewer tokens than seconds, so vesting per second rounds to zero and
+        // only multiplying before dividing accrues anything
+        let mut env = mock_env();
+        let start_time = env.block.time.seconds();
+        let end_time = start_time + 15000;
+        let stream = Stream {
+            owner: Addr::unchecked("Alice"),
+            recipient: Addr::unchecked("Bob"),
+            amount: Uint128::new(100),
+            claimed_amount: Uint128::zero(),
+            start_time,
+            end_time,
+            rate_per_second: Uint128::zero(),
+            rate_per_day: Uint128::new(576),
+            installments: None,
+            checkpoint_time: start_time,
+            checkpoint_vested: Uint128::zero(),
+            fallback_recipient: None,
+            recipient_controller: None,
+            created_at: start_time,
+            frozen_at: None,
+            beneficiary: None,
+            condition: None,
+            cancel_at: None,
+            cancelled: false,
+            rounding_unit: None,
+            cliff_time: None,
+            encumbrance: None,
+            cw20_addr: Some(Addr::unchecked(MOCK_CONTRACT_ADDR)),
+            denom: None,
+            allowed_destinations: None,
+            failed_withdraw_count: 0,
+            last_claim_time: None,
+            unlock_schedule: None,
+            paused_at: None,
+            paused_duration: 0,
+            cancel_window_end: None,
+        };
+        save_stream(deps.as_mut(), &stream).unwrap();
+        assert_eq!(
+            compute_vested(&stream, start_time + 1).unwrap(),
+            Uint128::zero()
+        );
+        assert_eq!(
+            compute_vested(&stream, start_time + 150).unwrap(),
+            Uint128::new(1)
+        );
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = env.block.time.plus_seconds(7500);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "50"));
+
+        // From the end time on the rest is paid in full
+        env.block.time = env.block.time.plus_seconds(7500);
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "50"));
+        assert_eq!(
+            compute_vested(&stream, end_time + 1000).unwrap(),
+            Uint128::new(100)
+        );
+    }
+
+    #[test]
+    fn withdraw_before_start() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            start_time + 100,
+        )
+        .unwrap();
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        for offset in [50, 100] {
+            env.block.time = mock_env().block.time.plus_seconds(offset);
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw.clone(),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::StreamNotStarted {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        env.block.time = env.block.time.plus_seconds(10);
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "10"));
+    }
+
+    #[test]
+    fn cancel_window() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let alice = mock_info("Alice", &[]);
+        let set_window = |id: u128, end: u64| ExecuteMsg::SetCancelWindow {
+            id: Uint128::new(id),
+            cancel_window_end: end,
+        };
+        let cancel = |id: u128| ExecuteMsg::Cancel {
+            id: Uint128::new(id),
+        };
+
+        let info = mock_info("Bob", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, set_window(1, start_time)).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The window can be shortened but not extended
+        let msg = set_window(1, start_time + 300);
+        execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap();
+        let msg = set_window(1, start_time + 400);
+        let err = execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap_err();
+        match err {
+            ContractError::InvalidCancelWindow {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = set_window(1, start_time + 200);
+        execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).cancel_window_end,
+            Some(start_time + 200)
+        );
+
+        // Scheduled cancellations have to take effect within it
+        let schedule = |cancel_at| ExecuteMsg::ScheduleCancel {
+            id: Uint128::new(1),
+            cancel_at: Some(cancel_at),
+        };
+        let msg = schedule(start_time + 250);
+        let err = execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap_err();
+        match err {
+            ContractError::CancellationWindowClosed {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = schedule(start_time + 150);
+        execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap();
+
+        // Inside the window the stream can still be cut down
+        env.block.time = Timestamp::from_seconds(start_time + 110);
+        let partial = ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(100),
+            note: None,
+        };
+        execute(deps.as_mut(), env.clone(), alice.clone(), partial.clone()).unwrap();
+
+        // Past it the stream is irrevocable, including the cancellation
+        // scheduled before the window was shortened
+        let msg = set_window(1, start_time + 120);
+        execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap();
+        env.block.time = Timestamp::from_seconds(start_time + 160);
+        let settle = ExecuteMsg::ExecuteScheduledCancel {
+            id: Uint128::new(1),
+        };
+        for msg in [settle, cancel(1), partial] {
+            let err = execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap_err();
+            match err {
+                ContractError::CancellationWindowClosed {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Another stream still inside its window is cancelled as usual
+        let msg = set_window(2, start_time + 500);
+        execute(deps.as_mut(), env.clone(), alice.clone(), msg).unwrap();
+        let res = execute(deps.as_mut(), env, alice, cancel(2)).unwrap();
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.paid, Uint128::new(160));
+        assert_eq!(data.refunded, Uint128::new(840));
+    }
+
+    #[test]
+    fn soft_cancel() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        let alice = mock_info("Alice", &[]);
+        let bob = mock_info("Bob", &[]);
+        let soft_cancel = |id: u128| ExecuteMsg::SoftCancel {
+            id: Uint128::new(id),
+        };
+        let restore = |id: u128| ExecuteMsg::RestoreStream {
+            id: Uint128::new(id),
+        };
+        let finalize = |id: u128| ExecuteMsg::FinalizeCancellation {
+            id: Uint128::new(id),
+        };
+        let withdraw = |id: u128| ExecuteMsg::Withdraw {
+            id: Uint128::new(id),
+            to: None,
+        };
+
+        env.block.time = Timestamp::from_seconds(start_time + 200);
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), soft_cancel(1)).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Nothing leaves the contract yet, and the recipient can't withdraw
+        let res = execute(deps.as_mut(), env.clone(), alice.clone(), soft_cancel(1)).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(res.attributes[2], attr("paid", "200"));
+        assert_eq!(res.attributes[3], attr("refund_amount", "800"));
+        assert!(get_stream(deps.as_ref(), 1).cancelled);
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), withdraw(1)).unwrap_err();
+        match err {
+            ContractError::CancellationPending {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), finalize(1)).unwrap_err();
+        match err {
+            ContractError::GracePeriodNotOver {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The stream owner restores it, and it vests as if never cancelled
+        let err = execute(deps.as_mut(), env.clone(), bob.clone(), restore(1)).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), alice.clone(), restore(1)).unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert!(!stream.cancelled);
+        assert_eq!(stream.amount, Uint128::new(1000));
+        assert_eq!(stream.claimed_amount, Uint128::zero());
+        let err = execute(deps.as_mut(), env.clone(), alice.clone(), restore(1)).unwrap_err();
+        match err {
+            ContractError::NoPendingCancellation {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        env.block.time = Timestamp::from_seconds(start_time + 300);
+        let res = execute(deps.as_mut(), env.clone(), bob.clone(), withdraw(1)).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(Some(&token), None, "Bob", Uint128::new(300)).unwrap()
+        );
+
+        // Past the grace period it can only be finalized, by anyone
+        execute(deps.as_mut(), env.clone(), alice.clone(), soft_cancel(2)).unwrap();
+        env.block.time = Timestamp::from_seconds(start_time + 300 + SOFT_CANCEL_GRACE_PERIOD);
+        let err = execute(deps.as_mut(), env.clone(), alice, restore(2)).unwrap_err();
+        match err {
+            ContractError::GracePeriodOver {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info("Carl", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info.clone(), finalize(2)).unwrap();
+        assert_eq!(
+            res.messages
+                .into_iter()
+                .map(|msg| msg.msg)
+                .collect::<Vec<_>>(),
+            vec![
+                transfer_msg(Some(&token), None, "Bob", Uint128::new(300)).unwrap(),
+                transfer_msg(Some(&token), None, "Alice", Uint128::new(700)).unwrap(),
+            ]
+        );
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.paid, Uint128::new(300));
+        assert_eq!(data.refunded, Uint128::new(700));
+        let err = execute(deps.as_mut(), env.clone(), bob, withdraw(2)).unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(deps.as_mut(), env, info, finalize(2)).unwrap_err();
+        match err {
+            ContractError::NoPendingCancellation {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn scheduled_cancel() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+
+        let schedule = |cancel_at| ExecuteMsg::ScheduleCancel {
+            id: Uint128::new(1),
+            cancel_at: Some(cancel_at),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            schedule(start_time + 600),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let alice = mock_info("Alice", &[]);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            alice.clone(),
+            schedule(start_time + 1000),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidCancelTime {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            alice.clone(),
+            schedule(start_time + 600),
+        )
+        .unwrap();
+
+        // Projections stop growing at the cancellation
+        let claimable_at = |deps: Deps, at| -> Uint128 {
+            let msg = QueryMsg::ClaimableAt {
+                id: Uint128::new(1),
+                at,
+            };
+            let res: ClaimableAtResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.claimable
+        };
+        assert_eq!(
+            claimable_at(deps.as_ref(), start_time + 300),
+            Uint128::new(300)
+        );
+        assert_eq!(
+            claimable_at(deps.as_ref(), start_time + 600),
+            Uint128::new(600)
+        );
+        assert_eq!(
+            claimable_at(deps.as_ref(), start_time + 900),
+            Uint128::new(600)
+        );
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+
+        let cancel = ExecuteMsg::ExecuteScheduledCancel {
+            id: Uint128::new(1),
+        };
+        let keeper = mock_info("Keeper", &[]);
+        let err = execute(deps.as_mut(), env.clone(), keeper.clone(), cancel.clone()).unwrap_err();
+        match err {
+            ContractError::ScheduledCancelNotDue {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Once due, a keeper settles what vested, repaying a lien on it
+        // first, and refunds the rest
+        let mut stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        stream.encumbrance = Some(Encumbrance {
+            lender: Addr::unchecked("Lender"),
+            outstanding: Uint128::new(100),
+        });
+        streams()
+            .save(&mut deps.storage, 1u128.into(), &stream)
+            .unwrap();
+        env.block.time = env.block.time.plus_seconds(500);
+
+        // A frozen stream is settled by neither cancellation until unfrozen
+        let freeze = ExecuteMsg::FreezeStream {
+            id: Uint128::new(1),
+        };
+        execute(deps.as_mut(), env.clone(), alice.clone(), freeze).unwrap();
+        let owner_cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        for (info, msg) in [
+            (keeper.clone(), cancel.clone()),
+            (alice.clone(), owner_cancel),
+        ] {
+            let err = execute(deps.as_mut(), env.clone(), info, msg).unwrap_err();
+            match err {
+                ContractError::StreamFrozen {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        let unfreeze = ExecuteMsg::UnfreezeStream {
+            id: Uint128::new(1),
+        };
+        execute(deps.as_mut(), env.clone(), alice.clone(), unfreeze).unwrap();
+
+        let res = execute(deps.as_mut(), env.clone(), keeper.clone(), cancel.clone()).unwrap();
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+        let msgs: Vec<CosmosMsg> = res.messages.into_iter().map(|m| m.msg).collect();
+        assert_eq!(
+            msgs,
+            vec![
+                transfer("Lender", 100),
+                transfer("Bob", 300),
+                transfer("Alice", 400)
+            ]
+        );
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, Uint128::new(600));
+        assert_eq!(stream.claimed_amount, Uint128::new(600));
+        assert_eq!(stream.end_time, start_time + 600);
+        assert_eq!(stream.encumbrance, None);
+        assert!(stream.cancelled);
+
+        let err = execute(deps.as_mut(), env, keeper, cancel).unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn withdraw_twice_in_same_block() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            start_time + 100,
+        )
+        .unwrap();
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        env.block.time = env.block.time.plus_seconds(150);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "50"));
+
+        // Nothing new has vested since, which is an error rather than a panic
+        let err = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn execute_response_data() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for id in 1..=2u128 {
+            let res = create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+            let data: CreateResult = from_binary(&res.data.unwrap()).unwrap();
+            assert_eq!(data, CreateResult { id: id.into() });
+        }
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Bob"),
+                num_installments: 4,
+                interval_seconds: 100,
+                start_time,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        let data: CreateResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data, CreateResult { id: 3u128.into() });
+
+        env.block.time = env.block.time.plus_seconds(400);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let data: WithdrawResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            data,
+            WithdrawResult {
+                id: Uint128::new(1),
+                amount: Uint128::new(300),
+                new_claimed: Uint128::new(300),
+                remaining: Uint128::new(700),
+            }
+        );
+
+        let alice = mock_info("Alice", &[]);
+        let cancel = ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(200),
+            note: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), alice.clone(), cancel).unwrap();
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            data,
+            CancelResult {
+                id: Uint128::new(1),
+                refunded: Uint128::new(200),
+                paid: Uint128::zero(),
+            }
+        );
+
+        let schedule = ExecuteMsg::ScheduleCancel {
+            id: Uint128::new(2),
+            cancel_at: Some(start_time + 500),
+        };
+        execute(deps.as_mut(), env.clone(), alice, schedule).unwrap();
+        env.block.time = env.block.time.plus_seconds(300);
+        let cancel = ExecuteMsg::ExecuteScheduledCancel {
+            id: Uint128::new(2),
+        };
+        let res = execute(deps.as_mut(), env, mock_info("Keeper", &[]), cancel).unwrap();
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            data,
+            CancelResult {
+                id: Uint128::new(2),
+                refunded: Uint128::new(500),
+                paid: Uint128::new(500),
+            }
+        );
+    }
+
+    #[test]
+    fn create_stream_normalizes_owner() {
+        let mut deps = mock_dependencies_case_insensitive();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "ALICE",
+            "Bob",
+            200,
+            start_time,
+            start_time + 200,
+        )
+        .unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.owner, "alice");
+        assert_eq!(stream.recipient, "bob");
+
+        // Malformed addresses fail validation instead of aborting
+        let err = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "b",
+            200,
+            start_time,
+            start_time + 200,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Std(StdError::GenericErr { msg, .. }) => {
+                assert!(msg.contains("too short"))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = create_stream(
+            deps.as_mut(),
+            env,
+            "a",
+            "Bob",
+            200,
+            start_time,
+            start_time + 200,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Std(StdError::GenericErr { msg, .. }) => {
+                assert!(msg.contains("too short"))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn cancel_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: Some(String::from("Admin")),
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+
+        let cancel = |id: u128| ExecuteMsg::Cancel { id: id.into() };
+        let err =
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), cancel(1)).unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Before the start everything goes back to the owner, the contract
+        // owner may cancel too
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Admin", &[]),
+            cancel(2),
+        )
+        .unwrap();
+        let msgs: Vec<CosmosMsg> = res.messages.into_iter().map(|m| m.msg).collect();
+        assert_eq!(msgs, vec![transfer("Alice", 1000)]);
+
+        env.block.time = env.block.time.plus_seconds(200);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        // The vested remainder is paid out and the unvested part refunded
+        env.block.time = env.block.time.plus_seconds(200);
+        let alice = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env.clone(), alice.clone(), cancel(1)).unwrap();
+        let msgs: Vec<CosmosMsg> = res.messages.into_iter().map(|m| m.msg).collect();
+        assert_eq!(msgs, vec![transfer("Bob", 200), transfer("Alice", 700)]);
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert!(stream.cancelled);
+        assert_eq!(stream.amount, Uint128::new(300));
+        assert_eq!(stream.claimed_amount, Uint128::new(300));
+
+        env.block.time = env.block.time.plus_seconds(200);
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(deps.as_mut(), env, alice, cancel(1)).unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn rounding_unit() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let create = |rounding_unit: u128| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(3_500_000),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time: start_time + 1000,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: Some(Uint128::new(rounding_unit)),
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), create(0)).unwrap_err();
+        match err {
+            ContractError::InvalidRoundingUnit {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), info, create(1_000_000)).unwrap();
+
+        let withdraw = |deps: DepsMut, env: Env| -> Result<Uint128, ContractError> {
+            let msg = ExecuteMsg::Withdraw {
+                id: Uint128::new(1),
+                to: None,
+            };
+            let res = execute(deps, env, mock_info("Bob", &[]), msg)?;
+            let data: WithdrawResult = from_binary(&res.data.unwrap()).unwrap();
+            Ok(data.amount)
+        };
+
+        // 1.05 tokens vested, the sub-unit part waits for a later claim
+        env.block.time = env.block.time.plus_seconds(400);
+        let msg = QueryMsg::WithdrawPreview {
+            id: Uint128::new(1),
+        };
+        let res: WithdrawPreviewResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.claimable, Uint128::new(1_000_000));
+        assert_eq!(
+            withdraw(deps.as_mut(), env.clone()).unwrap(),
+            Uint128::new(1_000_000)
+        );
+
+        // Only 0.7 tokens more by now, less than a unit
+        env.block.time = env.block.time.plus_seconds(200);
+        match withdraw(deps.as_mut(), env.clone()).unwrap_err() {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // 2.45 tokens vested, the carried 0.05 counts towards the next unit
+        env.block.time = env.block.time.plus_seconds(200);
+        assert_eq!(
+            withdraw(deps.as_mut(), env.clone()).unwrap(),
+            Uint128::new(1_000_000)
+        );
+
+        // At the end the remainder is released in full
+        env.block.time = env.block.time.plus_seconds(300);
+        assert_eq!(
+            withdraw(deps.as_mut(), env).unwrap(),
+            Uint128::new(1_500_000)
+        );
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.claimed_amount, stream.amount);
+    }
+
+    #[test]
+    fn stream_ids_are_contiguous_within_a_call() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            100,
+            start_time,
+            start_time + 100,
+        )
+        .unwrap();
+
+        // Saving several streams in one execution, as a batch would, hands
+        // out the next ids in order without gaps or reuse
+        let stream = streams().load(&deps.storage, 1u128.into()).unwrap();
+        let ids: Vec<Uint128> = (0..5)
+            .map(|_| save_stream(deps.as_mut(), &stream).unwrap())
+            .collect();
+        let expected: Vec<Uint128> = (2..=6u128).map(Uint128::new).collect();
+        assert_eq!(ids, expected);
+        for id in 2..=6 {
+            assert_eq!(get_stream(deps.as_ref(), id).id, Uint128::new(id));
+        }
+        assert_eq!(STREAM_SEQ.load(&deps.storage).unwrap(), Uint128::new(6));
+    }
+
+    #[test]
+    fn list_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Carol", "Dave", "Erin", "Frank"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                100,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+        }
+
+        let list = |start_after: Option<u128>| -> Vec<(Uint128, String)> {
+            let msg = QueryMsg::ListStreams {
+                start_after: start_after.map(Uint128::new),
+                limit: Some(3),
+            };
+            let res: StreamsResponse =
+                from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+            res.streams
+                .into_iter()
+                .map(|stream| (stream.id, stream.recipient))
+                .collect()
+        };
+        let first = list(None);
+        assert_eq!(
+            first,
+            vec![
+                (Uint128::new(1), String::from("Bob")),
+                (Uint128::new(2), String::from("Carol")),
+                (Uint128::new(3), String::from("Dave")),
+            ]
+        );
+        let second = list(Some(first.last().unwrap().0.u128()));
+        assert_eq!(
+            second,
+            vec![
+                (Uint128::new(4), String::from("Erin")),
+                (Uint128::new(5), String::from("Frank")),
+            ]
+        );
+        assert!(list(Some(5)).is_empty());
+    }
+
+    #[test]
+    fn streams_by_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Carol", "Bob", "Carol", "Bob"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                100,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+        }
+
+        let list = |recipient: &str, start_after: Option<u128>, limit| -> Vec<Uint128> {
+            let msg = QueryMsg::StreamsByRecipient {
+                recipient: String::from(recipient),
+                start_after: start_after.map(Uint128::new),
+                limit,
+            };
+            let res: StreamsResponse =
+                from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+            assert!(res.streams.iter().all(|s| s.recipient == recipient));
+            res.streams.into_iter().map(|stream| stream.id).collect()
+        };
+        assert_eq!(
+            list("Bob", None, None),
+            vec![Uint128::new(1), Uint128::new(3), Uint128::new(5)]
+        );
+        assert_eq!(
+            list("Carol", None, None),
+            vec![Uint128::new(2), Uint128::new(4)]
+        );
+        assert_eq!(
+            list("Bob", None, Some(2)),
+            vec![Uint128::new(1), Uint128::new(3)]
+        );
+        assert_eq!(list("Bob", Some(3), Some(2)), vec![Uint128::new(5)]);
+        assert!(list("Dave", None, None).is_empty());
+    }
+
+    #[test]
+    fn freeze_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: Some(String::from("Admin")),
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Bob", "Carol"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let withdraw = |id: u128| ExecuteMsg::Withdraw {
+            id: id.into(),
+            to: None,
+        };
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let freeze = ExecuteMsg::FreezeRecipient {
+            recipient: String::from("Bob"),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            freeze.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let admin = mock_info("Admin", &[]);
+        execute(deps.as_mut(), env.clone(), admin.clone(), freeze).unwrap();
+
+        // Both of Bob's streams are blocked, Carol's isn't
+        for id in 1..=2 {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw(id),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::RecipientFrozen {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            withdraw(3),
+        )
+        .unwrap();
+
+        // The stream owner can't lift the freeze stream by stream
+        let msg = ExecuteMsg::UnfreezeStream {
+            id: Uint128::new(1),
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), msg).unwrap_err();
+        match err {
+            ContractError::RecipientFrozen {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Like a stream freeze, accrual stops and the schedule shifts
+        env.block.time = env.block.time.plus_seconds(200);
+        let unfreeze = ExecuteMsg::UnfreezeRecipient {
+            recipient: String::from("Bob"),
+        };
+        execute(deps.as_mut(), env.clone(), admin.clone(), unfreeze.clone()).unwrap();
+        for id in 1..=2 {
+            let res = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw(id),
+            )
+            .unwrap();
+            let data: WithdrawResult = from_binary(&res.data.unwrap()).unwrap();
+            assert_eq!(data.amount, Uint128::new(200));
+            let stream = get_stream(deps.as_ref(), id);
+            assert_eq!(stream.frozen_at, None);
+            assert_eq!(stream.end_time, start_time + 1200);
+        }
+        assert_eq!(get_stream(deps.as_ref(), 3).end_time, start_time + 1000);
+
+        let err = execute(deps.as_mut(), env, admin, unfreeze).unwrap_err();
+        match err {
+            ContractError::RecipientNotFrozen {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn owner_summary() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for (owner, amount) in [
+            ("Alice", 1000),
+            ("Alice", 2000),
+            ("Dave", 500),
+            ("Alice", 3000),
+        ] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                owner,
+                "Bob",
+                amount,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let alice = mock_info("Alice", &[]);
+        let window = ExecuteMsg::SetCancelWindow {
+            id: Uint128::new(4),
+            cancel_window_end: start_time + 100,
+        };
+        execute(deps.as_mut(), env.clone(), alice.clone(), window).unwrap();
+
+        // A quarter through, Bob withdraws from the first stream and Alice
+        // cancels the second
+        env.block.time = Timestamp::from_seconds(start_time + 250);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        execute(deps.as_mut(), env.clone(), alice, cancel).unwrap();
+
+        let summary = |start_after: Option<u128>| -> OwnerSummaryResponse {
+            let msg = QueryMsg::OwnerSummary {
+                owner: String::from("Alice"),
+                start_after: start_after.map(Uint128::new),
+            };
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap()
+        };
+        // Only the first stream can still be cancelled, the fourth is past its
+        // window
+        assert_eq!(
+            summary(None),
+            OwnerSummaryResponse {
+                stream_count: 3,
+                total_committed: Uint128::new(4500),
+                total_remaining: Uint128::new(3750),
+                total_refundable: Uint128::new(750),
+                last_scanned: None,
+            }
+        );
+        assert_eq!(
+            summary(Some(1)),
+            OwnerSummaryResponse {
+                stream_count: 2,
+                total_committed: Uint128::new(3500),
+                total_remaining: Uint128::new(3000),
+                total_refundable: Uint128::zero(),
+                last_scanned: None,
+            }
+        );
+    }
+
+    #[test]
+    fn streams_by_owner() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for owner in ["Alice", "Dave", "Alice", "Alice"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                owner,
+                "Bob",
+                100,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+        }
+
+        let list = |owner: &str, start_after: Option<u128>, limit| -> Vec<Uint128> {
+            let msg = QueryMsg::StreamsByOwner {
+                owner: String::from(owner),
+                start_after: start_after.map(Uint128::new),
+                limit,
+            };
+            let res: StreamsResponse =
+                from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+            assert!(res.streams.iter().all(|s| s.owner == owner));
+            res.streams.into_iter().map(|stream| stream.id).collect()
+        };
+        assert_eq!(
+            list("Alice", None, Some(2)),
+            vec![Uint128::new(1), Uint128::new(3)]
+        );
+        assert_eq!(list("Alice", Some(3), Some(2)), vec![Uint128::new(4)]);
+        assert_eq!(list("Dave", None, None), vec![Uint128::new(2)]);
+
+        // An owner without streams gets an empty page, not an error
+        assert!(list("Erin", None, None).is_empty());
+    }
+
+    #[test]
+    fn preview_top_up() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            start_time + 300,
+        )
+        .unwrap();
+
+        // A third of the way in, 400 are left to vest over 200 seconds
+        env.block.time = env.block.time.plus_seconds(200);
+        let msg = QueryMsg::PreviewTopUp {
+            id: Uint128::new(1),
+            amount: Uint128::new(200),
+        };
+        let preview: TopUpPreviewResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(
+            preview,
+            TopUpPreviewResponse {
+                amount: Uint128::new(500),
+                rate_per_second: Uint128::new(2),
+                rate_per_day: Uint128::new(2 * SECONDS_PER_DAY as u128),
+            }
+        );
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(300));
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: vec![Uint128::new(1)],
+                weights: None,
+                note: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, preview.amount);
+        assert_eq!(stream.rate_per_second, preview.rate_per_second);
+        assert_eq!(stream.rate_per_day, preview.rate_per_day);
+
+        // Top-ups that would fail can't be previewed either
+        env.block.time = env.block.time.plus_seconds(200);
+        let msg = QueryMsg::PreviewTopUp {
+            id: Uint128::new(1),
+            amount: Uint128::new(200),
+        };
+        query(deps.as_ref(), env, msg).unwrap_err();
+    }
+
+    #[test]
+    fn claimable_matches_withdraw() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+
+        let msg = QueryMsg::Claimable {
+            id: Uint128::new(1),
+        };
+        let res: ClaimableResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg.clone()).unwrap()).unwrap();
+        assert_eq!(res.claimable, Uint128::zero());
+        assert_eq!(res.remaining, Uint128::new(1000));
+
+        let mut claimed = Uint128::zero();
+        for elapsed in [137, 250, 613, 1000] {
+            env.block.time = mock_env().block.time.plus_seconds(100 + elapsed);
+            let res: ClaimableResponse =
+                from_binary(&query(deps.as_ref(), env.clone(), msg.clone()).unwrap()).unwrap();
+            assert_eq!(res.claimed, claimed);
+            assert_eq!(res.claimable, Uint128::new(elapsed.into()) - claimed);
+            assert_eq!(res.remaining, Uint128::new(1000) - claimed);
+
+            let withdraw = ExecuteMsg::Withdraw {
+                id: Uint128::new(1),
+                to: None,
+            };
+            let res_withdraw =
+                execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+            let data: WithdrawResult = from_binary(&res_withdraw.data.unwrap()).unwrap();
+            assert_eq!(data.amount, res.claimable);
+            claimed += data.amount;
+        }
+
+        let res: ClaimableResponse = from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!(res.claimable, Uint128::zero());
+        assert_eq!(res.claimed, Uint128::new(1000));
+        assert_eq!(res.remaining, Uint128::zero());
+    }
+
+    #[test]
+    fn auto_close_on_final_withdraw() {
+        for auto_close in [true, false] {
+            let mut deps = mock_dependencies();
+            let msg = InstantiateMsg {
+                owner: None,
+                cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+                event_namespace: None,
+                global_cancellers: vec![],
+                arbiter: None,
+                max_total_streams: None,
+                name_service: None,
+                auto_close_on_final_withdraw: auto_close,
+                time_oracle: None,
+                fee_bps: 0,
+                fee_recipient: None,
+                min_claim_interval_seconds: None,
+            };
+            let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+            instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+            let mut env = mock_env();
+            let start_time = env.block.time.plus_seconds(100).seconds();
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                100,
+                start_time,
+                start_time + 100,
+            )
+            .unwrap();
+            let withdraw = ExecuteMsg::Withdraw {
+                id: Uint128::new(1),
+                to: None,
+            };
+
+            // A partial withdrawal never closes the stream
+            env.block.time = env.block.time.plus_seconds(150);
+            execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw.clone(),
+            )
+            .unwrap();
+            assert_eq!(
+                get_stream(deps.as_ref(), 1).claimed_amount,
+                Uint128::new(50)
+            );
+
+            env.block.time = env.block.time.plus_seconds(100);
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+            let msg = QueryMsg::GetStream {
+                id: Uint128::new(1),
+            };
+            let res = query(deps.as_ref(), env.clone(), msg);
+            let msg = QueryMsg::StreamActions {
+                id: Uint128::new(1),
+                start_after: None,
+                limit: None,
+            };
+            let actions: StreamActionsResponse =
+                from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+            let last = actions.actions.last().unwrap().kind.clone();
+            if auto_close {
+                match res.unwrap_err() {
+                    StdError::NotFound { .. } => {}
+                    e => panic!("unexpected error: {}", e),
+                }
+                assert_eq!(last, StreamActionKind::Close);
+                // The index entries are gone too
+                let msg = QueryMsg::StreamsByRecipient {
+                    recipient: String::from("Bob"),
+                    start_after: None,
+                    limit: None,
+                };
+                let res: StreamsResponse =
+                    from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+                assert!(res.streams.is_empty());
+            } else {
+                let stream: StreamResponse = from_binary(&res.unwrap()).unwrap();
+                assert_eq!(stream.claimed_amount, stream.amount);
+                assert_eq!(last, StreamActionKind::Withdraw);
+            }
+        }
+    }
+
+    #[test]
+    fn pending_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Carol", "Bob"] {
+            let msg = ExecuteMsg::ProposeStream {
+                recipient: String::from(recipient),
+                amount: Uint128::new(200),
+                start_time,
+                end_time: start_time + 200,
+            };
+            execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), msg).unwrap();
+        }
+
+        let pending = |deps: Deps, recipient: Option<&str>, start_after, limit| {
+            let msg = QueryMsg::PendingStreams {
+                recipient: recipient.map(String::from),
+                start_after,
+                limit,
+            };
+            let res: PendingStreamsResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            let ids: Vec<u64> = res.proposals.iter().map(|p| p.id).collect();
+            (ids, res.last_scanned)
+        };
+        assert_eq!(
+            pending(deps.as_ref(), None, None, None),
+            (vec![1, 2, 3], Some(3))
+        );
+        assert_eq!(
+            pending(deps.as_ref(), Some("Bob"), None, None),
+            (vec![1, 3], Some(3))
+        );
+        assert_eq!(
+            pending(deps.as_ref(), None, None, Some(1)),
+            (vec![1], Some(1))
+        );
+        assert_eq!(
+            pending(deps.as_ref(), None, Some(1), Some(1)),
+            (vec![2], Some(2))
+        );
+
+        // Approved proposals are no longer pending
+        let approve = ExecuteMsg::ApproveProposedStream {
+            id: 1,
+            accept_amount: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), approve).unwrap();
+        assert_eq!(
+            pending(deps.as_ref(), Some("Bob"), None, None),
+            (vec![3], Some(3))
+        );
+        assert_eq!(
+            pending(deps.as_ref(), Some("Carol"), None, None),
+            (vec![2], Some(3))
+        );
+        assert_eq!(
+            pending(deps.as_ref(), Some("Dave"), None, None),
+            (vec![], Some(3))
+        );
+    }
+
+    #[test]
+    fn cliff() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = start_time + 1000;
+        let create = |cliff_time: u64| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(1000),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: Some(cliff_time),
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        for cliff_time in [start_time - 1, end_time + 1] {
+            let err =
+                execute(deps.as_mut(), env.clone(), info.clone(), create(cliff_time)).unwrap_err();
+            match err {
+                ContractError::InvalidCliff {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        execute(deps.as_mut(), env.clone(), info, create(start_time + 250)).unwrap();
+
+        let claimable = |deps: Deps, env: Env| -> Uint128 {
+            let msg = QueryMsg::Claimable {
+                id: Uint128::new(1),
+            };
+            let res: ClaimableResponse = from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            res.claimable
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+
+        // Just before the cliff nothing has vested
+        env.block.time = env.block.time.plus_seconds(349);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::zero());
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // At the cliff everything accrued since the start unlocks
+        env.block.time = env.block.time.plus_seconds(1);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::new(250));
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        // Then vesting carries on linearly
+        env.block.time = env.block.time.plus_seconds(350);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::new(350));
+        execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).claimed_amount,
+            Uint128::new(600)
+        );
+    }
+
+    #[test]
+    fn top_up() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let top_up = |sender: &str, id: u128, amount: u128| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from(sender),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::TopUp { id: id.into() }).unwrap(),
+            })
+        };
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let claimable = |deps: Deps, env: Env| -> Uint128 {
+            let msg = QueryMsg::Claimable {
+                id: Uint128::new(1),
+            };
+            let res: ClaimableResponse = from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            res.claimable
+        };
+
+        env.block.time = env.block.time.plus_seconds(500);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            top_up("Carol", 1, 600),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // 400 vested, the new 1200 left vest over the 600 seconds remaining
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            top_up("Alice", 1, 600),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[0], attr("method", "try_top_up"));
+        assert_eq!(res.attributes[1], attr("stream_id", "1"));
+        assert_eq!(res.attributes[3], attr("new_amount", "1600"));
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::new(400));
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).rate_per_second,
+            Uint128::new(2)
+        );
+
+        env.block.time = env.block.time.plus_seconds(300);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::new(1000));
+
+        // Cancelled streams can't be topped up
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            cw20.clone(),
+            top_up("Alice", 2, 600),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::StreamCancelled {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        env.block.time = env.block.time.plus_seconds(300);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), Uint128::new(1600));
+        let err = execute(deps.as_mut(), env, cw20, top_up("Alice", 1, 600)).unwrap_err();
+        match err {
+            ContractError::InvalidTopUp {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn top_up_rates() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let rates = |deps: Deps, id: u128| {
+            let stream = get_stream(deps, id);
+            (stream.rate_per_second.u128(), stream.rate_per_day.u128())
+        };
+        assert_eq!(rates(deps.as_ref(), 1), (1, 86400));
+        assert_eq!(rates(deps.as_ref(), 2), (1, 86400));
+
+        // 400 vested, the 1100 left vest over the 600 seconds remaining. The
+        // daily rate is recomputed from the amount rather than scaled up
+        // from the rounded per-second rate.
+        env.block.time = Timestamp::from_seconds(start_time + 400);
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(500),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env.clone(), cw20.clone(), top_up).unwrap();
+        assert_eq!(rates(deps.as_ref(), 1), (1, 158400));
+
+        // Batch top-ups recompute them the same way
+        let batch = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(1400),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: vec![Uint128::new(2)],
+                weights: None,
+                note: None,
+            })
+            .unwrap(),
+        });
+        execute(deps.as_mut(), env, cw20, batch).unwrap();
+        assert_eq!(rates(deps.as_ref(), 2), (3, 288000));
+        assert_eq!(rates(deps.as_ref(), 1), (1, 158400));
+    }
+
+    #[test]
+    fn time_oracle() {
+        let oracle_time = Rc::new(Cell::new(None));
+        let oracle = oracle_time.clone();
+        let mut deps = mock_dependencies_with_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "clock" => {
+                assert_eq!(msg, &Binary::from(br#"{"current_time":{}}"#));
+                match oracle.get() {
+                    Some(time) => {
+                        let res = format!(r#"{{"time":{}}}"#, time);
+                        SystemResult::Ok(ContractResult::Ok(Binary::from(res.as_bytes())))
+                    }
+                    None => SystemResult::Ok(ContractResult::Err(String::from("stopped"))),
+                }
+            }
+            _ => SystemResult::Ok(ContractResult::Err(String::from("unsupported"))),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: Some(TimeOracleSpec {
+                contract: String::from("clock"),
+                max_drift: 500,
+            }),
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // The block time never moves, only the oracle's
+        let env = mock_env();
+        let now = env.block.time.seconds();
+        oracle_time.set(Some(now));
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            now + 100,
+            now + 1100,
+        )
+        .unwrap();
+        let claimable = |deps: Deps| -> Uint128 {
+            let msg = QueryMsg::Claimable {
+                id: Uint128::new(1),
+            };
+            let res: ClaimableResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.claimable
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+
+        oracle_time.set(Some(now + 400));
+        assert_eq!(claimable(deps.as_ref()), Uint128::new(300));
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        let data: WithdrawResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.amount, Uint128::new(300));
+
+        // Drift beyond the bound is clamped
+        oracle_time.set(Some(now + 10_000));
+        assert_eq!(claimable(deps.as_ref()), Uint128::new(100));
+
+        // Without an answer the block time is used, at which nothing vested
+        oracle_time.set(None);
+        assert_eq!(claimable(deps.as_ref()), Uint128::zero());
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap_err();
+        match err {
+            ContractError::StreamNotStarted {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Stepping back behind a freeze unfreezes without shifting the stream
+        oracle_time.set(Some(now + 400));
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            ExecuteMsg::FreezeStream {
+                id: Uint128::new(1),
+            },
+        )
+        .unwrap();
+        oracle_time.set(Some(now + 100));
+        execute(
+            deps.as_mut(),
+            env,
+            mock_info("Alice", &[]),
+            ExecuteMsg::UnfreezeStream {
+                id: Uint128::new(1),
+            },
+        )
+        .unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.frozen_at, None);
+        assert_eq!(
+            (stream.start_time, stream.end_time),
+            (now + 100, now + 1100)
+        );
+    }
+
+    #[cfg(feature = "flash-withdraw")]
+    #[test]
+    fn pre_claim() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let pre_claim = |amount: u128| ExecuteMsg::PreClaim {
+            id: Uint128::new(1),
+            amount: Uint128::new(amount),
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            pre_claim(100),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::LiquidityProviderNotSet {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Only the contract owner picks the liquidity provider
+        let set_provider = ExecuteMsg::SetLiquidityProvider {
+            address: Some(String::from("Lender")),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            set_provider.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, set_provider).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            pre_claim(100),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // 200 vested, so no more than the other 800 can be borrowed
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            pre_claim(801),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidPreClaimAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The advance is drawn from the provider's allowance
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            pre_claim(500),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::TransferFrom {
+                    owner: String::from("Lender"),
+                    recipient: String::from("Bob"),
+                    amount: Uint128::new(500),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+
+        // Withdrawals repay the provider first
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].msg, transfer("Lender", 200));
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).encumbrance,
+            Some(Encumbrance {
+                lender: Addr::unchecked("Lender"),
+                outstanding: Uint128::new(300),
+            })
+        );
+
+        env.block.time = env.block.time.plus_seconds(400);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Lender", 300));
+        assert_eq!(res.messages[1].msg, transfer("Bob", 100));
+
+        // Once cleared the stream is released and pays Bob again
+        assert_eq!(get_stream(deps.as_ref(), 1).encumbrance, None);
+        env.block.time = env.block.time.plus_seconds(100);
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].msg, transfer("Bob", 100));
+    }
+
+    #[test]
+    fn transfer_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let transfer = ExecuteMsg::TransferRecipient {
+            id: Uint128::new(1),
+            new_recipient: String::from("Carol"),
+        };
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let streams_of = |deps: Deps, recipient: &str| -> Vec<Uint128> {
+            let msg = QueryMsg::StreamsByRecipient {
+                recipient: String::from(recipient),
+                start_after: None,
+                limit: None,
+            };
+            let res: StreamsResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.streams.into_iter().map(|stream| stream.id).collect()
+        };
+
+        // Only the current recipient can hand the stream over
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            transfer.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = ExecuteMsg::TransferRecipient {
+            id: Uint128::new(1),
+            new_recipient: String::from(MOCK_CONTRACT_ADDR),
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap_err();
+        match err {
+            ContractError::InvalidRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Bob's payout settings don't carry over to Carol
+        for msg in [
+            ExecuteMsg::SetFallbackRecipient {
+                id: Uint128::new(1),
+                fallback_recipient: Some(String::from("Dave")),
+                note: None,
+            },
+            ExecuteMsg::SetAllowedDestinations {
+                id: Uint128::new(1),
+                destinations: Some(vec![String::from("Bob")]),
+            },
+            ExecuteMsg::SetBeneficiary {
+                id: Uint128::new(1),
+                beneficiary: Some(String::from("Dave")),
+                inactivity_seconds: 1000,
+            },
+        ] {
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        }
+
+        env.block.time = env.block.time.plus_seconds(300);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            transfer.clone(),
+        )
+        .unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.recipient, String::from("Carol"));
+        assert_eq!(stream.fallback_recipient, None);
+        assert_eq!(stream.allowed_destinations, None);
+        assert_eq!(stream.beneficiary, None);
+        assert!(streams_of(deps.as_ref(), "Bob").is_empty());
+        assert_eq!(streams_of(deps.as_ref(), "Carol"), vec![Uint128::new(1)]);
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            withdraw,
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Carol"),
+                    amount: Uint128::new(200),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+
+        // Bob no longer has any say over the stream
+        let err = execute(deps.as_mut(), env, mock_info("Bob", &[]), transfer).unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn native_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let create = ExecuteMsg::CreateNativeStream {
+            recipient: String::from("Bob"),
+            start_time,
+            end_time: start_time + 1000,
+            allow_fee_recipient: None,
+        };
+
+        // Exactly one coin has to be sent
+        let two_coins = [coin(1000, "ujuno"), coin(1000, "uatom")];
+        for funds in [&[][..], &two_coins, &coins(0, "ujuno")] {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Alice", funds),
+                create.clone(),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::InvalidNativeFunds {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        let info = mock_info("Alice", &coins(1000, "ujuno"));
+        let res = execute(deps.as_mut(), env.clone(), info, create).unwrap();
+        let data: CreateResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.id, Uint128::new(1));
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.owner, String::from("Alice"));
+        assert_eq!(stream.amount, Uint128::new(1000));
+        assert_eq!(stream.denom, Some(String::from("ujuno")));
+
+        // Withdrawals are paid through the bank module
+        env.block.time = env.block.time.plus_seconds(400);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: String::from("Bob"),
+                amount: coins(300, "ujuno"),
+            })
+        );
+
+        // cw20 can't top up a native stream
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, top_up).unwrap_err();
+        match err {
+            ContractError::DenomMismatch {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Cancelling refunds the owner in the same denom
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        let res = execute(deps.as_mut(), env, mock_info("Alice", &[]), cancel).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: String::from("Alice"),
+                amount: coins(700, "ujuno"),
+            })
+        );
+    }
+
+    #[test]
+    fn multiple_tokens() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let receive = |msg: ReceiveMsg| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(1000),
+                msg: to_binary(&msg).unwrap(),
+            })
+        };
+        let create = receive(ReceiveMsg::CreateStream {
+            recipient: String::from("Bob"),
+            start_time,
+            end_time: start_time + 1000,
+            recipient_kind: None,
+            recipient_controller: None,
+            condition: None,
+            recipient_is_name: None,
+            amount: None,
+            rounding_unit: None,
+            cliff_time: None,
+            unlock_schedule: None,
+            rebasing: None,
+            allow_fee_recipient: None,
+        });
+
+        // Only the instantiation token is accepted at first
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("other_token", &[]),
+            create.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let add = ExecuteMsg::AddAllowedToken {
+            token: String::from("other_token"),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            add.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, add).unwrap();
+
+        // Each stream remembers the token it was created with
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, create.clone()).unwrap();
+        let info = mock_info("other_token", &[]);
+        execute(deps.as_mut(), env.clone(), info, create.clone()).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).cw20_addr,
+            Some(String::from(MOCK_CONTRACT_ADDR))
+        );
+        assert_eq!(
+            get_stream(deps.as_ref(), 2).cw20_addr,
+            Some(String::from("other_token"))
+        );
+
+        // A stream can only be topped up with its own token
+        let top_up = receive(ReceiveMsg::TopUp {
+            id: Uint128::new(2),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, top_up).unwrap_err();
+        match err {
+            ContractError::DenomMismatch {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Removing a token stops new streams but existing ones still pay out
+        let remove = ExecuteMsg::RemoveAllowedToken {
+            token: String::from("other_token"),
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, remove).unwrap();
+        let info = mock_info("other_token", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, create).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        env.block.time = env.block.time.plus_seconds(400);
+        for (id, token) in [(1, MOCK_CONTRACT_ADDR), (2, "other_token")] {
+            let withdraw = ExecuteMsg::Withdraw {
+                id: Uint128::new(id),
+                to: None,
+            };
+            let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+            assert_eq!(
+                res.messages[0].msg,
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: String::from(token),
+                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: String::from("Bob"),
+                        amount: Uint128::new(300),
+                    })
+                    .unwrap(),
+                    funds: vec![],
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn migrate_from_v0_1() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let start_time = env.block.time.seconds() + 100;
+
+        // State as 0.1.0 left it
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.1.0").unwrap();
+        let legacy_config = LegacyConfig {
+            owner: Addr::unchecked("admin"),
+            cw20_addr: Addr::unchecked(MOCK_CONTRACT_ADDR),
+        };
+        LEGACY_CONFIG
+            .save(&mut deps.storage, &legacy_config)
+            .unwrap();
+        let legacy_stream = LegacyStream {
+            owner: Addr::unchecked("Alice"),
+            recipient: Addr::unchecked("Bob"),
+            amount: Uint128::new(1000),
+            claimed_amount: Uint128::zero(),
+            start_time,
+            end_time: start_time + 1000,
+            rate_per_second: Uint128::new(1),
+        };
+        LEGACY_STREAMS
+            .save(&mut deps.storage, 1u128.into(), &legacy_stream)
+            .unwrap();
+        STREAM_SEQ
+            .save(&mut deps.storage, &Uint128::new(1))
+            .unwrap();
+
+        migrate(deps.as_mut(), env.clone(), MigrateMsg {}).unwrap();
+        let version = get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.version, CONTRACT_VERSION);
+
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.owner, String::from("Alice"));
+        assert_eq!(stream.amount, Uint128::new(1000));
+        assert_eq!(stream.rate_per_day, Uint128::new(86400));
+        assert_eq!(stream.checkpoint_time, start_time);
+        assert!(!stream.cancelled);
+        assert_eq!(stream.cw20_addr, Some(String::from(MOCK_CONTRACT_ADDR)));
+
+        // The stream is indexed and behaves like one created after the upgrade
+        let msg = QueryMsg::StreamsByOwner {
+            owner: String::from("Alice"),
+            start_after: None,
+            limit: None,
+        };
+        let res: StreamsResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.streams.len(), 1);
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Carol",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 2).recipient,
+            String::from("Carol")
+        );
+
+        let mut env = env;
+        env.block.time = env.block.time.plus_seconds(400);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let data: WithdrawResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.amount, Uint128::new(300));
+
+        // Migrating again at the same version leaves the state alone
+        migrate(deps.as_mut(), env.clone(), MigrateMsg {}).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).claimed_amount,
+            Uint128::new(300)
+        );
+
+        // Downgrades and other contracts are refused
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "9.0.0").unwrap();
+        let err = migrate(deps.as_mut(), env.clone(), MigrateMsg {}).unwrap_err();
+        match err {
+            ContractError::CannotDowngrade {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        set_contract_version(&mut deps.storage, "crates.io:cw20-base", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), env, MigrateMsg {}).unwrap_err();
+        match err {
+            ContractError::WrongContract {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn assign_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let assign = |new_owner: &str, new_recipient: &str| ExecuteMsg::AssignStream {
+            id: Uint128::new(1),
+            new_owner: String::from(new_owner),
+            new_recipient: String::from(new_recipient),
+        };
+        let status = |res: Response| {
+            res.attributes
+                .into_iter()
+                .find(|attr| attr.key == "status")
+                .unwrap()
+                .value
+        };
+        let ids = |deps: Deps, msg: QueryMsg| -> Vec<Uint128> {
+            let res: StreamsResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.streams.into_iter().map(|stream| stream.id).collect()
+        };
+        let by_owner = |owner: &str| QueryMsg::StreamsByOwner {
+            owner: String::from(owner),
+            start_after: None,
+            limit: None,
+        };
+        let by_recipient = |recipient: &str| QueryMsg::StreamsByRecipient {
+            recipient: String::from(recipient),
+            start_after: None,
+            limit: None,
+        };
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            assign("Carol", "Carol"),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            assign("Dave", MOCK_CONTRACT_ADDR),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Bob's payout settings don't carry over to the new recipient
+        for msg in [
+            ExecuteMsg::SetFallbackRecipient {
+                id: Uint128::new(1),
+                fallback_recipient: Some(String::from("Gina")),
+                note: None,
+            },
+            ExecuteMsg::SetAllowedDestinations {
+                id: Uint128::new(1),
+                destinations: Some(vec![String::from("Bob")]),
+            },
+            ExecuteMsg::SetBeneficiary {
+                id: Uint128::new(1),
+                beneficiary: Some(String::from("Gina")),
+                inactivity_seconds: 1000,
+            },
+        ] {
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        }
+
+        // Nothing changes until both parties agree on the same terms
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            assign("Dave", "Erin"),
+        )
+        .unwrap();
+        assert_eq!(status(res), "pending");
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            assign("Dave", "Frank"),
+        )
+        .unwrap();
+        assert_eq!(status(res), "pending");
+        assert_eq!(get_stream(deps.as_ref(), 1).owner, String::from("Alice"));
+
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            assign("Dave", "Frank"),
+        )
+        .unwrap();
+        assert_eq!(status(res), "assigned");
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.owner, String::from("Dave"));
+        assert_eq!(stream.recipient, String::from("Frank"));
+        assert_eq!(stream.fallback_recipient, None);
+        assert_eq!(stream.allowed_destinations, None);
+        assert_eq!(stream.beneficiary, None);
+        assert!(ids(deps.as_ref(), by_owner("Alice")).is_empty());
+        assert!(ids(deps.as_ref(), by_recipient("Bob")).is_empty());
+        assert_eq!(ids(deps.as_ref(), by_owner("Dave")), vec![Uint128::new(1)]);
+        assert_eq!(
+            ids(deps.as_ref(), by_recipient("Frank")),
+            vec![Uint128::new(1)]
+        );
+
+        // The previous parties lose access, the new ones take it over
+        env.block.time = env.block.time.plus_seconds(300);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Frank", &[]),
+            withdraw,
+        )
+        .unwrap();
+
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            cancel.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env, mock_info("Dave", &[]), cancel).unwrap();
+    }
+
+    #[test]
+    fn allowed_destinations() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let set_destinations =
+            |destinations: Option<Vec<&str>>| ExecuteMsg::SetAllowedDestinations {
+                id: Uint128::new(1),
+                destinations: destinations
+                    .map(|destinations| destinations.into_iter().map(String::from).collect()),
+            };
+        let withdraw = |to: Option<&str>| ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: to.map(String::from),
+        };
+        let transfer = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            set_destinations(Some(vec!["Cold"])),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            set_destinations(Some(vec!["Cold"])),
+        )
+        .unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).allowed_destinations,
+            Some(vec![String::from("Cold")])
+        );
+
+        // Neither another address nor the recipient itself is allowed now
+        env.block.time = env.block.time.plus_seconds(300);
+        for to in [Some("Hot"), None] {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw(to),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::DestinationNotAllowed {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw(Some("Cold")),
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Cold", 200));
+
+        // Only the recipient chooses the destination
+        env.block.time = env.block.time.plus_seconds(100);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            withdraw(Some("Cold")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Lifting the restriction allows any destination again
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            set_destinations(None),
+        )
+        .unwrap();
+        let res = execute(
+            deps.as_mut(),
+            env,
+            mock_info("Bob", &[]),
+            withdraw(Some("Hot")),
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Hot", 100));
+    }
+
+    #[test]
+    fn update_config() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let update = ExecuteMsg::UpdateConfig {
+            new_owner: Some(String::from("Admin")),
+            new_cw20_addr: Some(String::from("new_token")),
+            new_fee_bps: None,
+            new_fee_recipient: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("Alice", &[]),
+            update.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, update.clone()).unwrap();
+        assert_eq!(
+            res.attributes,
+            vec![
+                attr("method", "try_update_config"),
+                attr("owner", "Admin"),
+                attr("cw20_addr", "new_token"),
+                attr("fee_bps", "0"),
+                attr("fee_recipient", MOCK_CONTRACT_ADDR),
+            ]
+        );
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_binary(&res).unwrap();
+        assert_eq!(config.owner, String::from("Admin"));
+        assert_eq!(config.cw20_addr, String::from("new_token"));
+
+        // The previous owner has lost its rights
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, update).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The new token is accepted for streams
+        let create = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::CreateStream {
+                recipient: String::from("Bob"),
+                start_time: mock_env().block.time.plus_seconds(100).seconds(),
+                end_time: mock_env().block.time.plus_seconds(1100).seconds(),
+                recipient_kind: None,
+                recipient_controller: None,
+                condition: None,
+                recipient_is_name: None,
+                amount: None,
+                rounding_unit: None,
+                cliff_time: None,
+                unlock_schedule: None,
+                rebasing: None,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info("new_token", &[]);
+        execute(deps.as_mut(), mock_env(), info, create).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).cw20_addr,
+            Some(String::from("new_token"))
+        );
+    }
+
+    #[test]
+    fn stuck_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Carol"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let msg = ExecuteMsg::SetFallbackRecipient {
+            id: Uint128::new(1),
+            fallback_recipient: Some(String::from("Dave")),
+            note: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+
+        let stuck = |deps: Deps, min_failures| -> StuckStreamsResponse {
+            let msg = QueryMsg::StuckStreams {
+                min_failures,
+                start_after: None,
+                limit: None,
+            };
+            from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap()
+        };
+        let mut withdraw = |env: Env, result| {
+            let msg = ExecuteMsg::Withdraw {
+                id: Uint128::new(1),
+                to: None,
+            };
+            execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+            let msg = Reply {
+                id: WITHDRAW_REPLY_ID,
+                result,
+            };
+            reply(deps.as_mut(), env, msg).unwrap();
+        };
+
+        // Every transfer to Bob is rejected by the token
+        env.block.time = env.block.time.plus_seconds(100);
+        for _ in 0..3 {
+            env.block.time = env.block.time.plus_seconds(100);
+            withdraw(
+                env.clone(),
+                ContractResult::Err(String::from("recipient is blocked")),
+            );
+        }
+        assert_eq!(get_stream(deps.as_ref(), 1).failed_withdraw_count, 3);
+        assert_eq!(
+            stuck(deps.as_ref(), 3),
+            StuckStreamsResponse {
+                streams: vec![StuckStream {
+                    id: Uint128::new(1),
+                    recipient: String::from("Bob"),
+                    failed_withdraw_count: 3,
+                }],
+                last_scanned: Some(Uint128::new(2)),
+            }
+        );
+        assert!(stuck(deps.as_ref(), 4).streams.is_empty());
+        assert_eq!(stuck(deps.as_ref(), 0).streams.len(), 1);
+
+        // A transfer that goes through clears the count
+        env.block.time = env.block.time.plus_seconds(100);
+        let msg = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
+        let msg = Reply {
+            id: WITHDRAW_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), env, msg).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).failed_withdraw_count, 0);
+        assert!(stuck(deps.as_ref(), 1).streams.is_empty());
+    }
+
+    #[test]
+    fn query_msg_docs() {
+        // Each query carries its own doc in the generated schema
+        let schema = schemars::schema_for!(QueryMsg);
+        let description = |variant: &str| -> String {
+            let variants = schema.schema.subschemas.as_ref().unwrap().one_of.as_ref();
+            variants
+                .unwrap()
+                .iter()
+                .find_map(|schema| match schema {
+                    Schema::Object(object)
+                        if object.object.as_ref()?.required.contains(variant) =>
+                    {
+                        object.metadata.as_ref()?.description.clone()
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        assert!(description("stuck_streams").starts_with("Streams whose last `min_failures`"));
+        assert!(!description("stuck_streams").contains("Tokens paid"));
+        assert!(description("recipient_tax_report").starts_with("Tokens paid to `recipient`"));
+    }
+
+    #[test]
+    fn creation_fee() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 10001,
+            fee_recipient: Some(String::from("Treasury")),
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = instantiate(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        match err {
+            ContractError::InvalidFee {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let msg = InstantiateMsg {
+            fee_bps: 250,
+            ..msg
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // 2.5% of the deposit goes to the fee pool, the rest is streamed
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let res = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            40000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        let pooled = FEE_POOL.load(&deps.storage, &token).unwrap();
+        assert_eq!(pooled, Uint128::new(1000));
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.amount, Uint128::new(39000));
+        assert_eq!(stream.rate_per_second, Uint128::new(39));
+
+        // Native coins have no pool, their fee is paid out straight away
+        let create = ExecuteMsg::CreateNativeStream {
+            recipient: String::from("Bob"),
+            start_time,
+            end_time: start_time + 1000,
+            allow_fee_recipient: None,
+        };
+        let info = mock_info("Alice", &coins(40000, "ujuno"));
+        let res = execute(deps.as_mut(), env, info, create).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: String::from("Treasury"),
+                amount: coins(1000, "ujuno"),
+            })
+        );
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.amount, Uint128::new(39000));
+
+        // What is left after the fee vests pro rata, even when it falls short
+        // of a token per second
+        let mut env = mock_env();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 3).amount, Uint128::new(975));
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(3),
+            to: None,
+        };
+        for (elapsed, paid) in [(500, 487), (1000, 488)] {
+            env.block.time = mock_env().block.time.plus_seconds(100 + elapsed);
+            let res = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Bob", &[]),
+                withdraw.clone(),
+            )
+            .unwrap();
+            assert_eq!(
+                res.messages[0].msg,
+                transfer_msg(
+                    Some(&Addr::unchecked(MOCK_CONTRACT_ADDR)),
+                    None,
+                    "Bob",
+                    Uint128::new(paid)
+                )
+                .unwrap()
+            );
+        }
+
+        // The fee can't be raised past the whole deposit later either
+        let update = ExecuteMsg::UpdateConfig {
+            new_owner: None,
+            new_cw20_addr: None,
+            new_fee_bps: Some(10001),
+            new_fee_recipient: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, update).unwrap_err();
+        match err {
+            ContractError::InvalidFee {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fee_recipient_as_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 250,
+            fee_recipient: Some(String::from("Treasury")),
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let create = |allow_fee_recipient: Option<bool>| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(40000),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Treasury"),
+                    start_time,
+                    end_time: start_time + 1000,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient,
+                })
+                .unwrap(),
+            })
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+
+        // Streaming to the fee recipient has to be asked for
+        for allow_fee_recipient in [None, Some(false)] {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                info.clone(),
+                create(allow_fee_recipient),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::RecipientIsFeeRecipient {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        let installments = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(40000),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Treasury"),
+                num_installments: 4,
+                interval_seconds: 100,
+                start_time,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), installments).unwrap_err();
+        match err {
+            ContractError::RecipientIsFeeRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // With the override the fee is still taken
+        execute(deps.as_mut(), env.clone(), info.clone(), create(Some(true))).unwrap();
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        let pooled = FEE_POOL.load(&deps.storage, &token).unwrap();
+        assert_eq!(pooled, Uint128::new(1000));
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.recipient, String::from("Treasury"));
+        assert_eq!(stream.amount, Uint128::new(39000));
+
+        // Without a fee there is nothing to guard
+        let update = ExecuteMsg::UpdateConfig {
+            new_owner: None,
+            new_cw20_addr: None,
+            new_fee_bps: Some(0),
+            new_fee_recipient: None,
+        };
+        execute(deps.as_mut(), env.clone(), info.clone(), update).unwrap();
+        execute(deps.as_mut(), env, info, create(None)).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(40000));
+    }
+
+    #[test]
+    fn fee_dust() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 250,
+            fee_recipient: Some(String::from("Treasury")),
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let set_policy = |deps: DepsMut, policy: DustPolicy| {
+            let msg = ExecuteMsg::SetDustPolicy { policy };
+            execute(deps, mock_env(), mock_info(MOCK_CONTRACT_ADDR, &[]), msg).unwrap();
+        };
+        let fee_pool = |deps: Deps| -> Uint128 {
+            let msg = QueryMsg::FeePool {
+                token: String::from(MOCK_CONTRACT_ADDR),
+            };
+            let res: FeePoolResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.amount
+        };
+        let create = |deps: DepsMut| {
+            create_stream(
+                deps,
+                env.clone(),
+                "Alice",
+                "Bob",
+                30,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap()
+        };
+
+        // A fee of 0.75 tokens is dropped by default
+        create(deps.as_mut());
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(30));
+        assert_eq!(fee_pool(deps.as_ref()), Uint128::zero());
+
+        // Or charged as a whole token
+        set_policy(deps.as_mut(), DustPolicy::RoundUpToOne);
+        create(deps.as_mut());
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(29));
+        assert_eq!(fee_pool(deps.as_ref()), Uint128::new(1));
+
+        // Or added up, charging a token to every stream that completes one
+        set_policy(deps.as_mut(), DustPolicy::AccumulateInternally);
+        for (id, streamed) in [(3, 30), (4, 29), (5, 29), (6, 29)] {
+            create(deps.as_mut());
+            assert_eq!(get_stream(deps.as_ref(), id).amount, Uint128::new(streamed));
+        }
+        assert_eq!(fee_pool(deps.as_ref()), Uint128::new(4));
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        assert_eq!(
+            FEE_DUST.load(&deps.storage, &token).unwrap(),
+            Uint128::zero()
+        );
+
+        // The fraction left over stays counted
+        create(deps.as_mut());
+        assert_eq!(get_stream(deps.as_ref(), 7).amount, Uint128::new(30));
+        assert_eq!(fee_pool(deps.as_ref()), Uint128::new(4));
+        assert_eq!(
+            FEE_DUST.load(&deps.storage, &token).unwrap(),
+            Uint128::new(7500)
+        );
+    }
+
+    #[test]
+    fn fee_rounding() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 333,
+            fee_recipient: Some(String::from("Treasury")),
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+
+        // 3.33% of each deposit is a fraction of a token, which is left to
+        // the recipient, and the fee and stream add up to the deposit
+        let mut pooled = Uint128::zero();
+        let mut id = 0;
+        for (deposit, fee) in [(1000, 33), (1001, 33), (2999, 99), (12345, 411)] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                deposit,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+            id += 1;
+            let streamed = get_stream(deps.as_ref(), id).amount;
+            let charged = FEE_POOL.load(&deps.storage, &token).unwrap() - pooled;
+            pooled += charged;
+            assert_eq!(charged, Uint128::new(fee));
+            assert_eq!(streamed + charged, Uint128::new(deposit));
+        }
+
+        // The same for installment streams
+        let installments = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(2999),
+            msg: to_binary(&ReceiveMsg::CreateInstallmentStream {
+                recipient: String::from("Bob"),
+                num_installments: 2,
+                interval_seconds: 100,
+                start_time,
+                allow_fee_recipient: None,
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, installments).unwrap();
+        let streamed = get_stream(deps.as_ref(), 5).amount;
+        let charged = FEE_POOL.load(&deps.storage, &token).unwrap() - pooled;
+        assert_eq!(charged, Uint128::new(99));
+        assert_eq!(streamed + charged, Uint128::new(2999));
+
+        // And for native coins, whose fee is paid out
+        let create = ExecuteMsg::CreateNativeStream {
+            recipient: String::from("Bob"),
+            start_time,
+            end_time: start_time + 1000,
+            allow_fee_recipient: None,
+        };
+        let info = mock_info("Alice", &coins(2999, "ujuno"));
+        let res = execute(deps.as_mut(), env, info, create).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: String::from("Treasury"),
+                amount: coins(99, "ujuno"),
+            })
+        );
+        assert_eq!(get_stream(deps.as_ref(), 6).amount, Uint128::new(2900));
+    }
+
+    #[test]
+    fn fee_pool() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from("token_a"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 100,
+            fee_recipient: Some(String::from("Treasury")),
+            min_claim_interval_seconds: None,
+        };
+        let owner = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let allow = ExecuteMsg::AddAllowedToken {
+            token: String::from("token_b"),
+        };
+        execute(deps.as_mut(), mock_env(), owner.clone(), allow).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let create = |amount: u128| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time: start_time + 1000,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+        let fee_pool = |deps: Deps, token: &str| -> Uint128 {
+            let msg = QueryMsg::FeePool {
+                token: String::from(token),
+            };
+            let res: FeePoolResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.amount
+        };
+
+        // Fees are held per token rather than paid out on creation
+        for (token, amount) in [("token_a", 1000), ("token_a", 2000), ("token_b", 5000)] {
+            let info = mock_info(token, &[]);
+            let res = execute(deps.as_mut(), env.clone(), info, create(amount)).unwrap();
+            assert!(res.messages.is_empty());
+        }
+        assert_eq!(fee_pool(deps.as_ref(), "token_a"), Uint128::new(30));
+        assert_eq!(fee_pool(deps.as_ref(), "token_b"), Uint128::new(50));
+
+        // Only the contract owner can sweep them
+        let sweep = |token: &str, to: Option<&str>| ExecuteMsg::SweepFeePool {
+            token: String::from(token),
+            to: to.map(String::from),
+        };
+        let info = mock_info("Alice", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, sweep("token_a", None)).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // To the fee recipient by default, and one token at a time
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            owner.clone(),
+            sweep("token_a", None),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(
+                Some(&Addr::unchecked("token_a")),
+                None,
+                "Treasury",
+                Uint128::new(30)
+            )
+            .unwrap()
+        );
+        assert_eq!(fee_pool(deps.as_ref(), "token_a"), Uint128::zero());
+        assert_eq!(fee_pool(deps.as_ref(), "token_b"), Uint128::new(50));
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            owner.clone(),
+            sweep("token_a", None),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ZeroAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let res = execute(deps.as_mut(), env, owner, sweep("token_b", Some("Ops"))).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(
+                Some(&Addr::unchecked("token_b")),
+                None,
+                "Ops",
+                Uint128::new(50)
+            )
+            .unwrap()
+        );
+        assert_eq!(fee_pool(deps.as_ref(), "token_b"), Uint128::zero());
+    }
+
+    #[test]
+    fn claim_cooldown() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: Some(100),
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+
+        // The first withdrawal isn't held back
+        env.block.time = env.block.time.plus_seconds(300);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        env.block.time = env.block.time.plus_seconds(50);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ClaimCooldown {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        env.block.time = env.block.time.plus_seconds(50);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap();
+
+        // A top-up halfway through the cooldown restarts it
+        env.block.time = env.block.time.plus_seconds(50);
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(650),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, top_up).unwrap();
+        assert_eq!(
+            get_stream(deps.as_ref(), 1).last_claim_time,
+            Some(env.block.time.seconds())
+        );
+
+        env.block.time = env.block.time.plus_seconds(50);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ClaimCooldown {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        env.block.time = env.block.time.plus_seconds(50);
+        let res = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw).unwrap();
+        // 50 vested after the last withdrawal until the top-up, then 1300
+        // over the remaining 650 seconds
+        assert_eq!(res.attributes[2].value, "250");
+    }
+
+    #[test]
+    fn stream_groups() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for recipient in ["Bob", "Carol", "Dave"] {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let add = |id, group: &str| ExecuteMsg::AddToGroup {
+            id: Uint128::new(id),
+            group: String::from(group),
+        };
+        let in_group = |deps: Deps, group: &str, start_after: Option<u128>, limit| -> Vec<u128> {
+            let msg = QueryMsg::StreamsInGroup {
+                group: String::from(group),
+                start_after: start_after.map(Uint128::new),
+                limit,
+            };
+            let res: StreamsResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.streams.iter().map(|stream| stream.id.u128()).collect()
+        };
+
+        // Only the stream owner can tag it, with a bounded name
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            add(1, "2024-hires"),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        for group in [String::new(), "a".repeat(65)] {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                mock_info("Alice", &[]),
+                add(1, &group),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::InvalidGroupName {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        for (id, group) in [(1, "2024-hires"), (3, "2024-hires"), (2, "advisors")] {
+            let info = mock_info("Alice", &[]);
+            execute(deps.as_mut(), env.clone(), info, add(id, group)).unwrap();
+        }
+        assert_eq!(
+            in_group(deps.as_ref(), "2024-hires", None, None),
+            vec![1, 3]
+        );
+        assert_eq!(
+            in_group(deps.as_ref(), "2024-hires", None, Some(1)),
+            vec![1]
+        );
+        assert_eq!(
+            in_group(deps.as_ref(), "2024-hires", Some(1), None),
+            vec![3]
+        );
+        assert_eq!(in_group(deps.as_ref(), "advisors", None, None), vec![2]);
+        assert!(in_group(deps.as_ref(), "2023-hires", None, None).is_empty());
+
+        let remove = ExecuteMsg::RemoveFromGroup {
+            id: Uint128::new(1),
+            group: String::from("2024-hires"),
+        };
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            remove.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamOwner {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env, mock_info("Alice", &[]), remove).unwrap();
+        assert_eq!(in_group(deps.as_ref(), "2024-hires", None, None), vec![3]);
+    }
+
+    #[test]
+    fn withdraw_all() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..3 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        // Not started yet by the time of the withdrawal
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time + 1000,
+            start_time + 2000,
+        )
+        .unwrap();
+        let withdraw_all = ExecuteMsg::WithdrawAll {
+            ids: (1..=4).map(Uint128::new).collect(),
+        };
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Carol", &[]),
+            withdraw_all.clone(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // One transfer pays what vested on all three running streams
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw_all.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Bob"),
+                    amount: Uint128::new(600),
+                })
+                .unwrap(),
+                funds: vec![],
+            }))]
+        );
+        for id in 1..=3 {
+            assert_eq!(
+                get_stream(deps.as_ref(), id).claimed_amount,
+                Uint128::new(200)
+            );
+        }
+        assert!(get_stream(deps.as_ref(), 4).claimed_amount.is_zero());
+
+        // Nothing left to claim in the same block is not an error
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw_all,
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+
+        let withdraw_all = ExecuteMsg::WithdrawAll {
+            ids: (1..=MAX_BATCH_SIZE as u128 + 1).map(Uint128::new).collect(),
+        };
+        let err = execute(deps.as_mut(), env, mock_info("Bob", &[]), withdraw_all).unwrap_err();
+        match err {
+            ContractError::BatchTooLarge {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn withdraw_all_own_streams() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Bob's first stream is drained before the batch and the third is
+        // cancelled, the second goes to Carol, then come one more of Bob's
+        // than fit in a batch
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let mut recipients = vec![
+            ("Bob", 100, start_time + 100),
+            ("Carol", 1000, start_time + 1000),
+        ];
+        recipients.extend((0..MAX_BATCH_SIZE + 2).map(|_| ("Bob", 1000, start_time + 1000)));
+        for (recipient, amount, end_time) in recipients {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                recipient,
+                amount,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+        }
+        env.block.time = Timestamp::from_seconds(start_time + 300);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let soft_cancel = ExecuteMsg::SoftCancel {
+            id: Uint128::new(3),
+        };
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Alice", &[]),
+            soft_cancel,
+        )
+        .unwrap();
+
+        // No ids withdraws from the first 30 of Bob's streams with funds left
+        let withdraw_all = |ids: Vec<u128>| ExecuteMsg::WithdrawAll {
+            ids: ids.into_iter().map(Uint128::new).collect(),
+        };
+        let bob = mock_info("Bob", &[]);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            bob.clone(),
+            withdraw_all(vec![]),
+        )
+        .unwrap();
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(
+                transfer_msg(Some(&token), None, "Bob", Uint128::new(9000)).unwrap()
+            )]
+        );
+        let last = MAX_BATCH_SIZE as u128 + 4;
+        for id in 4..last {
+            assert_eq!(
+                get_stream(deps.as_ref(), id).claimed_amount,
+                Uint128::new(300)
+            );
+        }
+        assert!(get_stream(deps.as_ref(), 2).claimed_amount.is_zero());
+        assert!(get_stream(deps.as_ref(), last).claimed_amount.is_zero());
+
+        // A pending cancellation has nothing to withdraw, it doesn't fail
+        // the batch
+        let res = execute(deps.as_mut(), env, bob, withdraw_all(vec![3, 4])).unwrap();
+        assert!(res.messages.is_empty());
+    }
+
+    #[test]
+    fn partially_accept_proposal() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let propose = ExecuteMsg::ProposeStream {
+            recipient: String::from("Bob"),
+            amount: Uint128::new(2000),
+            start_time,
+            end_time: start_time + 1000,
+        };
+        let approve = |id, accept_amount: Option<u128>| ExecuteMsg::ApproveProposedStream {
+            id,
+            accept_amount: accept_amount.map(Uint128::new),
+        };
+        let fund = |amount: u128, id: u64| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::FundProposal { id }).unwrap(),
+            })
+        };
+        let alice = mock_info("Alice", &[]);
+        let bob = mock_info("Bob", &[]);
+        let cw20 = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        for _ in 0..3 {
+            execute(deps.as_mut(), env.clone(), alice.clone(), propose.clone()).unwrap();
+        }
+
+        // Nothing, or more than was proposed, can't be accepted
+        for accept_amount in [0, 2001] {
+            let err = execute(
+                deps.as_mut(),
+                env.clone(),
+                bob.clone(),
+                approve(1, Some(accept_amount)),
+            )
+            .unwrap_err();
+            match err {
+                ContractError::InvalidProposalAmount {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Accepting the full amount is the same as a plain approval
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            bob.clone(),
+            approve(1, Some(2000)),
+        )
+        .unwrap();
+        execute(deps.as_mut(), env.clone(), cw20.clone(), fund(2000, 1)).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(2000));
+
+        // A reduced acceptance is funded with the accepted amount only
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            bob.clone(),
+            approve(2, Some(1000)),
+        )
+        .unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "1000"));
+        let err = execute(deps.as_mut(), env.clone(), cw20.clone(), fund(2000, 2)).unwrap_err();
+        match err {
+            ContractError::InvalidProposalAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        execute(deps.as_mut(), env.clone(), cw20.clone(), fund(1000, 2)).unwrap();
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.amount, Uint128::new(1000));
+        assert_eq!(stream.rate_per_second, Uint128::new(1));
+
+        // The accepted amount is checked like a new proposal, here against a
+        // fee raised to the whole deposit since it was proposed
+        let update = ExecuteMsg::UpdateConfig {
+            new_owner: None,
+            new_cw20_addr: None,
+            new_fee_bps: Some(10000),
+            new_fee_recipient: None,
+        };
+        execute(deps.as_mut(), env.clone(), cw20, update).unwrap();
+        let err = execute(deps.as_mut(), env, bob, approve(3, Some(1000))).unwrap_err();
+        match err {
+            ContractError::ZeroAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn stream_status_lifecycle() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        let status_at = |deps: Deps, env: Env, id| -> StreamStatus {
+            let msg = QueryMsg::GetStream {
+                id: Uint128::new(id),
+            };
+            let stream: StreamResponse = from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            stream.status
+        };
+        let by_status = |deps: Deps, env: Env, status| -> Vec<u128> {
+            let msg = QueryMsg::StreamsByStatus {
+                status,
+                start_after: None,
+                limit: None,
+            };
+            let res: StreamsByStatusResponse =
+                from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            res.streams.iter().map(|stream| stream.id.u128()).collect()
+        };
+
+        assert_eq!(
+            status_at(deps.as_ref(), env.clone(), 1),
+            StreamStatus::NotStarted
+        );
+        assert_eq!(
+            by_status(deps.as_ref(), env.clone(), StreamStatus::NotStarted),
+            vec![1, 2]
+        );
+
+        env.block.time = env.block.time.plus_seconds(500);
+        assert_eq!(
+            status_at(deps.as_ref(), env.clone(), 1),
+            StreamStatus::Active
+        );
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
+        assert_eq!(
+            status_at(deps.as_ref(), env.clone(), 2),
+            StreamStatus::Cancelled
+        );
+        assert_eq!(
+            by_status(deps.as_ref(), env.clone(), StreamStatus::Active),
+            vec![1]
+        );
+        assert_eq!(
+            by_status(deps.as_ref(), env.clone(), StreamStatus::Cancelled),
+            vec![2]
+        );
+
+        // A cancelled stream stays cancelled past its end time
+        env.block.time = env.block.time.plus_seconds(1000);
+        assert_eq!(
+            status_at(deps.as_ref(), env.clone(), 1),
+            StreamStatus::Completed
+        );
+        assert_eq!(
+            status_at(deps.as_ref(), env.clone(), 2),
+            StreamStatus::Cancelled
+        );
+        assert_eq!(
+            by_status(deps.as_ref(), env.clone(), StreamStatus::Completed),
+            vec![1]
+        );
+        assert!(by_status(deps.as_ref(), env, StreamStatus::NotStarted).is_empty());
+    }
+
+    #[test]
+    fn projected_totals() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            1000,
+            start_time,
+            start_time + 1000,
+        )
+        .unwrap();
+        // Claimed and still to claim always reconcile to the total
+        let totals = |deps: Deps| -> (u128, u128, u128) {
+            let msg = QueryMsg::ProjectedTotals {
+                id: Uint128::new(1),
+            };
+            let res: ProjectedTotalsResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            assert_eq!(
+                res.already_claimed + res.remaining_to_claim,
+                res.total_to_recipient
+            );
+            (
+                res.total_to_recipient.u128(),
+                res.already_claimed.u128(),
+                res.remaining_to_claim.u128(),
+            )
+        };
+        assert_eq!(totals(deps.as_ref()), (1000, 0, 1000));
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(totals(deps.as_ref()), (1000, 200, 800));
+
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(400),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, top_up).unwrap();
+        assert_eq!(totals(deps.as_ref()), (1400, 200, 1200));
+
+        // Cancelling pays out what vested and ends the stream there
+        env.block.time = env.block.time.plus_seconds(100);
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        execute(deps.as_mut(), env, mock_info("Alice", &[]), cancel).unwrap();
+        let (total, claimed, remaining) = totals(deps.as_ref());
+        assert_eq!((total, remaining), (claimed, 0));
+    }
+
+    #[test]
+    fn emergency_recipient() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                300,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+        }
+        let refund = |recipient: &str, amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from(recipient),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+        let partial_cancel = ExecuteMsg::PartialCancel {
+            id: Uint128::new(1),
+            refund_amount: Uint128::new(100),
+            note: None,
+        };
+
+        // Refunds go to the stream owner by default
+        let info = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, partial_cancel.clone()).unwrap();
+        assert_eq!(res.messages[0].msg, refund("Alice", 100));
+
+        // Only the contract owner opts in
+        let set = ExecuteMsg::SetEmergencyRecipient {
+            address: Some(String::from("Treasury")),
+        };
+        let info = mock_info("Alice", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, set.clone()).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, set).unwrap();
+        assert_eq!(
+            res.attributes,
+            vec![
+                attr("method", "try_set_emergency_recipient"),
+                attr("emergency_recipient", "Treasury"),
+            ]
+        );
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_binary(&res).unwrap();
+        assert_eq!(config.emergency_recipient, Some(String::from("Treasury")));
+
+        // Once set, partial and full cancellations refund the emergency recipient
+        let info = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, partial_cancel).unwrap();
+        assert_eq!(res.messages[0].msg, refund("Treasury", 100));
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        let info = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, cancel).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].msg, refund("Treasury", 100));
+
+        // Clearing it sends refunds back to stream owners
+        let clear = ExecuteMsg::SetEmergencyRecipient { address: None };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, clear).unwrap();
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        let info = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env, info, cancel).unwrap();
+        assert_eq!(res.messages[0].msg, refund("Alice", 300));
+    }
+
+    #[test]
+    fn zero_amount() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        let err = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            0,
+            start_time,
+            end_time,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ZeroAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::zero(),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env, info, top_up).unwrap_err();
+        match err {
+            ContractError::ZeroAmount {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(300));
+    }
+
+    #[cfg(feature = "auto-swap")]
+    #[test]
+    fn auto_swap() {
+        use cosmwasm_std::Decimal;
+
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        let set_auto_swap = ExecuteMsg::SetAutoSwap {
+            id: Uint128::new(1),
+            auto_swap: Some(AutoSwapSpec {
+                target_token: String::from("usdc"),
+                min_output_rate: Decimal::percent(150),
+            }),
+        };
+        let info = mock_info("Bob", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, set_auto_swap.clone()).unwrap_err();
+        match err {
+            ContractError::DexNotConfigured {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Only the contract owner picks the DEX
+        let set_dex = ExecuteMsg::SetDex {
+            address: Some(String::from("Dex")),
+        };
+        let info = mock_info("Alice", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, set_dex.clone()).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, set_dex).unwrap();
+
+        // Only the recipient sets up swapping
+        let info = mock_info("Alice", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, set_auto_swap.clone()).unwrap_err();
+        match err {
+            ContractError::NotStreamRecipient {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info("Bob", &[]);
+        execute(deps.as_mut(), env.clone(), info, set_auto_swap).unwrap();
+
+        // The withdrawal goes to the DEX, which must pay Bob at least 1.5
+        // usdc per streamed token
+        let swap = |amount: u128, minimum_receive: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Send {
+                    contract: String::from("Dex"),
+                    amount: Uint128::new(amount),
+                    msg: to_binary(&DexHookMsg::Swap {
+                        ask_token: String::from("usdc"),
+                        minimum_receive: Uint128::new(minimum_receive),
+                        to: String::from("Bob"),
+                    })
+                    .unwrap(),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+        env.block.time = env.block.time.plus_seconds(200);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, withdraw.clone()).unwrap();
+        assert_eq!(res.messages[0].msg, swap(100, 150));
+
+        env.block.time = env.block.time.plus_seconds(50);
+        let withdraw_all = ExecuteMsg::WithdrawAll {
+            ids: vec![Uint128::new(1)],
+        };
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, withdraw_all).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].msg, swap(50, 75));
+
+        // Without a DEX withdrawals are paid out as streamed
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let clear_dex = ExecuteMsg::SetDex { address: None };
+        execute(deps.as_mut(), env.clone(), info, clear_dex).unwrap();
+        env.block.time = env.block.time.plus_seconds(50);
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env, info, withdraw).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: String::from(MOCK_CONTRACT_ADDR),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("Bob"),
+                    amount: Uint128::new(50),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn unlock_schedule() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let now = env.block.time.seconds();
+        let (start_time, end_time) = (now + 100, now + 500);
+        let create = |schedule: Vec<(u64, u128)>| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(400),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: Some(
+                        schedule
+                            .into_iter()
+                            .map(|(offset, amount)| (now + offset, Uint128::new(amount)))
+                            .collect(),
+                    ),
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            })
+        };
+
+        // Points must move forward, never unlock less and end at the amount
+        for schedule in [
+            vec![],
+            vec![(300, 200), (200, 100), (500, 400)],
+            vec![(200, 200), (300, 100), (500, 400)],
+            vec![(200, 100), (500, 300)],
+            vec![(50, 100), (500, 400)],
+            vec![(200, 100), (600, 400)],
+        ] {
+            let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+            let err = execute(deps.as_mut(), env.clone(), info, create(schedule)).unwrap_err();
+            match err {
+                ContractError::InvalidUnlockSchedule {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // A quarter unlocks at each point and nothing in between
+        let schedule = vec![(200, 100), (300, 200), (400, 300), (500, 400)];
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, create(schedule)).unwrap();
+        let claimable_at = |deps: Deps, offset: u64| -> u128 {
+            let msg = QueryMsg::ClaimableAt {
+                id: Uint128::new(1),
+                at: now + offset,
+            };
+            let res: ClaimableAtResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.claimable.u128()
+        };
+        assert_eq!(claimable_at(deps.as_ref(), 150), 0);
+        assert_eq!(claimable_at(deps.as_ref(), 199), 0);
+        assert_eq!(claimable_at(deps.as_ref(), 200), 100);
+        assert_eq!(claimable_at(deps.as_ref(), 299), 100);
+        assert_eq!(claimable_at(deps.as_ref(), 350), 200);
+        assert_eq!(claimable_at(deps.as_ref(), 500), 400);
+
+        env.block.time = env.block.time.plus_seconds(350);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, withdraw.clone()).unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "200"));
+        let info = mock_info("Bob", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, withdraw).unwrap_err();
+        match err {
+            ContractError::NoFundsToClaim {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Only linear streams can be topped up
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, top_up).unwrap_err();
+        match err {
+            ContractError::NotLinearStream {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Time spent frozen delays the remaining unlock points
+        let freeze = ExecuteMsg::FreezeStream {
+            id: Uint128::new(1),
+        };
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info, freeze).unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        let unfreeze = ExecuteMsg::UnfreezeStream {
+            id: Uint128::new(1),
+        };
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info, unfreeze).unwrap();
+        assert_eq!(claimable_at(deps.as_ref(), 450), 0);
+        assert_eq!(claimable_at(deps.as_ref(), 500), 100);
+        assert_eq!(claimable_at(deps.as_ref(), 600), 200);
+
+        // Without a schedule a stream still vests continuously
+        let env = mock_env();
+        create_stream(
+            deps.as_mut(),
+            env,
+            "Alice",
+            "Bob",
+            400,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let stream = get_stream(deps.as_ref(), 2);
+        assert_eq!(stream.unlock_schedule, None);
+        let msg = QueryMsg::ClaimableAt {
+            id: Uint128::new(2),
+            at: now + 150,
+        };
+        let res: ClaimableAtResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.claimable, Uint128::new(50));
+    }
+
+    #[test]
+    fn withdrawal_history() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+
+        env.block.time = env.block.time.plus_seconds(150);
+        let first = env.block.time.seconds();
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let info = mock_info("Bob", &[]);
+        execute(deps.as_mut(), env.clone(), info, withdraw).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(100);
+        let second = env.block.time.seconds();
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: Some(String::from("Carol")),
+        };
+        let info = mock_info("Bob", &[]);
+        execute(deps.as_mut(), env.clone(), info, withdraw).unwrap();
+
+        // Paying out the vested rest at cancellation is recorded too
+        env.block.time = env.block.time.plus_seconds(50);
+        let third = env.block.time.seconds();
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        execute(deps.as_mut(), env, mock_info("Alice", &[]), cancel).unwrap();
+
+        let history = |deps: Deps, start_after: Option<u64>| -> WithdrawalHistoryResponse {
+            let msg = QueryMsg::WithdrawalHistory {
+                id: Uint128::new(1),
+                start_after,
+                limit: None,
+            };
+            from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap()
+        };
+        assert_eq!(
+            history(deps.as_ref(), None).withdrawals,
+            vec![
+                WithdrawalRecordResponse {
+                    seq: 1,
+                    time: first,
+                    amount: Uint128::new(50),
+                    recipient: String::from("Bob"),
+                },
+                WithdrawalRecordResponse {
+                    seq: 2,
+                    time: second,
+                    amount: Uint128::new(100),
+                    recipient: String::from("Carol"),
+                },
+                WithdrawalRecordResponse {
+                    seq: 3,
+                    time: third,
+                    amount: Uint128::new(50),
+                    recipient: String::from("Bob"),
+                },
+            ]
+        );
+        let page = history(deps.as_ref(), Some(1)).withdrawals;
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].time, second);
+    }
+
+    #[test]
+    fn pause_stream() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let claimable = |deps: Deps, env: Env| -> u128 {
+            let msg = QueryMsg::Claimable {
+                id: Uint128::new(1),
+            };
+            let res: ClaimableResponse = from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            res.claimable.u128()
+        };
+
+        // Only the stream owner and the contract owner can pause, and the
+        // contract owner can't freeze instead
+        env.block.time = env.block.time.plus_seconds(200);
+        let pause = ExecuteMsg::Pause {
+            id: Uint128::new(1),
+        };
+        let freeze = ExecuteMsg::FreezeStream {
+            id: Uint128::new(1),
+        };
+        for (sender, msg) in [("Bob", pause.clone()), (MOCK_CONTRACT_ADDR, freeze.clone())] {
+            let err = execute(deps.as_mut(), env.clone(), mock_info(sender, &[]), msg).unwrap_err();
+            match err {
+                ContractError::Unauthorized {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        let resume = ExecuteMsg::Resume {
+            id: Uint128::new(1),
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, resume.clone()).unwrap_err();
+        match err {
+            ContractError::StreamNotPaused {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Pausing 100 seconds in by the contract owner stops accrual without
+        // freezing the stream or moving its schedule
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, pause.clone()).unwrap();
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.paused_at, Some(env.block.time.seconds()));
+        assert_eq!(stream.frozen_at, None);
+        for msg in [pause.clone(), freeze] {
+            let err =
+                execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), msg).unwrap_err();
+            match err {
+                ContractError::StreamPaused {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        env.block.time = env.block.time.plus_seconds(50);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), 100);
+
+        // What vested before the pause can still be withdrawn
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, withdraw).unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "100"));
+
+        // A paused stream can still be topped up. The unvested 300 vest over
+        // the 200 seconds its schedule has left.
+        let top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ReceiveMsg::TopUp {
+                id: Uint128::new(1),
+            })
+            .unwrap(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, top_up).unwrap();
+        assert_eq!(claimable(deps.as_ref(), env.clone()), 0);
+
+        // Resuming after 100 paused seconds picks up where it stopped, 100
+        // seconds behind the block time
+        env.block.time = env.block.time.plus_seconds(50);
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, resume.clone()).unwrap();
+        assert_eq!(claimable(deps.as_ref(), env.clone()), 0);
+        env.block.time = env.block.time.plus_seconds(50);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), 75);
+        let stream = get_stream(deps.as_ref(), 1);
+        assert_eq!(stream.end_time, end_time);
+        assert_eq!(stream.paused_at, None);
+        assert_eq!(stream.paused_duration, 100);
+
+        // A second pause by the stream owner adds to the paused time
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info, pause.clone()).unwrap();
+        env.block.time = env.block.time.plus_seconds(30);
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info, resume).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).paused_duration, 130);
+        assert_eq!(claimable(deps.as_ref(), env.clone()), 75);
+
+        // A paused stream can be cancelled, settling what vested before the
+        // pause
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info, pause).unwrap();
+        env.block.time = env.block.time.plus_seconds(20);
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(1),
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
+        let data: CancelResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(data.paid, Uint128::new(75));
+        assert_eq!(data.refunded, Uint128::new(225));
+
+        // Time paused before the start doesn't hold vesting back
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            env.block.time.plus_seconds(100).seconds(),
+            env.block.time.plus_seconds(400).seconds(),
+        )
+        .unwrap();
+        let pause = ExecuteMsg::Pause {
+            id: Uint128::new(2),
+        };
+        let resume = ExecuteMsg::Resume {
+            id: Uint128::new(2),
+        };
+        let info = mock_info("Alice", &[]);
+        execute(deps.as_mut(), env.clone(), info.clone(), pause.clone()).unwrap();
+        env.block.time = env.block.time.plus_seconds(50);
+        execute(deps.as_mut(), env.clone(), info.clone(), resume.clone()).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 2).paused_duration, 0);
+        execute(deps.as_mut(), env.clone(), info.clone(), pause).unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        execute(deps.as_mut(), env, info, resume).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 2).paused_duration, 50);
+    }
 
     #[test]
-    fn initialization() {
+    fn contract_paused() {
         let mut deps = mock_dependencies();
         let msg = InstantiateMsg {
             owner: None,
             cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
         };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
 
-        let info = mock_info("creator", &[]);
-        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                300,
+                start_time,
+                end_time,
+            )
+            .unwrap();
+        }
+        let msg = ExecuteMsg::SetBeneficiary {
+            id: Uint128::new(1),
+            beneficiary: Some(String::from("Dave")),
+            inactivity_seconds: 0,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), msg).unwrap();
 
-        let msg = QueryMsg::GetConfig {};
-        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
-        let config: Config = from_binary(&res).unwrap();
+        let pause = ExecuteMsg::SetContractPaused { paused: true };
+        let info = mock_info("Alice", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, pause.clone()).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, pause).unwrap();
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetConfig {}).unwrap();
+        let config: ConfigResponse = from_binary(&res).unwrap();
+        assert!(config.paused);
 
-        assert_eq!(
-            config,
-            Config {
-                owner: Addr::unchecked("creator"),
-                cw20_addr: Addr::unchecked(MOCK_CONTRACT_ADDR)
-            }
-        );
+        // No new streams and no withdrawals while paused
+        let err = create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::ContractPaused {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        env.block.time = env.block.time.plus_seconds(200);
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let info = mock_info("Bob", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, withdraw.clone()).unwrap_err();
+        match err {
+            ContractError::ContractPaused {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        let withdraw_all = ExecuteMsg::WithdrawAll {
+            ids: vec![Uint128::new(1)],
+        };
+        let info = mock_info("Bob", &[]);
+        let err = execute(deps.as_mut(), env.clone(), info, withdraw_all).unwrap_err();
+        match err {
+            ContractError::ContractPaused {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        // Nor by the beneficiary, even once the recipient has gone quiet
+        let mut late = env.clone();
+        late.block.time = late.block.time.plus_seconds(1000);
+        let claim = ExecuteMsg::ClaimAsBeneficiary {
+            id: Uint128::new(1),
+        };
+        let info = mock_info("Dave", &[]);
+        let err = execute(deps.as_mut(), late, info, claim).unwrap_err();
+        match err {
+            ContractError::ContractPaused {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Cancelling still pays out and refunds
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        let info = mock_info("Alice", &[]);
+        let res = execute(deps.as_mut(), env.clone(), info, cancel).unwrap();
+        assert_eq!(res.messages.len(), 2);
+
+        let unpause = ExecuteMsg::SetContractPaused { paused: false };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        execute(deps.as_mut(), env.clone(), info, unpause).unwrap();
+        let info = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env, info, withdraw).unwrap();
+        assert_eq!(res.attributes[2], attr("amount", "100"));
     }
 
     #[test]
-    fn try_withdraw() {
+    fn stats() {
         let mut deps = mock_dependencies();
         let msg = InstantiateMsg {
             owner: None,
             cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
         };
-        let mut info = mock_info(MOCK_CONTRACT_ADDR, &[]);
-        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
 
-        let sender = Addr::unchecked("Alice").to_string();
-        let recipient = Addr::unchecked("Bob").to_string();
-        let amount = Uint128::new(200);
+        let stats = |deps: Deps, env: Env| -> StatsResponse {
+            from_binary(&query(deps, env, QueryMsg::Stats {}).unwrap()).unwrap()
+        };
         let mut env = mock_env();
-        let start_time = env.block.time.plus_seconds(100).seconds();
-        let end_time = env.block.time.plus_seconds(300).seconds();
+        assert_eq!(
+            stats(deps.as_ref(), env.clone()),
+            StatsResponse {
+                total_deposited: Uint128::zero(),
+                total_claimed: Uint128::zero(),
+                total_refunded: Uint128::zero(),
+                total_locked: Uint128::zero(),
+                active_streams: 0,
+            }
+        );
 
-        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
-            sender: sender.clone(),
-            amount: amount.clone(),
-            msg: to_binary(&ReceiveMsg::CreateStream {
-                recipient: recipient.clone(),
-                start_time: start_time.clone(),
-                end_time: end_time.clone(),
-            })
-            .unwrap(),
-        });
-        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        let end_time = env.block.time.plus_seconds(400).seconds();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            300,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        create_stream(
+            deps.as_mut(),
+            env.clone(),
+            "Alice",
+            "Bob",
+            600,
+            start_time,
+            end_time,
+        )
+        .unwrap();
+        let res = stats(deps.as_ref(), env.clone());
+        assert_eq!(res.total_deposited, Uint128::new(900));
+        assert_eq!(res.total_locked, Uint128::new(900));
+        assert_eq!(res.active_streams, 2);
 
-        let msg = QueryMsg::GetStream {
+        // Half of each stream has vested
+        env.block.time = env.block.time.plus_seconds(250);
+        let withdraw = ExecuteMsg::Withdraw {
             id: Uint128::new(1),
+            to: None,
         };
-        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
-        let stream: Stream = from_binary(&res).unwrap();
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let res = stats(deps.as_ref(), env.clone());
+        assert_eq!(res.total_claimed, Uint128::new(150));
+        assert_eq!(res.total_locked, Uint128::new(750));
 
+        // The vested half is paid out and the rest refunded
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
         assert_eq!(
-            stream,
-            Stream {
-                owner: Addr::unchecked("Alice"),
-                recipient: Addr::unchecked("Bob"),
-                amount: amount.clone(),
-                claimed_amount: Uint128::new(0),
-                start_time: start_time.clone(),
-                rate_per_second: Uint128::new(1),
-                end_time: end_time.clone()
+            stats(deps.as_ref(), env.clone()),
+            StatsResponse {
+                total_deposited: Uint128::new(900),
+                total_claimed: Uint128::new(450),
+                total_refunded: Uint128::new(300),
+                total_locked: Uint128::new(150),
+                active_streams: 1,
             }
         );
 
-        let msg = ExecuteMsg::Withdraw {
+        env.block.time = env.block.time.plus_seconds(150);
+        let withdraw = ExecuteMsg::Withdraw {
             id: Uint128::new(1),
+            to: None,
         };
-        info.sender = Addr::unchecked("Bob");
-        env.block.time = env.block.time.plus_seconds(150);
-        let res = execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
-        let msg = res.messages[0].clone().msg;
+        execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        let res = stats(deps.as_ref(), env);
+        assert_eq!(res.total_claimed, Uint128::new(600));
+        assert_eq!(res.total_locked, Uint128::zero());
+        assert_eq!(res.active_streams, 0);
+    }
 
-        assert_eq!(
-            msg,
-            CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: String::from(MOCK_CONTRACT_ADDR),
-                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+    #[cfg(feature = "rebasing")]
+    #[test]
+    fn rebasing_shares() {
+        let balance = Rc::new(Cell::new(0u128));
+        let token_balance = balance.clone();
+        let mut deps = mock_dependencies_with_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "token" => {
+                match from_binary(msg).unwrap() {
+                    Cw20QueryMsg::Balance { address } => {
+                        assert_eq!(address, MOCK_CONTRACT_ADDR);
+                        let res = BalanceResponse {
+                            balance: Uint128::new(token_balance.get()),
+                        };
+                        SystemResult::Ok(ContractResult::Ok(to_binary(&res).unwrap()))
+                    }
+                    _ => panic!("unexpected query"),
+                }
+            }
+            _ => panic!("unexpected query"),
+        });
+        let msg = InstantiateMsg {
+            owner: None,
+            cw20_addr: String::from("token"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        let create = |amount: u128, start_time: u64, end_time: u64, rebasing: Option<bool>| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(amount),
+                msg: to_binary(&ReceiveMsg::CreateStream {
                     recipient: String::from("Bob"),
-                    amount: Uint128::new(50)
+                    start_time,
+                    end_time,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing,
+                    allow_fee_recipient: None,
                 })
                 .unwrap(),
-                funds: vec![]
             })
-        );
+        };
+        let transfer = |recipient: &str, amount: u128| {
+            let token = Addr::unchecked("token");
+            transfer_msg(Some(&token), None, recipient, Uint128::new(amount)).unwrap()
+        };
+        let token = mock_info("token", &[]);
+        let mut env = mock_env();
+        let now = env.block.time.seconds();
 
-        let msg = QueryMsg::GetStream {
+        // Tokens already held would be pooled as if they had rebased
+        balance.set(1500);
+        let msg = create(1000, now + 100, now + 1100, Some(true));
+        let err = execute(deps.as_mut(), env.clone(), token.clone(), msg).unwrap_err();
+        match err {
+            ContractError::RebasingMismatch {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The first deposit buys shares one for one
+        balance.set(1000);
+        let msg = create(1000, now + 100, now + 1100, Some(true));
+        execute(deps.as_mut(), env.clone(), token.clone(), msg).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 1).amount, Uint128::new(1000));
+
+        // Half the shares have vested when the balance doubles
+        balance.set(2000);
+        env.block.height += 1;
+        env.block.time = env.block.time.plus_seconds(600);
+        let msg = QueryMsg::Claimable {
             id: Uint128::new(1),
         };
-        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
-        let stream: Stream = from_binary(&res).unwrap();
+        let res: ClaimableResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.claimable, Uint128::new(1000));
+
+        // So do the portfolio and bootstrap queries
+        let msg = QueryMsg::PortfolioFor {
+            recipient: String::from("Bob"),
+            limit: None,
+        };
+        let res: PortfolioResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.streams[0].claimable, Uint128::new(1000));
+        let msg = QueryMsg::Bootstrap {
+            recipient: Some(String::from("Bob")),
+        };
+        let res: BootstrapResponse =
+            from_binary(&query(deps.as_ref(), env.clone(), msg).unwrap()).unwrap();
+        assert_eq!(res.total_claimable, Some(Uint128::new(1000)));
+
+        let withdraw = ExecuteMsg::Withdraw {
+            id: Uint128::new(1),
+            to: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Bob", &[]), withdraw).unwrap();
+        assert_eq!(res.messages[0].msg, transfer("Bob", 1000));
         assert_eq!(
-            stream,
-            Stream {
-                owner: Addr::unchecked("Alice"),
-                recipient: Addr::unchecked("Bob"),
-                amount: amount.clone(),
-                claimed_amount: Uint128::new(50),
-                start_time: start_time.clone(),
-                rate_per_second: Uint128::new(1),
-                end_time: end_time.clone()
-            }
+            get_stream(deps.as_ref(), 1).claimed_amount,
+            Uint128::new(500)
         );
+        balance.set(1000);
+
+        // Later deposits buy shares at the current price
+        env.block.height += 1;
+        balance.set(2000);
+        let now = env.block.time.seconds();
+        let msg = create(1000, now + 100, now + 600, Some(true));
+        execute(deps.as_mut(), env.clone(), token.clone(), msg).unwrap();
+        assert_eq!(get_stream(deps.as_ref(), 2).amount, Uint128::new(500));
+
+        // Refunds are paid at the same price within the block
+        let cancel = ExecuteMsg::Cancel {
+            id: Uint128::new(2),
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("Alice", &[]), cancel).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(transfer("Alice", 1000))]);
+        balance.set(1000);
+
+        // Streams of a rebasing token must all be rebasing
+        let msg = create(1000, now + 100, now + 600, None);
+        let err = execute(deps.as_mut(), env, token, msg).unwrap_err();
+        match err {
+            ContractError::RebasingMismatch {} => {}
+            e => panic!("unexpected error: {}", e),
+        }
     }
 
     #[test]
-    fn invalid_start_time() {
+    fn streams_by_token() {
         let mut deps = mock_dependencies();
-
         let msg = InstantiateMsg {
             owner: None,
-            cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            cw20_addr: String::from("token_a"),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
         };
-        let mut info = mock_info("Alice", &[]);
-        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
-
-        let sender = Addr::unchecked("Alice").to_string();
-        let recipient = Addr::unchecked("Bob").to_string();
-        let amount = Uint128::new(100);
-        let start_time = mock_env().block.time.plus_seconds(100).seconds();
-        let end_time = mock_env().block.time.plus_seconds(20).seconds();
+        let owner = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let allow = ExecuteMsg::AddAllowedToken {
+            token: String::from("token_b"),
+        };
+        execute(deps.as_mut(), mock_env(), owner, allow).unwrap();
 
-        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
-            sender: sender.clone(),
-            amount: amount.clone(),
-            msg: to_binary(&ReceiveMsg::CreateStream {
-                recipient: recipient.clone(),
-                start_time: start_time.clone(),
-                end_time: end_time.clone(),
-            })
-            .unwrap(),
-        });
-        info.sender = Addr::unchecked(MOCK_CONTRACT_ADDR);
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
-        match err {
-            ContractError::InvalidStartTime {} => {}
-            e => panic!("unexpected error: {}", e),
+        let env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for token in ["token_a", "token_b", "token_a", "token_b", "token_a"] {
+            let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: String::from("Alice"),
+                amount: Uint128::new(1000),
+                msg: to_binary(&ReceiveMsg::CreateStream {
+                    recipient: String::from("Bob"),
+                    start_time,
+                    end_time: start_time + 1000,
+                    recipient_kind: None,
+                    recipient_controller: None,
+                    condition: None,
+                    recipient_is_name: None,
+                    amount: None,
+                    rounding_unit: None,
+                    cliff_time: None,
+                    unlock_schedule: None,
+                    rebasing: None,
+                    allow_fee_recipient: None,
+                })
+                .unwrap(),
+            });
+            execute(deps.as_mut(), env.clone(), mock_info(token, &[]), msg).unwrap();
         }
+
+        let by_token = |deps: Deps, token: &str, start_after: Option<u128>, limit| {
+            let msg = QueryMsg::StreamsByToken {
+                cw20_addr: String::from(token),
+                start_after: start_after.map(Uint128::new),
+                limit,
+            };
+            let res: StreamsResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.streams.iter().map(|s| s.id.u128()).collect::<Vec<_>>()
+        };
+
+        // The two tokens partition the streams
+        assert_eq!(
+            by_token(deps.as_ref(), "token_a", None, None),
+            vec![1, 3, 5]
+        );
+        assert_eq!(by_token(deps.as_ref(), "token_b", None, None), vec![2, 4]);
+        assert_eq!(
+            by_token(deps.as_ref(), "token_c", None, None),
+            Vec::<u128>::new()
+        );
+
+        // Pagination stays within the token
+        assert_eq!(
+            by_token(deps.as_ref(), "token_a", None, Some(2)),
+            vec![1, 3]
+        );
+        assert_eq!(by_token(deps.as_ref(), "token_a", Some(3), None), vec![5]);
+        assert_eq!(
+            by_token(deps.as_ref(), "token_b", Some(2), Some(1)),
+            vec![4]
+        );
     }
 
     #[test]
-    fn invalid_cw20_addr() {
+    fn stream_token_mismatch() {
         let mut deps = mock_dependencies();
-
         let msg = InstantiateMsg {
             owner: None,
             cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
         };
-        let mut info = mock_info("Alice", &[]);
-        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let owner = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
 
-        let sender = Addr::unchecked("Alice").to_string();
-        let recipient = Addr::unchecked("Bob").to_string();
-        let amount = Uint128::new(100);
-        let start_time = mock_env().block.time.plus_seconds(100).seconds();
-        let end_time = mock_env().block.time.plus_seconds(200).seconds();
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..2 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
 
-        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
-            sender: sender.clone(),
-            amount: amount.clone(),
-            msg: to_binary(&ReceiveMsg::CreateStream {
-                recipient: recipient.clone(),
-                start_time: start_time.clone(),
-                end_time: end_time.clone(),
-            })
-            .unwrap(),
-        });
-        info.sender = Addr::unchecked("wrongCw20");
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+        // The config token is replaced after the streams were funded
+        let update = ExecuteMsg::UpdateConfig {
+            new_owner: None,
+            new_cw20_addr: Some(String::from("new_token")),
+            new_fee_bps: None,
+            new_fee_recipient: None,
+        };
+        execute(deps.as_mut(), env.clone(), owner, update).unwrap();
+
+        // The stream still pays out in its own token
+        env.block.time = Timestamp::from_seconds(start_time + 300);
+        let withdraw = |id: u128| ExecuteMsg::Withdraw {
+            id: Uint128::new(id),
+            to: None,
+        };
+        let bob = mock_info("Bob", &[]);
+        let res = execute(deps.as_mut(), env.clone(), bob.clone(), withdraw(1)).unwrap();
+        let token = Addr::unchecked(MOCK_CONTRACT_ADDR);
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_msg(Some(&token), None, "Bob", Uint128::new(300)).unwrap()
+        );
 
+        // A stream whose token can't be resolved is not paid from the config's
+        let mut stream = streams().load(&deps.storage, 2u128.into()).unwrap();
+        stream.cw20_addr = None;
+        stream.denom = None;
+        streams()
+            .save(&mut deps.storage, 2u128.into(), &stream)
+            .unwrap();
+        let err = execute(deps.as_mut(), env, bob, withdraw(2)).unwrap_err();
         match err {
-            ContractError::Unauthorized {} => {}
+            ContractError::StreamTokenMismatch {} => {}
             e => panic!("unexpected error: {}", e),
         }
+        assert_eq!(get_stream(deps.as_ref(), 2).claimed_amount, Uint128::zero());
     }
 
     #[test]
-    fn invalid_deposit_amount() {
+    fn batch_too_large() {
         let mut deps = mock_dependencies();
-
         let msg = InstantiateMsg {
             owner: None,
             cw20_addr: String::from(MOCK_CONTRACT_ADDR),
+            event_namespace: None,
+            global_cancellers: vec![],
+            arbiter: None,
+            max_total_streams: None,
+            name_service: None,
+            auto_close_on_final_withdraw: false,
+            time_oracle: None,
+            fee_bps: 0,
+            fee_recipient: None,
+            min_claim_interval_seconds: None,
         };
-        let mut info = mock_info("Alice", &[]);
-        instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
 
-        let sender = Addr::unchecked("Alice").to_string();
-        let recipient = Addr::unchecked("Bob").to_string();
-        let amount = Uint128::new(3);
-        let start_time = mock_env().block.time.plus_seconds(100).seconds();
-        let end_time = mock_env().block.time.plus_seconds(200).seconds();
+        let mut env = mock_env();
+        let start_time = env.block.time.plus_seconds(100).seconds();
+        for _ in 0..MAX_BATCH_SIZE + 1 {
+            create_stream(
+                deps.as_mut(),
+                env.clone(),
+                "Alice",
+                "Bob",
+                1000,
+                start_time,
+                start_time + 1000,
+            )
+            .unwrap();
+        }
+        env.block.time = Timestamp::from_seconds(start_time + 500);
 
-        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
-            sender: sender.clone(),
-            amount: amount.clone(),
-            msg: to_binary(&ReceiveMsg::CreateStream {
-                recipient: recipient.clone(),
-                start_time: start_time.clone(),
-                end_time: end_time.clone(),
+        // Every batch handler rejects a list one over the cap as a whole,
+        // even though each stream in it is valid
+        let ids: Vec<Uint128> = (1..=MAX_BATCH_SIZE as u128 + 1).map(Uint128::new).collect();
+        let batch_top_up = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: String::from("Alice"),
+            amount: Uint128::new(31000),
+            msg: to_binary(&ReceiveMsg::BatchTopUp {
+                ids: ids.clone(),
+                weights: None,
+                note: None,
             })
             .unwrap(),
         });
-        info.sender = Addr::unchecked(MOCK_CONTRACT_ADDR);
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
-
-        // TODO: More specific error for invalid deposit amount
-        match err {
-            _e => {}
+        let withdraw_all = ExecuteMsg::WithdrawAll { ids: ids.clone() };
+        let consolidate = ExecuteMsg::RequestConsolidation { ids: ids.clone() };
+        for (sender, msg) in [
+            (MOCK_CONTRACT_ADDR, batch_top_up),
+            ("Bob", withdraw_all),
+            ("Bob", consolidate),
+        ] {
+            let err = execute(deps.as_mut(), env.clone(), mock_info(sender, &[]), msg).unwrap_err();
+            match err {
+                ContractError::BatchTooLarge {} => {}
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        for id in 1..=MAX_BATCH_SIZE as u128 + 1 {
+            let stream = get_stream(deps.as_ref(), id);
+            assert_eq!(stream.amount, Uint128::new(1000));
+            assert_eq!(stream.claimed_amount, Uint128::zero());
         }
+
+        // At the cap they go through
+        let withdraw_all = ExecuteMsg::WithdrawAll {
+            ids: ids[..MAX_BATCH_SIZE].to_vec(),
+        };
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("Bob", &[]),
+            withdraw_all,
+        )
+        .unwrap();
+        let consolidate = ExecuteMsg::RequestConsolidation {
+            ids: ids[..MAX_BATCH_SIZE].to_vec(),
+        };
+        execute(deps.as_mut(), env, mock_info("Bob", &[]), consolidate).unwrap();
     }
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{OverflowError, StdError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,6 +6,9 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    Overflow(#[from] OverflowError),
+
     #[error("Unauthorized")]
     Unauthorized {},
 
@@ -35,4 +38,184 @@ pub enum ContractError {
 
     #[error("InvalidRecipient")]
     InvalidRecipient {},
+
+    #[error("RecipientIsFeeRecipient")]
+    RecipientIsFeeRecipient {},
+
+    #[error("InvalidInstallments")]
+    InvalidInstallments {},
+
+    #[error("NotStreamOwner")]
+    NotStreamOwner {},
+
+    #[error("InvalidTopUp")]
+    InvalidTopUp {},
+
+    #[error("InvalidWeights")]
+    InvalidWeights {},
+
+    #[error("InvalidEventNamespace")]
+    InvalidEventNamespace {},
+
+    #[error("RecipientKindMismatch")]
+    RecipientKindMismatch {},
+
+    #[error("NotLinearStream")]
+    NotLinearStream {},
+
+    #[error("InvalidRefundAmount")]
+    InvalidRefundAmount {},
+
+    #[error("BatchTooLarge")]
+    BatchTooLarge {},
+
+    #[error("StreamEnded")]
+    StreamEnded {},
+
+    #[error("InvalidNote")]
+    InvalidNote {},
+
+    #[error("Reentrancy")]
+    Reentrancy {},
+
+    #[error("StreamFrozen")]
+    StreamFrozen {},
+
+    #[error("StreamNotFrozen")]
+    StreamNotFrozen {},
+
+    #[error("StreamPaused")]
+    StreamPaused {},
+
+    #[error("StreamNotPaused")]
+    StreamNotPaused {},
+
+    #[error("RecipientFrozen")]
+    RecipientFrozen {},
+
+    #[error("RecipientNotFrozen")]
+    RecipientNotFrozen {},
+
+    #[error("RecipientStillActive")]
+    RecipientStillActive {},
+
+    #[error("GlobalStreamLimitReached")]
+    GlobalStreamLimitReached {},
+
+    #[error("ProposalNotApproved")]
+    ProposalNotApproved {},
+
+    #[error("InvalidProposalAmount")]
+    InvalidProposalAmount {},
+
+    #[error("InvalidConsolidation")]
+    InvalidConsolidation {},
+
+    #[error("ConditionNotMet")]
+    ConditionNotMet {},
+
+    #[error("NameServiceNotConfigured")]
+    NameServiceNotConfigured {},
+
+    #[error("NameNotResolved")]
+    NameNotResolved {},
+
+    #[error("InvalidStreamAmount")]
+    InvalidStreamAmount {},
+
+    #[error("AllowanceExceeded")]
+    AllowanceExceeded {},
+
+    #[error("TopUpNotRevertible")]
+    TopUpNotRevertible {},
+
+    #[error("InvalidCancelTime")]
+    InvalidCancelTime {},
+
+    #[error("CancellationWindowClosed")]
+    CancellationWindowClosed {},
+
+    #[error("CancellationPending")]
+    CancellationPending {},
+
+    #[error("NoPendingCancellation")]
+    NoPendingCancellation {},
+
+    #[error("GracePeriodOver")]
+    GracePeriodOver {},
+
+    #[error("GracePeriodNotOver")]
+    GracePeriodNotOver {},
+
+    #[error("InvalidCancelWindow")]
+    InvalidCancelWindow {},
+
+    #[error("ScheduledCancelNotDue")]
+    ScheduledCancelNotDue {},
+
+    #[error("StreamCancelled")]
+    StreamCancelled {},
+
+    #[error("InvalidRoundingUnit")]
+    InvalidRoundingUnit {},
+
+    #[error("InvalidCliff")]
+    InvalidCliff {},
+
+    #[error("InvalidUnlockSchedule")]
+    InvalidUnlockSchedule {},
+
+    #[error("LiquidityProviderNotSet")]
+    LiquidityProviderNotSet {},
+
+    #[error("StreamEncumbered")]
+    StreamEncumbered {},
+
+    #[error("InvalidPreClaimAmount")]
+    InvalidPreClaimAmount {},
+
+    #[error("InvalidNativeFunds")]
+    InvalidNativeFunds {},
+
+    #[error("DenomMismatch")]
+    DenomMismatch {},
+
+    #[error("WrongContract")]
+    WrongContract {},
+
+    #[error("CannotDowngrade")]
+    CannotDowngrade {},
+
+    #[error("DestinationNotAllowed")]
+    DestinationNotAllowed {},
+
+    #[error("TooManyDestinations")]
+    TooManyDestinations {},
+
+    #[error("InvalidFee")]
+    InvalidFee {},
+
+    #[error("ClaimCooldown")]
+    ClaimCooldown {},
+
+    #[error("InvalidGroupName")]
+    InvalidGroupName {},
+
+    #[error("ZeroAmount")]
+    ZeroAmount {},
+
+    #[error("DexNotConfigured")]
+    DexNotConfigured {},
+
+    #[error("ContractPaused")]
+    ContractPaused {},
+
+    #[error("RebasingMismatch")]
+    RebasingMismatch {},
+
+    #[error("RebasingNotEnabled")]
+    RebasingNotEnabled {},
+
+    #[error("StreamTokenMismatch")]
+    StreamTokenMismatch {},
 }
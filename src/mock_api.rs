@@ -0,0 +1,85 @@
+use cosmwasm_std::testing::{MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    Addr, Api, CanonicalAddr, OwnedDeps, RecoverPubkeyError, StdError, StdResult, VerificationError,
+};
+use std::marker::PhantomData;
+
+/// Wraps `MockApi` to treat addresses case-insensitively like bech32 does:
+/// any casing is accepted and addresses are normalized to lower case.
+#[derive(Default)]
+pub struct CaseInsensitiveApi {
+    base: MockApi,
+}
+
+impl Api for CaseInsensitiveApi {
+    fn addr_validate(&self, human: &str) -> StdResult<Addr> {
+        let normalized = self.addr_humanize(&self.addr_canonicalize(human)?)?;
+        if normalized != human {
+            return Err(StdError::generic_err(
+                "Invalid input: address not normalized",
+            ));
+        }
+        Ok(normalized)
+    }
+
+    fn addr_canonicalize(&self, human: &str) -> StdResult<CanonicalAddr> {
+        self.base.addr_canonicalize(&human.to_lowercase())
+    }
+
+    fn addr_humanize(&self, canonical: &CanonicalAddr) -> StdResult<Addr> {
+        self.base.addr_humanize(canonical)
+    }
+
+    fn secp256k1_verify(
+        &self,
+        message_hash: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> Result<bool, VerificationError> {
+        self.base
+            .secp256k1_verify(message_hash, signature, public_key)
+    }
+
+    fn secp256k1_recover_pubkey(
+        &self,
+        message_hash: &[u8],
+        signature: &[u8],
+        recovery_param: u8,
+    ) -> Result<Vec<u8>, RecoverPubkeyError> {
+        self.base
+            .secp256k1_recover_pubkey(message_hash, signature, recovery_param)
+    }
+
+    fn ed25519_verify(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> Result<bool, VerificationError> {
+        self.base.ed25519_verify(message, signature, public_key)
+    }
+
+    fn ed25519_batch_verify(
+        &self,
+        messages: &[&[u8]],
+        signatures: &[&[u8]],
+        public_keys: &[&[u8]],
+    ) -> Result<bool, VerificationError> {
+        self.base
+            .ed25519_batch_verify(messages, signatures, public_keys)
+    }
+
+    fn debug(&self, message: &str) {
+        self.base.debug(message)
+    }
+}
+
+pub fn mock_dependencies_case_insensitive(
+) -> OwnedDeps<MockStorage, CaseInsensitiveApi, MockQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: CaseInsensitiveApi::default(),
+        querier: MockQuerier::default(),
+        custom_query_type: PhantomData,
+    }
+}
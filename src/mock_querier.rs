@@ -0,0 +1,47 @@
+use cosmwasm_std::testing::{MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    from_slice, Empty, OwnedDeps, Querier, QuerierResult, QueryRequest, SystemError, SystemResult,
+    WasmQuery,
+};
+use std::marker::PhantomData;
+
+/// Wraps `MockQuerier` so tests can answer wasm queries made to other contracts.
+pub struct WasmMockQuerier {
+    base: MockQuerier,
+    wasm_handler: Box<dyn Fn(&WasmQuery) -> QuerierResult>,
+}
+
+impl Querier for WasmMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        match &request {
+            QueryRequest::Wasm(msg) => (*self.wasm_handler)(msg),
+            _ => self.base.handle_query(&request),
+        }
+    }
+}
+
+pub fn mock_dependencies_with_wasm<WH>(
+    wasm_handler: WH,
+) -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier>
+where
+    WH: Fn(&WasmQuery) -> QuerierResult + 'static,
+{
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: WasmMockQuerier {
+            base: MockQuerier::default(),
+            wasm_handler: Box::new(wasm_handler),
+        },
+        custom_query_type: PhantomData,
+    }
+}
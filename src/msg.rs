@@ -1,20 +1,332 @@
-use cosmwasm_std::Uint128;
+use crate::state::{
+    Beneficiary, Condition, DustPolicy, Encumbrance, Installments, Proposal, StreamActionKind,
+    StreamStatus, TimeOracle,
+};
+use cosmwasm_std::{Binary, Decimal, Uint128};
 use cw20::Cw20ReceiveMsg;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
+    /// Contract admin. Defaults to the instantiating address when omitted;
+    /// a provided but invalid address is rejected.
     pub owner: Option<String>,
     pub cw20_addr: String,
+    /// Optional prefix for emitted attribute keys so a shared indexer can
+    /// tell several deployments apart.
+    pub event_namespace: Option<String>,
+    /// Addresses allowed to cancel any stream, on top of each stream's owner
+    #[serde(default)]
+    pub global_cancellers: Vec<String>,
+    /// May freeze and unfreeze any stream during a dispute
+    pub arbiter: Option<String>,
+    /// Cap on the number of streams ever created. Cancelled or fully
+    /// claimed streams still count towards it.
+    pub max_total_streams: Option<u64>,
+    /// Resolves recipient names given at creation, see `NameServiceQueryMsg`
+    pub name_service: Option<String>,
+    /// Removes a stream once a withdrawal drains it, saving its storage. The
+    /// stream can no longer be queried afterwards.
+    #[serde(default)]
+    pub auto_close_on_final_withdraw: bool,
+    /// Reads the current time from an oracle instead of the block, see
+    /// `TimeOracleQueryMsg`
+    pub time_oracle: Option<TimeOracleSpec>,
+    /// Share of every new stream's deposit, in basis points, paid to
+    /// `fee_recipient`. At most 10000.
+    #[serde(default)]
+    pub fee_bps: u16,
+    /// Receives creation fees. Defaults to the owner.
+    pub fee_recipient: Option<String>,
+    /// Minimum time between withdrawals from a stream. Topping up a stream
+    /// restarts the wait.
+    pub min_claim_interval_seconds: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
     Receive(Cw20ReceiveMsg),
+    /// Streams a native coin instead of the cw20. Exactly one coin must be
+    /// sent with the message, and the whole of it is streamed.
+    CreateNativeStream {
+        recipient: String,
+        start_time: u64,
+        end_time: u64,
+        /// Allows streaming to the fee recipient while a creation fee is charged
+        allow_fee_recipient: Option<bool>,
+    },
     Withdraw {
         id: Uint128, // Stream id
+        /// Pays this address instead of the recipient. Recipient only, and
+        /// it must be one of the stream's allowed destinations if it has any.
+        to: Option<String>,
+    },
+    /// Withdraws from up to 30 streams at once, skipping those with nothing
+    /// to claim yet. Without ids, from the first 30 of the sender's streams
+    /// that have funds left. Fallback recipients aren't used.
+    WithdrawAll {
+        ids: Vec<Uint128>,
+    },
+    PartialCancel {
+        id: Uint128,
+        refund_amount: Uint128, // Refunded from the unvested balance
+        note: Option<String>,
+    },
+    /// Pays the recipient what has vested and refunds the rest to the owner,
+    /// closing the stream. Same callers as `partial_cancel`, plus the
+    /// contract owner.
+    Cancel {
+        id: Uint128,
+    },
+    /// Cancels like `cancel`, but holds the payout and refund in the contract
+    /// for a grace period during which the stream owner can restore the
+    /// stream. Same callers as `cancel`.
+    SoftCancel {
+        id: Uint128,
+    },
+    /// Undoes a soft cancellation within its grace period. Stream owner only.
+    RestoreStream {
+        id: Uint128,
+    },
+    /// Pays out a soft cancellation after its grace period. Callable by
+    /// anyone.
+    FinalizeCancellation {
+        id: Uint128,
+    },
+    /// Stops vesting at `cancel_at`, after which anyone can settle the stream
+    /// with `execute_scheduled_cancel`. Same callers as `partial_cancel`.
+    ScheduleCancel {
+        id: Uint128,
+        cancel_at: Option<u64>, // None clears it
+    },
+    /// Pays the recipient what vested until the scheduled cancellation and
+    /// refunds the rest to the owner. Callable by anyone once it's due.
+    ExecuteScheduledCancel {
+        id: Uint128,
+    },
+    /// Allows cancelling the stream only until `cancel_window_end`, after
+    /// which it can't be cancelled, partially or on schedule. Stream owner
+    /// only. The window can be shortened later but never extended.
+    SetCancelWindow {
+        id: Uint128,
+        cancel_window_end: u64,
+    },
+    SetFallbackRecipient {
+        id: Uint128,
+        fallback_recipient: Option<String>, // None clears it
+        note: Option<String>,
+    },
+    /// Lets `delegate` withdraw, on the caller's behalf, from every stream
+    /// the caller receives
+    SetGlobalDelegate {
+        delegate: Option<String>, // None clears it
+    },
+    /// Undoes the stream's latest top-up and refunds it. Stream owner only,
+    /// in the same block as the top-up.
+    RevertTopUp {
+        id: Uint128,
+    },
+    /// Recipient only. Lets `delegate` withdraw up to `allowance` in total
+    /// from the stream, the recipient can still withdraw freely.
+    SetDelegateAllowance {
+        id: Uint128,
+        delegate: String,
+        allowance: Option<Uint128>, // None revokes it
+    },
+    /// Snapshots the vested amount as a claimable lump and restarts linear
+    /// vesting of the rest from now until the end time
+    CrystallizeStream {
+        id: Uint128,
+        note: Option<String>,
+    },
+    /// Replaces the rest of a linear stream with `steps` equal installments
+    /// until the end time. Takes effect once both the owner and the recipient
+    /// have sent it with the same number of steps.
+    Convert {
+        id: Uint128,
+        steps: u32,
+    },
+    /// Recipient only. Asks the owner to merge linear streams they fund into
+    /// the first stream of `ids`, vesting what's left of all of them until
+    /// the latest end time.
+    RequestConsolidation {
+        ids: Vec<Uint128>,
+    },
+    /// Owner of the streams only, applies a pending consolidation request
+    ApproveConsolidation {
+        id: Uint128,
+    },
+    /// Stops accrual, capping what can be withdrawn at what is vested now.
+    /// Stream owner or arbiter only.
+    FreezeStream {
+        id: Uint128,
+    },
+    /// Resumes accrual, shifting the schedule by the time spent frozen
+    UnfreezeStream {
+        id: Uint128,
+    },
+    /// Stops the stream's schedule, recording when the pause began. Vested
+    /// funds stay withdrawable. Stream owner or contract owner only.
+    Pause {
+        id: Uint128,
+    },
+    /// Ends a pause, adding the time paused to the stream's `paused_duration`,
+    /// by which vesting runs behind. Stream owner or contract owner only.
+    Resume {
+        id: Uint128,
+    },
+    /// Freezes every running stream of `recipient` and blocks withdrawals
+    /// from all of its streams. Contract owner only.
+    FreezeRecipient {
+        recipient: String,
+    },
+    /// Lifts a recipient freeze, unfreezing the streams it froze
+    UnfreezeRecipient {
+        recipient: String,
+    },
+    /// Accepts streams and top-ups in another cw20 token. Contract owner only.
+    AddAllowedToken {
+        token: String,
+    },
+    /// Stops accepting a token. Its existing streams still pay out.
+    /// Contract owner only.
+    RemoveAllowedToken {
+        token: String,
+    },
+    /// Rotates the contract owner and the instantiation token, and changes
+    /// the creation fee. A new token is added to the allowed tokens.
+    /// Contract owner only.
+    UpdateConfig {
+        new_owner: Option<String>,
+        new_cw20_addr: Option<String>,
+        new_fee_bps: Option<u16>,
+        new_fee_recipient: Option<String>,
+    },
+    /// Redirects all refunds to stream owners from cancellations to one
+    /// address. Contract owner only, None sends refunds back to stream
+    /// owners.
+    SetEmergencyRecipient {
+        address: Option<String>,
+    },
+    /// Emergency stop for stream creation and withdrawals. Streams can still
+    /// be cancelled so funds aren't trapped. Contract owner only.
+    SetContractPaused {
+        paused: bool,
+    },
+    /// Sets what happens to creation fees below one token. Contract owner
+    /// only.
+    SetDustPolicy {
+        policy: DustPolicy,
+    },
+    /// Pays out the fees collected in a cw20 token, to the fee recipient by
+    /// default. Contract owner only.
+    SweepFeePool {
+        token: String,
+        to: Option<String>,
+    },
+    /// Sets who pre-claims borrow from. Contract owner only, None disables
+    /// pre-claims.
+    #[cfg(feature = "flash-withdraw")]
+    SetLiquidityProvider {
+        address: Option<String>,
+    },
+    /// Recipient only. Borrows up to the unvested amount from the liquidity
+    /// provider, which must have given this contract a cw20 allowance. Later
+    /// withdrawals repay the provider first.
+    #[cfg(feature = "flash-withdraw")]
+    PreClaim {
+        id: Uint128,
+        amount: Uint128,
+    },
+    /// Sets the DEX that auto-swaps go through. Contract owner only, None
+    /// pays withdrawals out unswapped.
+    #[cfg(feature = "auto-swap")]
+    SetDex {
+        address: Option<String>,
+    },
+    /// Has later withdrawals of a cw20 stream swapped on the DEX before they
+    /// reach the recipient. Recipient only, None stops swapping.
+    #[cfg(feature = "auto-swap")]
+    SetAutoSwap {
+        id: Uint128,
+        auto_swap: Option<AutoSwapSpec>,
+    },
+    /// Moves the stream to a new recipient, e.g. after a key rotation.
+    /// Recipient only.
+    TransferRecipient {
+        id: Uint128,
+        new_recipient: String,
+    },
+    /// Hands the whole stream to a new owner and recipient. Both the owner
+    /// and the recipient have to send the same terms, the second call applies
+    /// them.
+    AssignStream {
+        id: Uint128,
+        new_owner: String,
+        new_recipient: String,
+    },
+    /// Restricts where withdrawals of the stream may be paid, the recipient
+    /// included. Recipient only, None lifts the restriction.
+    SetAllowedDestinations {
+        id: Uint128,
+        destinations: Option<Vec<String>>,
+    },
+    /// Tags the stream into a named group, e.g. a cohort of hires. Stream
+    /// owner only.
+    AddToGroup {
+        id: Uint128,
+        group: String,
+    },
+    /// Stream owner only
+    RemoveFromGroup {
+        id: Uint128,
+        group: String,
+    },
+    /// Recipient only. None clears it.
+    SetBeneficiary {
+        id: Uint128,
+        beneficiary: Option<String>,
+        inactivity_seconds: u64,
+    },
+    /// Pays what is left of the stream to its beneficiary once the recipient
+    /// has been inactive for the configured time past the end time
+    ClaimAsBeneficiary {
+        id: Uint128,
+    },
+    /// Proposes stream terms to the recipient. Nothing is funded until the
+    /// recipient approves and the proposal is funded with `fund_proposal`.
+    ProposeStream {
+        recipient: String,
+        amount: Uint128,
+        start_time: u64,
+        end_time: u64,
+    },
+    /// Recipient only. An `accept_amount` below the proposed amount reduces
+    /// the proposal, which is then funded with the accepted amount.
+    ApproveProposedStream {
+        id: u64,
+        accept_amount: Option<Uint128>,
+    },
+    /// Recipient only, removes the proposal
+    RejectProposedStream {
+        id: u64,
+    },
+    /// Proposer only, until the proposal is funded
+    WithdrawProposal {
+        id: u64,
+    },
+    /// Contract owner only
+    AddGlobalCanceller {
+        address: String,
+    },
+    /// Contract owner only
+    RemoveGlobalCanceller {
+        address: String,
     },
 }
 
@@ -25,6 +337,86 @@ pub enum ReceiveMsg {
         recipient: String,
         start_time: u64,
         end_time: u64,
+        recipient_kind: Option<RecipientKind>, // Defaults to Any
+        recipient_controller: Option<String>,
+        condition: Option<ConditionSpec>,
+        recipient_is_name: Option<bool>, // Resolve `recipient` through the name service
+        amount: Option<Uint128>,         // Defaults to the amount sent, any surplus is refunded
+        rounding_unit: Option<Uint128>,  // Claims are whole multiples of it until the end
+        cliff_time: Option<u64>,         // Nothing vests before it, between start and end time
+        /// Vests in steps instead of linearly, as `(timestamp, cumulative
+        /// amount)` points within the stream's window. Both must increase and
+        /// the last amount must be the whole stream amount.
+        unlock_schedule: Option<Vec<(u64, Uint128)>>,
+        /// Holds shares of the contract's balance of a rebasing token rather
+        /// than a fixed amount. Every stream of the token must set it.
+        rebasing: Option<bool>,
+        /// Allows streaming to the fee recipient while a creation fee is charged
+        allow_fee_recipient: Option<bool>,
+    },
+    CreateInstallmentStream {
+        recipient: String,
+        num_installments: u32,
+        interval_seconds: u64,
+        start_time: u64,
+        /// Allows streaming to the fee recipient while a creation fee is charged
+        allow_fee_recipient: Option<bool>,
+    },
+    BatchTopUp {
+        ids: Vec<Uint128>,
+        weights: Option<Vec<u16>>, // Basis points per stream, equal split if omitted
+        note: Option<String>,      // Logged against every stream topped up
+    },
+    /// Adds the amount sent to one of the sender's streams, vesting over the
+    /// time left
+    TopUp { id: Uint128 },
+    /// Creates the stream of an approved proposal. Must be sent by the
+    /// proposer with exactly the proposed amount.
+    FundProposal { id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientKind {
+    Contract,
+    Wallet,
+    Any,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TimeOracleSpec {
+    pub contract: String,
+    /// Oracle times further than this from the block time, in seconds, are
+    /// clamped
+    pub max_drift: u64,
+}
+
+/// Gates withdrawals on `contract` answering the smart query `msg` with
+/// exactly `expected`, e.g. an oracle reporting a milestone as reached
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ConditionSpec {
+    pub contract: String,
+    pub msg: Binary,
+    pub expected: Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AutoSwapSpec {
+    pub target_token: String,
+    /// Least amount of `target_token` accepted per streamed token, the swap
+    /// fails below it
+    pub min_output_rate: Decimal,
+}
+
+/// Hook sent to the DEX with the withdrawn cw20, which is expected to pay at
+/// least `minimum_receive` of `ask_token` to `to` or fail
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DexHookMsg {
+    Swap {
+        ask_token: String,
+        minimum_receive: Uint128,
+        to: String,
     },
 }
 
@@ -32,17 +424,200 @@ pub enum ReceiveMsg {
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     GetConfig {},
-    GetStream { id: Uint128 },
+    /// Config, stats and optionally a recipient's portfolio in one call, for
+    /// apps starting up
+    Bootstrap {
+        recipient: Option<String>,
+    },
+    GetStream {
+        id: Uint128,
+    },
+    /// Best-effort check that the configured cw20 contract responds to queries
+    TokenAvailable {},
+    /// Fees collected in a cw20 token and not swept yet
+    FeePool {
+        token: String,
+    },
+    /// Rough storage used by streams, a capacity planning aid rather than an
+    /// exact figure
+    StorageEstimate {},
+    /// All streams by ascending id
+    ListStreams {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Streams funded by `owner` by ascending id
+    StreamsByOwner {
+        owner: String,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Totals over the streams funded by `owner`, summed across tokens and in
+    /// shares for rebasing streams. Scans a bounded number of streams per
+    /// call, resume from `last_scanned`.
+    OwnerSummary {
+        owner: String,
+        start_after: Option<Uint128>,
+    },
+    /// Streams of the cw20 token by ascending id
+    StreamsByToken {
+        cw20_addr: String,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Streams paid to `recipient` by ascending id
+    StreamsByRecipient {
+        recipient: String,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Streams tagged into `group` by ascending id
+    StreamsInGroup {
+        group: String,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Streams with `status` at the current time. Scans a bounded number of
+    /// streams per call, resume from `last_scanned`.
+    StreamsByStatus {
+        status: StreamStatus,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    StreamsCreatedBetween {
+        from: u64,
+        to: u64,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    IsWithdrawAuthorized {
+        id: Uint128,
+        address: String,
+    },
+    /// Same as GetStream, with the timestamps also formatted as RFC 3339
+    GetStreamHuman {
+        id: Uint128,
+    },
+    /// Amount and rates the stream would have if topped up with `amount` at
+    /// this block
+    PreviewTopUp {
+        id: Uint128,
+        amount: Uint128,
+    },
+    /// What a withdrawal from the stream would do if executed at this block
+    WithdrawPreview {
+        id: Uint128,
+    },
+    /// What the recipient can withdraw at this block
+    Claimable {
+        id: Uint128,
+    },
+    /// What the recipient will be able to claim at `at`, assuming no further
+    /// withdrawals. Stops growing at a scheduled cancellation.
+    ClaimableAt {
+        id: Uint128,
+        at: u64,
+    },
+    /// What the stream pays its recipient in total, split into claimed and
+    /// still to claim. The two parts always add up to the total.
+    ProjectedTotals {
+        id: Uint128,
+    },
+    /// Installment and step-vesting streams with an unlock past its due time
+    /// that hasn't been withdrawn yet, for keepers picking up work. Scans a
+    /// bounded number of streams per call, resume from `last_scanned`.
+    OverdueClaims {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Streams whose last `min_failures` or more withdrawal transfers failed
+    /// to reach the recipient. Scans a bounded number of streams per call,
+    /// resume from `last_scanned`.
+    StuckStreams {
+        min_failures: u32,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Tokens paid to `recipient` with block time in `[from, to]`, per
+    /// stream, whoever receives the stream now. Scans a bounded number of
+    /// the streams that paid the recipient per call, resume from
+    /// `last_scanned`.
+    RecipientTaxReport {
+        recipient: String,
+        from: u64,
+        to: u64,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    },
+    /// Every stream paid to `recipient` together with what it can claim now
+    PortfolioFor {
+        recipient: String,
+        limit: Option<u32>,
+    },
+    GetProposal {
+        id: u64,
+    },
+    /// Proposals still awaiting the recipient's approval, optionally only
+    /// those to `recipient`. Scans a bounded number of proposals per call,
+    /// resume from `last_scanned`.
+    PendingStreams {
+        recipient: Option<String>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// What `delegate` may still withdraw from the stream
+    DelegateAllowance {
+        id: Uint128,
+        delegate: String,
+    },
+    /// Audit log of the stream, oldest first
+    StreamActions {
+        id: Uint128,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Running totals over all streams, summed across tokens
+    Stats {},
+    /// Payouts from the stream oldest first, paged by their sequence number
+    WithdrawalHistory {
+        id: Uint128,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ConfigResponse {
     pub owner: String,
     pub cw20_addr: String,
+    pub event_namespace: Option<String>,
+    pub arbiter: Option<String>,
+    pub max_total_streams: Option<u64>,
+    pub name_service: Option<String>,
+    pub auto_close_on_final_withdraw: bool,
+    pub time_oracle: Option<TimeOracle>,
+    pub fee_bps: u16,
+    pub fee_recipient: String,
+    pub min_claim_interval_seconds: Option<u64>,
+    pub emergency_recipient: Option<String>,
+    pub paused: bool,
+    pub dust_policy: DustPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BootstrapResponse {
+    pub config: ConfigResponse,
+    /// Running totals, as returned by the stats query
+    pub stats: StatsResponse,
+    /// First page of the recipient's portfolio, None without a recipient
+    pub portfolio: Option<PortfolioResponse>,
+    /// Sum of what the streams in `portfolio` can claim now, in tokens
+    pub total_claimable: Option<Uint128>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct StreamResponse {
+    pub id: Uint128,
     pub owner: String,
     pub recipient: String,
     pub amount: Uint128,
@@ -50,4 +625,306 @@ pub struct StreamResponse {
     pub start_time: u64,
     pub end_time: u64,
     pub rate_per_second: Uint128,
+    pub rate_per_day: Uint128,
+    pub installments: Option<Installments>,
+    pub checkpoint_time: u64,
+    pub checkpoint_vested: Uint128,
+    pub fallback_recipient: Option<String>,
+    pub recipient_controller: Option<String>,
+    pub created_at: u64,
+    pub frozen_at: Option<u64>,
+    pub beneficiary: Option<Beneficiary>,
+    pub condition: Option<Condition>,
+    pub cancel_at: Option<u64>,
+    pub cancelled: bool,
+    pub rounding_unit: Option<Uint128>,
+    pub cliff_time: Option<u64>,
+    pub encumbrance: Option<Encumbrance>,
+    pub cw20_addr: Option<String>,
+    pub denom: Option<String>,
+    pub allowed_destinations: Option<Vec<String>>,
+    pub failed_withdraw_count: u32,
+    pub last_claim_time: Option<u64>,
+    pub unlock_schedule: Option<Vec<(u64, Uint128)>>,
+    pub paused_at: Option<u64>,
+    pub paused_duration: u64,
+    pub cancel_window_end: Option<u64>,
+    /// Status at the time of the query
+    pub status: StreamStatus,
+}
+
+/// Timestamps in RFC 3339 UTC, e.g. `2019-10-23T02:23:39Z`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamHumanResponse {
+    pub stream: StreamResponse,
+    pub start_time: String,
+    pub end_time: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DelegateAllowanceResponse {
+    /// None when no allowance was granted
+    pub allowance: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StorageEstimateResponse {
+    pub bytes_per_stream: u64,
+    /// Every stream created, including closed ones, which stay in storage
+    pub total_streams: Uint128,
+    pub total_bytes: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ProposalResponse {
+    pub id: u64,
+    pub proposal: Proposal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingStreamsResponse {
+    pub proposals: Vec<ProposalResponse>,
+    /// Last proposal id looked at
+    pub last_scanned: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamsResponse {
+    pub streams: Vec<StreamResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ClaimableResponse {
+    /// In tokens, the current value of the shares for a rebasing stream.
+    /// The other amounts are in the stream's units.
+    pub claimable: Uint128,
+    pub claimed: Uint128,
+    /// Part of the stream amount not claimed yet, vested or not
+    pub remaining: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ProjectedTotalsResponse {
+    pub total_to_recipient: Uint128,
+    pub already_claimed: Uint128,
+    pub remaining_to_claim: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ClaimableAtResponse {
+    pub claimable: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawPreviewResponse {
+    /// In tokens, the current value of the shares for a rebasing stream
+    pub claimable: Uint128,
+    /// Whether the withdrawal claims everything left in the stream
+    pub will_close: bool,
+    /// Number of token transfers the withdrawal sends
+    pub transfer_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TopUpPreviewResponse {
+    pub amount: Uint128,
+    pub rate_per_second: Uint128,
+    pub rate_per_day: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OverdueClaim {
+    pub id: Uint128,
+    /// In tokens, the current value of the shares for a rebasing stream
+    pub claimable: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OverdueClaimsResponse {
+    pub claims: Vec<OverdueClaim>,
+    /// Last stream id looked at, None once the scan reached the end
+    pub last_scanned: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamsByStatusResponse {
+    pub streams: Vec<StreamResponse>,
+    /// Last stream id looked at, None once the scan reached the end
+    pub last_scanned: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StuckStream {
+    pub id: Uint128,
+    pub recipient: String,
+    pub failed_withdraw_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StuckStreamsResponse {
+    pub streams: Vec<StuckStream>,
+    /// Last stream id looked at, None once the scan reached the end
+    pub last_scanned: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TaxReportEntry {
+    pub id: Uint128,
+    pub received: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RecipientTaxReportResponse {
+    pub total_received: Uint128,
+    /// Streams with at least one payout to the recipient in the window
+    pub streams: Vec<TaxReportEntry>,
+    /// Last stream id looked at, None once the scan reached the end
+    pub last_scanned: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OwnerSummaryResponse {
+    pub stream_count: u64,
+    /// Stream amounts, less what cancellations refunded
+    pub total_committed: Uint128,
+    /// Not withdrawn yet, vested or not
+    pub total_remaining: Uint128,
+    /// Unvested and still cancellable, what cancelling every stream now
+    /// would refund
+    pub total_refundable: Uint128,
+    /// Set when the scan stopped before the owner's last stream
+    pub last_scanned: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PortfolioEntry {
+    pub stream: StreamResponse,
+    /// In tokens, the current value of the shares for a rebasing stream
+    pub claimable: Uint128,
+    /// Share of the stream amount already claimed, in whole percent
+    pub claimed_percent: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PortfolioResponse {
+    pub streams: Vec<PortfolioEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenAvailableResponse {
+    pub available: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeePoolResponse {
+    pub token: String,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct IsWithdrawAuthorizedResponse {
+    pub authorized: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamActionResponse {
+    pub seq: u64,
+    pub kind: StreamActionKind,
+    pub actor: String,
+    pub timestamp: u64,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamActionsResponse {
+    pub actions: Vec<StreamActionResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StatsResponse {
+    pub total_deposited: Uint128,
+    pub total_claimed: Uint128,
+    pub total_refunded: Uint128,
+    /// Still held for streams, deposited but neither claimed nor refunded
+    pub total_locked: Uint128,
+    pub active_streams: u64,
+}
+
+/// One payout from a stream, `seq` numbers them per stream in order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawalRecordResponse {
+    pub seq: u64,
+    pub time: u64,
+    pub amount: Uint128,
+    pub recipient: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawalHistoryResponse {
+    pub withdrawals: Vec<WithdrawalRecordResponse>,
+}
+
+/// Set as the response data of stream creation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CreateResult {
+    pub id: Uint128,
+}
+
+/// Set as the response data of `Withdraw`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawResult {
+    pub id: Uint128,
+    pub amount: Uint128,
+    pub new_claimed: Uint128,
+    /// Part of the stream amount not claimed yet, vested or not
+    pub remaining: Uint128,
+}
+
+/// Set as the response data of `Cancel`, `PartialCancel` and
+/// `ExecuteScheduledCancel`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CancelResult {
+    pub id: Uint128,
+    /// Sent back to the stream owner
+    pub refunded: Uint128,
+    /// Sent to the recipient
+    pub paid: Uint128,
+}
+
+/// Query interface a recipient controller contract must implement
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ControllerQueryMsg {
+    CurrentRecipient { stream_id: Uint128 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CurrentRecipientResponse {
+    pub recipient: String,
+}
+
+/// Query interface the configured name service contract must implement
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NameServiceQueryMsg {
+    ResolveRecord { name: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ResolveRecordResponse {
+    pub address: Option<String>,
+}
+
+/// Query interface the configured time oracle contract must implement
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeOracleQueryMsg {
+    CurrentTime {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CurrentTimeResponse {
+    /// Seconds since the Unix epoch
+    pub time: u64,
 }
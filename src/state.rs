@@ -1,17 +1,95 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Addr, DepsMut, StdResult, Uint128};
-use cw_storage_plus::{Item, Map, U128Key};
+use crate::ContractError;
+
+use cosmwasm_std::{
+    Addr, Binary, Decimal, DepsMut, Empty, Order, StdError, StdResult, Storage, Uint128,
+};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex, U128Key, U64Key};
+use std::convert::TryInto;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Config {
     pub owner: Addr,
+    /// Token allowed at instantiation, see `ALLOWED_TOKENS`
     pub cw20_addr: Addr,
+    /// Prefixed to emitted attribute keys, e.g. `payroll.stream_id`
+    pub event_namespace: Option<String>,
+    /// May freeze and unfreeze any stream during a dispute
+    pub arbiter: Option<Addr>,
+    /// Cap on the number of streams ever created, cancelled or not
+    pub max_total_streams: Option<u64>,
+    pub name_service: Option<Addr>,
+    /// Streams drained by a withdrawal are removed
+    pub auto_close_on_final_withdraw: bool,
+    pub time_oracle: Option<TimeOracle>,
+    /// Share of every new stream's deposit, in basis points
+    pub fee_bps: u16,
+    pub fee_recipient: Addr,
+    /// Withdrawals from a stream must be this far apart, counted from its
+    /// last withdrawal or top-up
+    pub min_claim_interval_seconds: Option<u64>,
+    /// Receives every refund to a stream owner instead of the owner. Unset
+    /// unless the contract owner opts in
+    pub emergency_recipient: Option<Addr>,
+    /// Stops stream creation and withdrawals. Cancellations still refund.
+    pub paused: bool,
+    /// What happens to the fraction of a token the creation fee rounds off
+    pub dust_policy: DustPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DustPolicy {
+    /// The fee is rounded down and the fraction is left in the stream
+    DropToZero,
+    /// A fee that rounds down to nothing is charged as one token instead
+    RoundUpToOne,
+    /// Fractions add up in `FEE_DUST`, and every whole token they make is
+    /// added to the fee of the stream that completes it
+    AccumulateInternally,
+}
+
+/// Contract queried for the current time, which is clamped to within
+/// `max_drift` seconds of the block time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TimeOracle {
+    pub contract: Addr,
+    pub max_drift: u64,
 }
 
 pub const CONFIG: Item<Config> = Item::new("config");
 
+/// Fraction of a token left over from the fees of each cw20 token, in
+/// `MAX_FEE_BPS`ths of a token
+pub const FEE_DUST: Map<&Addr, Uint128> = Map::new("fee_dust");
+
+/// Fees collected per cw20 token and held until swept
+pub const FEE_POOL: Map<&Addr, Uint128> = Map::new("fee_pool");
+
+/// cw20 contracts whose tokens can be streamed
+pub const ALLOWED_TOKENS: Item<Vec<Addr>> = Item::new("allowed_tokens");
+
+/// Lends pre-claims against the future of a stream
+pub const LIQUIDITY_PROVIDER: Item<Addr> = Item::new("liquidity_provider");
+
+/// Swaps withdrawals of streams with an auto-swap
+pub const DEX: Item<Addr> = Item::new("dex");
+
+/// The contract's balance of a rebasing token, owned by its streams in
+/// proportion to their shares
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SharePool {
+    pub total_shares: Uint128,
+    /// Balance read at `synced_height`, plus deposits and less payouts since
+    pub total_tokens: Uint128,
+    pub synced_height: u64,
+}
+
+/// Rebasing tokens, whose streams are all denominated in shares
+pub const SHARE_POOLS: Map<&Addr, SharePool> = Map::new("share_pools");
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Stream {
     pub owner: Addr,
@@ -20,15 +98,478 @@ pub struct Stream {
     pub claimed_amount: Uint128,
     pub start_time: u64,
     pub end_time: u64,
+    /// Floored rates of what remains after the checkpoint, for display.
+    /// Vesting uses the exact ratio so the rounding doesn't hold back funds.
     pub rate_per_second: Uint128,
+    pub rate_per_day: Uint128,
+    pub installments: Option<Installments>,
+    /// Linear vesting resumes from `checkpoint_vested` at `checkpoint_time`
+    /// whenever the stream amount changes mid-stream.
+    pub checkpoint_time: u64,
+    pub checkpoint_vested: Uint128,
+    /// Receives withdrawals that the recipient can't accept
+    pub fallback_recipient: Option<Addr>,
+    /// When set, queried at withdraw time for the address to pay
+    pub recipient_controller: Option<Addr>,
+    pub created_at: u64,
+    /// Accrual is stopped at this time while the stream is frozen
+    pub frozen_at: Option<u64>,
+    pub beneficiary: Option<Beneficiary>,
+    pub condition: Option<Condition>,
+    /// Vesting stops at this time, when the rest is due back to the owner
+    pub cancel_at: Option<u64>,
+    /// Set once the stream is cancelled, after which nothing more is paid
+    pub cancelled: bool,
+    /// Claims are floored to a multiple of this until everything has vested
+    pub rounding_unit: Option<Uint128>,
+    /// Nothing vests before this time, then linear vesting from the start
+    /// time unlocks at once
+    pub cliff_time: Option<u64>,
+    /// Set while an advance from a liquidity provider is being repaid
+    pub encumbrance: Option<Encumbrance>,
+    /// cw20 token streamed, None for native streams
+    pub cw20_addr: Option<Addr>,
+    /// Native denom streamed, None for cw20 streams
+    pub denom: Option<String>,
+    /// When set, withdrawals can only be paid to these addresses
+    pub allowed_destinations: Option<Vec<Addr>>,
+    /// Withdrawal transfers in a row that the recipient couldn't receive and
+    /// went to the fallback recipient instead
+    pub failed_withdraw_count: u32,
+    /// Last withdrawal or top-up, which starts the claim cooldown
+    pub last_claim_time: Option<u64>,
+    /// `(timestamp, cumulative amount)` unlock points replacing linear
+    /// vesting. Each cumulative amount vests at once when its time is reached.
+    pub unlock_schedule: Option<Vec<(u64, Uint128)>>,
+    /// Set while the stream is paused, during which its schedule stands still
+    pub paused_at: Option<u64>,
+    /// Seconds spent paused since the start time, by which the schedule runs
+    /// behind the block time
+    pub paused_duration: u64,
+    /// The stream can only be cancelled until then, after which it is
+    /// irrevocable. None leaves it cancellable throughout.
+    pub cancel_window_end: Option<u64>,
+}
+
+/// Withdrawals repay `lender` first until `outstanding` is cleared
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Encumbrance {
+    pub lender: Addr,
+    pub outstanding: Uint128,
+}
+
+/// Where a stream is in its lifecycle at a given time
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStatus {
+    NotStarted,
+    Active,
+    /// Past its end time, everything has vested
+    Completed,
+    Cancelled,
+}
+
+/// Inherits what is left of a stream once the recipient hasn't withdrawn for
+/// `inactivity_seconds` past the end time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Beneficiary {
+    pub address: Addr,
+    pub inactivity_seconds: u64,
+}
+
+/// Withdrawals are only allowed while `contract` answers the smart query
+/// `msg` with exactly `expected`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Condition {
+    pub contract: Addr,
+    pub msg: Binary,
+    pub expected: Binary,
+}
+
+/// Pays `amount / count` at the end of every interval, with the final
+/// installment absorbing any remainder.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Installments {
+    pub count: u32,
+    pub interval_seconds: u64,
+}
+
+/// Withdrawal transfer awaiting its reply, rerouted to the fallback
+/// recipient if the transfer to the stream recipient fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingFallback {
+    pub stream_id: Uint128,
+    pub fallback_recipient: Addr,
+    pub amount: Uint128,
+    pub cw20_addr: Option<Addr>,
+    pub denom: Option<String>,
+}
+
+pub const PENDING_FALLBACK: Item<PendingFallback> = Item::new("pending_fallback");
+
+/// Conversion to installments proposed by one party of a stream, applied
+/// once the other party sends the same terms
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingConversion {
+    pub proposer: Addr,
+    pub steps: u32,
+}
+
+pub const PENDING_CONVERSIONS: Map<U128Key, PendingConversion> = Map::new("pending_conversions");
+
+/// Latest top-up of a stream, revertible by its owner within the same block
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LastTopUp {
+    pub height: u64,
+    pub amount: Uint128,
+    /// Refunded if the top-up is reverted
+    pub sender: Addr,
+    /// The stream as it was before the top-up
+    pub prior: Stream,
+}
+
+pub const LAST_TOP_UPS: Map<U128Key, LastTopUp> = Map::new("last_top_ups");
+
+/// Soft cancellation held in escrow, restorable by the stream owner until
+/// `finalize_after`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingCancellation {
+    /// The stream as it was before the cancellation
+    pub prior: Stream,
+    /// Owed to the recipient, already counted as claimed
+    pub paid: Uint128,
+    /// Owed back to the owner
+    pub refund: Uint128,
+    pub finalize_after: u64,
+}
+
+pub const PENDING_CANCELLATIONS: Map<U128Key, PendingCancellation> =
+    Map::new("pending_cancellations");
+
+/// Recipient's standing order to have its withdrawals of a stream swapped to
+/// `target_token` on the DEX
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AutoSwap {
+    pub target_token: Addr,
+    /// Least amount of `target_token` accepted per streamed token
+    pub min_output_rate: Decimal,
+}
+
+pub const AUTO_SWAPS: Map<U128Key, AutoSwap> = Map::new("auto_swaps");
+
+/// Streams a recipient asked to consolidate, keyed by the stream they are
+/// consolidated into, applied once the owner approves
+pub const PENDING_CONSOLIDATIONS: Map<U128Key, Vec<Uint128>> = Map::new("pending_consolidations");
+
+/// New owner and recipient of a stream, agreed to by `approved_by` and
+/// applied once the other current party agrees to the same terms
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingAssignment {
+    pub new_owner: Addr,
+    pub new_recipient: Addr,
+    pub approved_by: Addr,
+}
+
+pub const PENDING_ASSIGNMENTS: Map<U128Key, PendingAssignment> = Map::new("pending_assignments");
+
+/// Recipient-level delegates allowed to withdraw any of the recipient's streams
+pub const GLOBAL_DELEGATES: Map<&Addr, Addr> = Map::new("global_delegates");
+
+/// What a delegate may still withdraw from a stream, granted by its recipient
+pub const DELEGATE_ALLOWANCES: Map<(U128Key, &Addr), Uint128> = Map::new("delegate_allowances");
+
+/// Stream terms proposed by `owner`. Once the recipient approves them the
+/// owner funds the proposal, which creates the stream.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Proposal {
+    pub owner: Addr,
+    pub recipient: Addr,
+    pub amount: Uint128,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub approved: bool,
 }
 
+pub const PROPOSALS: Map<U64Key, Proposal> = Map::new("proposals");
+
+pub const PROPOSAL_SEQ: Item<u64> = Item::new("proposal_seq");
+
+/// Recipients under a freeze, with the streams it froze. Those are unfrozen
+/// together when it's lifted.
+pub const FROZEN_RECIPIENTS: Map<&Addr, Vec<Uint128>> = Map::new("frozen_recipients");
+
+/// Operators allowed to cancel any stream
+pub const GLOBAL_CANCELLERS: Map<&Addr, Empty> = Map::new("global_cancellers");
+
+/// Streams tagged into a named group by their owner, keyed by group name and
+/// stream id
+pub const GROUPS: Map<(&str, U128Key), Empty> = Map::new("groups");
+
+/// Set while a mutating call is in progress, including any reply it awaits
+pub const REENTRANCY_LOCK: Item<bool> = Item::new("reentrancy_lock");
+
 pub const STREAM_SEQ: Item<Uint128> = Item::new("stream_seq");
-pub const STREAMS: Map<U128Key, Stream> = Map::new("stream");
 
-pub fn save_stream(deps: DepsMut, stream: &Stream) -> StdResult<()> {
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamActionKind {
+    Create,
+    Withdraw,
+    TopUp,
+    PartialCancel,
+    Crystallize,
+    SetFallbackRecipient,
+    Convert,
+    Freeze,
+    Unfreeze,
+    SetBeneficiary,
+    BeneficiaryClaim,
+    Consolidate,
+    RevertTopUp,
+    ScheduleCancel,
+    ScheduledCancel,
+    Cancel,
+    Close,
+    TransferRecipient,
+    Assign,
+    SetAllowedDestinations,
+    Pause,
+    Resume,
+    SetCancelWindow,
+    SoftCancel,
+    RestoreStream,
+    FinalizeCancellation,
+}
+
+/// Entry in a stream's audit log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StreamAction {
+    pub kind: StreamActionKind,
+    pub actor: Addr,
+    pub timestamp: u64,
+    pub note: Option<String>,
+}
+
+/// A payout from a stream, by withdrawal or at its cancellation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawalRecord {
+    pub time: u64,
+    pub amount: Uint128,
+    pub recipient: Addr,
+}
+
+pub struct WithdrawalIndexes<'a> {
+    pub recipient: MultiIndex<'a, (Vec<u8>, Vec<u8>), WithdrawalRecord>,
+}
+
+impl<'a> IndexList<WithdrawalRecord> for WithdrawalIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<WithdrawalRecord>> + '_> {
+        let v: Vec<&dyn Index<WithdrawalRecord>> = vec![&self.recipient];
+        Box::new(v.into_iter())
+    }
+}
+
+/// Payouts from each stream, keyed by stream id and a per-stream sequence
+/// so that payouts in the same block are kept apart, and indexed by the
+/// address that was paid
+pub fn withdrawals<'a>(
+) -> IndexedMap<'a, (U128Key, U64Key), WithdrawalRecord, WithdrawalIndexes<'a>> {
+    let indexes = WithdrawalIndexes {
+        recipient: MultiIndex::new(
+            |r, pk| (r.recipient.as_bytes().to_vec(), pk),
+            "withdrawals",
+            "withdrawals__recipient",
+        ),
+    };
+    IndexedMap::new("withdrawals", indexes)
+}
+
+pub fn record_withdrawal(
+    storage: &mut dyn Storage,
+    id: Uint128,
+    record: &WithdrawalRecord,
+) -> StdResult<()> {
+    let last = withdrawals()
+        .prefix(id.u128().into())
+        .keys(storage, None, None, Order::Descending)
+        .next();
+    let seq = match last {
+        Some(key) => {
+            let key: [u8; 8] = key
+                .as_slice()
+                .try_into()
+                .map_err(|_| StdError::generic_err("invalid withdrawal key"))?;
+            u64::from_be_bytes(key) + 1
+        }
+        None => 1,
+    };
+    withdrawals().save(storage, (id.u128().into(), seq.into()), record)
+}
+
+/// Audit log keyed by stream id and a contract-wide action sequence, so each
+/// stream's entries range in the order they happened
+pub const STREAM_ACTIONS: Map<(U128Key, U64Key), StreamAction> = Map::new("stream_actions");
+
+pub const ACTION_SEQ: Item<u64> = Item::new("action_seq");
+
+/// Running totals over every stream, summed across tokens
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Stats {
+    /// Deposited by stream creations and top-ups
+    pub total_deposited: Uint128,
+    /// Paid out of streams to recipients, beneficiaries and lenders
+    pub total_claimed: Uint128,
+    /// Returned to owners by cancellations and reverted top-ups
+    pub total_refunded: Uint128,
+    /// Streams that are neither cancelled nor fully claimed
+    pub active_streams: u64,
+}
+
+pub const STATS: Item<Stats> = Item::new("stats");
+
+fn is_active(stream: &Stream) -> bool {
+    !stream.cancelled && stream.claimed_amount < stream.amount
+}
+
+/// Folds the change of some streams from `before` to `after` into `STATS`.
+/// A higher total amount was deposited, a lower one refunded, so funds moved
+/// between streams cancel out.
+pub fn update_stats(
+    storage: &mut dyn Storage,
+    before: &[&Stream],
+    after: &[&Stream],
+) -> StdResult<()> {
+    let totals = |streams: &[&Stream]| -> StdResult<(Uint128, Uint128, u64)> {
+        let mut totals = (Uint128::zero(), Uint128::zero(), 0);
+        for stream in streams {
+            totals.0 = totals.0.checked_add(stream.amount)?;
+            totals.1 = totals.1.checked_add(stream.claimed_amount)?;
+            totals.2 += u64::from(is_active(stream));
+        }
+        Ok(totals)
+    };
+    let (amount_before, claimed_before, active_before) = totals(before)?;
+    let (amount_after, claimed_after, active_after) = totals(after)?;
+
+    let mut stats = STATS.may_load(storage)?.unwrap_or_default();
+    if amount_after > amount_before {
+        stats.total_deposited = stats
+            .total_deposited
+            .checked_add(amount_after.checked_sub(amount_before)?)?;
+    } else {
+        stats.total_refunded = stats
+            .total_refunded
+            .checked_add(amount_before.checked_sub(amount_after)?)?;
+    }
+    stats.total_claimed = stats
+        .total_claimed
+        .checked_add(claimed_after.checked_sub(claimed_before)?)?;
+    stats.active_streams = (stats.active_streams + active_after).saturating_sub(active_before);
+    STATS.save(storage, &stats)
+}
+
+pub struct StreamIndexes<'a> {
+    pub created_at: MultiIndex<'a, (U64Key, Vec<u8>), Stream>,
+    pub recipient: MultiIndex<'a, (Vec<u8>, Vec<u8>), Stream>,
+    pub owner: MultiIndex<'a, (Vec<u8>, Vec<u8>), Stream>,
+    /// By cw20 address, or denom for native streams
+    pub token: MultiIndex<'a, (Vec<u8>, Vec<u8>), Stream>,
+}
+
+impl<'a> IndexList<Stream> for StreamIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Stream>> + '_> {
+        let v: Vec<&dyn Index<Stream>> =
+            vec![&self.created_at, &self.recipient, &self.owner, &self.token];
+        Box::new(v.into_iter())
+    }
+}
+
+/// Key of the stream's token in the token index
+pub fn stream_token_key(stream: &Stream) -> Vec<u8> {
+    match (&stream.denom, &stream.cw20_addr) {
+        (Some(denom), _) => denom.as_bytes().to_vec(),
+        (None, Some(cw20_addr)) => cw20_addr.as_bytes().to_vec(),
+        (None, None) => vec![],
+    }
+}
+
+pub fn streams<'a>() -> IndexedMap<'a, U128Key, Stream, StreamIndexes<'a>> {
+    let indexes = StreamIndexes {
+        created_at: MultiIndex::new(
+            |s, pk| (U64Key::new(s.created_at), pk),
+            "stream",
+            "stream__created_at",
+        ),
+        recipient: MultiIndex::new(
+            |s, pk| (s.recipient.as_bytes().to_vec(), pk),
+            "stream",
+            "stream__recipient",
+        ),
+        owner: MultiIndex::new(
+            |s, pk| (s.owner.as_bytes().to_vec(), pk),
+            "stream",
+            "stream__owner",
+        ),
+        token: MultiIndex::new(|s, pk| (stream_token_key(s), pk), "stream", "stream__token"),
+    };
+    IndexedMap::new("stream", indexes)
+}
+
+pub fn save_stream(deps: DepsMut, stream: &Stream) -> Result<Uint128, ContractError> {
     let id = STREAM_SEQ.load(deps.storage)?;
+    // Ids are sequential, so the last id is the number of streams created
+    let config = CONFIG.load(deps.storage)?;
+    if let Some(max_total_streams) = config.max_total_streams {
+        if id >= Uint128::from(max_total_streams) {
+            return Err(ContractError::GlobalStreamLimitReached {});
+        }
+    }
+
     let id = id.checked_add(Uint128::new(1))?;
     STREAM_SEQ.save(deps.storage, &id)?;
-    STREAMS.save(deps.storage, id.u128().into(), stream)
+    streams().save(deps.storage, id.u128().into(), stream)?;
+    update_stats(deps.storage, &[], &[stream])?;
+    Ok(id)
+}
+
+pub fn log_stream_action(
+    storage: &mut dyn Storage,
+    id: Uint128,
+    kind: StreamActionKind,
+    actor: Addr,
+    timestamp: u64,
+    note: Option<String>,
+) -> StdResult<()> {
+    let seq = ACTION_SEQ.may_load(storage)?.unwrap_or_default() + 1;
+    ACTION_SEQ.save(storage, &seq)?;
+    let action = StreamAction {
+        kind,
+        actor,
+        timestamp,
+        note,
+    };
+    STREAM_ACTIONS.save(storage, (id.u128().into(), seq.into()), &action)
+}
+
+/// Config as stored by 0.1.0, read once by `migrate`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyConfig {
+    pub owner: Addr,
+    pub cw20_addr: Addr,
+}
+
+pub const LEGACY_CONFIG: Item<LegacyConfig> = Item::new("config");
+
+/// Stream as stored by 0.1.0, read once by `migrate`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyStream {
+    pub owner: Addr,
+    pub recipient: Addr,
+    pub amount: Uint128,
+    pub claimed_amount: Uint128,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub rate_per_second: Uint128,
 }
+
+pub const LEGACY_STREAMS: Map<U128Key, LegacyStream> = Map::new("stream");